@@ -0,0 +1,401 @@
+// 產生 fuzz/corpus 的初始內容: cargo run --example seed_corpus [capture...]
+// 未指定 capture 時只有合成的資料, 封包格式與 OBS 推流相同 (x264 High + AAC-LC, Enhanced RTMP HEVC)
+// capture 為實際推流的內容:
+//   *.flv  OBS 錄影 (輸出格式 flv), 取出各種 tag 並以前幾秒模擬推流
+//   其他   推流端送出的 bytes, 例: socat -r obs.rtmp TCP-LISTEN:1936,reuseaddr TCP:127.0.0.1:1935
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::{env, fs};
+use bytes::Bytes;
+use rml_rtmp::sessions::{ClientSession, ClientSessionConfig, ClientSessionResult, PublishRequestType, StreamMetadata};
+use rml_rtmp::time::RtmpTimestamp;
+use mock_yo_stream::playlist::PlayLists;
+use mock_yo_stream::stream::server::{Server, ServerResult};
+
+// x264 High@3.1 1280x720
+const AVC_SPS: &[u8] = &[0x67, 0x64, 0x00, 0x1f, 0xac, 0xd9, 0x40, 0x50, 0x05, 0xbb, 0x01, 0x6a, 0x02, 0x02, 0x02, 0x80, 0x00, 0x00, 0x03, 0x00, 0x80, 0x00, 0x00, 0x1e, 0x07, 0x8c, 0x18, 0xcb];
+const AVC_PPS: &[u8] = &[0x68, 0xeb, 0xe3, 0xcb, 0x22, 0xc0];
+// Main@L3.1 1280x720
+const HEVC_VPS: &[u8] = &[0x40, 0x01, 0x0c, 0x01, 0xff, 0xff];
+const HEVC_SPS: &[u8] = &[0x42, 0x01, 0x01, 0x01, 0x60, 0x00, 0x00, 0x03, 0x00, 0x90, 0x00, 0x00, 0x03, 0x00, 0x00, 0x03, 0x00, 0x5d, 0xa0, 0x02, 0x80, 0x80, 0x2d, 0x17, 0xa2];
+const HEVC_PPS: &[u8] = &[0x44, 0x01, 0xc1, 0x72];
+const HEVC_PROFILE: &[u8] = &[0x01, 0x60, 0x00, 0x00, 0x00, 0x90, 0x00, 0x00, 0x00, 0x00, 0x00, 0x5d];
+// AAC-LC 48000 Hz stereo
+const AAC_CONFIG: &[u8] = &[0x11, 0x90];
+
+fn main() {
+    let corpus = Path::new(env!("CARGO_MANIFEST_DIR")).join("corpus");
+
+    let avc_header = flv_video(0x17, 0, &avcc());
+    let avc_keyframe = flv_video(0x17, 1, &length_prefixed(&[&sei(), &slice(0x65, 0x88, 600)]));
+    let avc_frame = flv_video(0x27, 1, &length_prefixed(&[&slice(0x41, 0x9a, 200)]));
+    let hevc_header = [&[0x90][..], b"hvc1", &hvcc()].concat();
+    let hevc_keyframe = [&[0x91][..], b"hvc1", &[0, 0, 0], &length_prefixed(&[&slice(0x26, 0x01, 600)])].concat();
+    let hevc_frame = [&[0xa3][..], b"hvc1", &length_prefixed(&[&slice(0x02, 0x01, 200)])].concat();
+    let aac_header = [&[0xaf, 0x00][..], AAC_CONFIG].concat();
+    let aac_frame = [&[0xaf, 0x01][..], &[0x21, 0x10, 0x04, 0x60, 0x8c, 0x1c]].concat();
+    let mp3_frame = [&[0x2f][..], &[0xff, 0xfb, 0x90, 0x64], &[0x00; 64]].concat();
+    let opus_header = [&[0x90][..], b"Opus", b"OpusHead", &[0x01, 0x02, 0x38, 0x01, 0x80, 0xbb, 0x00, 0x00, 0x00, 0x00, 0x00]].concat();
+    let opus_frame = [&[0x91][..], b"Opus", &[0xfc, 0xff, 0xfe]].concat();
+
+    write(&corpus, "flv_video", "avc_sequence_header", &avc_header);
+    write(&corpus, "flv_video", "avc_keyframe", &avc_keyframe);
+    write(&corpus, "flv_video", "avc_frame", &avc_frame);
+    write(&corpus, "flv_video", "avc_end_of_sequence", &[0x17, 0x02, 0x00, 0x00, 0x00]);
+    write(&corpus, "flv_video", "hevc_sequence_start", &hevc_header);
+    write(&corpus, "flv_video", "hevc_keyframe", &hevc_keyframe);
+    write(&corpus, "flv_video", "hevc_frame_x", &hevc_frame);
+
+    write(&corpus, "flv_audio", "aac_sequence_header", &aac_header);
+    write(&corpus, "flv_audio", "aac_frame", &aac_frame);
+    write(&corpus, "flv_audio", "mp3_frame", &mp3_frame);
+    write(&corpus, "flv_audio", "opus_sequence_start", &opus_header);
+    write(&corpus, "flv_audio", "opus_frame", &opus_frame);
+
+    write(&corpus, "nalu_config", "avc", &[&[0][..], &avcc()].concat());
+    write(&corpus, "nalu_config", "hevc", &[&[1][..], &hvcc()].concat());
+
+    write(&corpus, "nalu", "avc_keyframe", &nalu_input(0, &avcc(), &avc_keyframe[5..]));
+    write(&corpus, "nalu", "avc_frame", &nalu_input(0, &avcc(), &avc_frame[5..]));
+    write(&corpus, "nalu", "avc_without_header", &nalu_input(6, &[], &avc_keyframe[5..]));
+    write(&corpus, "nalu", "hevc_keyframe", &nalu_input(1, &hvcc(), &hevc_keyframe[8..]));
+
+    let caption = caption_sei();
+    write(&corpus, "caption", "avc_cea608", &[&[0][..], &length_prefixed(&[&caption, &slice(0x41, 0x9a, 200)])].concat());
+    write(&corpus, "caption", "cc_data", &[&[2][..], &caption[13..caption.len() - 2]].concat());
+
+    write(&corpus, "adts_config", "aac_lc_48000", AAC_CONFIG);
+    write(&corpus, "adts_config", "aac_lc_44100", &[0x12, 0x10]);
+    write(&corpus, "adts_config", "he_aac", &[0x2b, 0x11, 0x88, 0x00]);
+    write(&corpus, "adts_config", "he_aac_v2", &[0xeb, 0x09, 0x88, 0x00]);
+    write(&corpus, "adts_config", "sync_extension", &[0x13, 0x10, 0x56, 0xe5, 0x98]);
+
+    // server 會寫入 ./video
+    let directory = env::temp_dir().join("mock-yo-stream-fuzz");
+    fs::create_dir_all(&directory).unwrap();
+    env::set_current_dir(&directory).unwrap();
+
+    let mut publisher = Publisher::new();
+    publisher.publish("obs", Some(metadata("7", "10")));
+    publisher.video(0, &avc_header);
+    publisher.audio(0, &aac_header);
+    for frame in 0..4u32 {
+        publisher.video(frame * 33, if frame == 0 { &avc_keyframe } else { &avc_frame });
+        publisher.audio(frame * 21, &aac_frame);
+    }
+    write(&corpus, "server", "obs_avc_aac", &publisher.sent);
+
+    let mut publisher = Publisher::new();
+    publisher.publish("obs_hevc", None);
+    publisher.video(0, &hevc_header);
+    publisher.audio(0, &opus_header);
+    for frame in 0..4u32 {
+        publisher.video(frame * 33, if frame == 0 { &hevc_keyframe } else { &hevc_frame });
+        publisher.audio(frame * 20, &opus_frame);
+    }
+    write(&corpus, "server", "obs_hevc_opus", &publisher.sent);
+
+    for path in env::args().skip(1) {
+        let path = Path::new(&path);
+        let name = path.file_name().unwrap().to_string_lossy().replace('.', "_");
+        let data = fs::read(path).unwrap_or_else(|error| panic!("{}: {}", path.display(), error));
+        if path.extension().is_some_and(|extension| extension == "flv") {
+            flv_capture(&corpus, &name, &data);
+        } else {
+            rtmp_capture(&corpus, &name, &data);
+        }
+    }
+}
+
+// 每種 tag 取第一個寫入各 target 的語料, 開頭的 tag 另外模擬推流寫入 server 的語料
+fn flv_capture(corpus: &Path, name: &str, data: &[u8]) {
+    let tags = flv_tags(data);
+    println!("{}: {} tags", name, tags.len());
+
+    // 以 tag type 與前兩個 byte 區分
+    let mut kinds = Vec::new();
+    let mut config: Option<(u8, Vec<u8>)> = None;
+    for (tag_type, _, body) in &tags {
+        let target = match tag_type {
+            8 => "flv_audio",
+            9 => "flv_video",
+            _ => continue,
+        };
+        let kind = format!("{}_{:02x}{:02x}", &target[4..], body.first().copied().unwrap_or(0), body.get(1).copied().unwrap_or(0));
+        if kinds.contains(&kind) {
+            continue;
+        }
+        write(corpus, target, &format!("{}_{}", name, kind), body);
+        kinds.push(kind);
+
+        if *tag_type == 8 && body.len() > 2 && body[0] >> 4 == 10 && body[1] == 0 {
+            write(corpus, "adts_config", name, &body[2..]);
+        }
+        match video_frame(body) {
+            Some((selector, VideoPacket::Header, header)) if *tag_type == 9 => {
+                write(corpus, "nalu_config", name, &[&[selector][..], header].concat());
+                config = Some((selector, header.to_vec()));
+            }
+            Some((selector, VideoPacket::Frame(keyframe), frame)) if *tag_type == 9 => {
+                let kind = if keyframe { "keyframe" } else { "frame" };
+                if let Some((_, header)) = config.as_ref().filter(|(codec, _)| *codec == selector) {
+                    write(corpus, "nalu", &format!("{}_{}", name, kind), &nalu_input(selector, header, frame));
+                }
+                write(corpus, "caption", &format!("{}_{}", name, kind), &[&[selector][..], frame].concat());
+            }
+            _ => (),
+        }
+    }
+
+    let mut publisher = Publisher::new();
+    publisher.publish(name, None);
+    for (tag_type, timestamp, body) in tags.iter().take_while(|(_, timestamp, _)| *timestamp <= Publisher::CAPTURE_DURATION) {
+        match tag_type {
+            8 => publisher.audio(*timestamp, body),
+            9 => publisher.video(*timestamp, body),
+            _ => (),
+        }
+    }
+    write(corpus, "server", name, &publisher.sent);
+}
+
+// FLV header 與 previous tag size 之後為 (tag type, timestamp, data)
+fn flv_tags(data: &[u8]) -> Vec<(u8, u32, &[u8])> {
+    let mut tags = Vec::new();
+    let mut offset = match data.get(5..9) {
+        Some(size) if data.starts_with(b"FLV") => u32::from_be_bytes([size[0], size[1], size[2], size[3]]) as usize + 4,
+        _ => panic!("not a flv file"),
+    };
+    while offset + 11 <= data.len() {
+        let header = &data[offset..offset + 11];
+        let size = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+        let timestamp = u32::from_be_bytes([header[7], header[4], header[5], header[6]]);
+        let body = match data.get(offset + 11..offset + 11 + size) {
+            Some(body) => body,
+            None => break,
+        };
+        tags.push((header[0] & 0x1f, timestamp, body));
+        offset += 11 + size + 4;
+    }
+    tags
+}
+
+enum VideoPacket {
+    Header,
+    Frame(bool),
+    Other,
+}
+
+// (nalu target 的編碼 selector, packet 種類, sequence header 或 length-prefixed nalu)
+// 支援 AVC 與 Enhanced RTMP 的 HEVC
+fn video_frame(body: &[u8]) -> Option<(u8, VideoPacket, &[u8])> {
+    let keyframe = body.first()? >> 4 & 0x07 == 1;
+    if body[0] & 0x80 == 0 {
+        if body[0] & 0x0f != 7 || body.len() < 5 {
+            return None;
+        }
+        let packet = match body[1] {
+            0 => VideoPacket::Header,
+            1 => VideoPacket::Frame(keyframe),
+            _ => VideoPacket::Other,
+        };
+        return Some((0, packet, &body[5..]));
+    }
+    if body.get(1..5)? != b"hvc1" {
+        return None;
+    }
+    match body[0] & 0x0f {
+        0 => Some((1, VideoPacket::Header, &body[5..])),
+        1 => Some((1, VideoPacket::Frame(keyframe), body.get(8..)?)),
+        3 => Some((1, VideoPacket::Frame(keyframe), &body[5..])),
+        _ => Some((1, VideoPacket::Other, &body[5..])),
+    }
+}
+
+// 去掉握手 (C0 + C1 + C2) 後以 fuzz_targets/server.rs 的格式分段
+fn rtmp_capture(corpus: &Path, name: &str, data: &[u8]) {
+    const HANDSHAKE: usize = 1 + 1536 + 1536;
+    if data.first() != Some(&3) || data.len() <= HANDSHAKE {
+        panic!("{}: not an rtmp capture", name);
+    }
+    let mut sent = Vec::new();
+    for chunk in data[HANDSHAKE..].chunks(Publisher::CAPTURE_READ) {
+        sent.extend(&(chunk.len() as u16).to_be_bytes());
+        sent.extend(chunk);
+        if sent.len() >= Publisher::CAPTURE_SIZE {
+            break;
+        }
+    }
+    println!("{}: {} bytes", name, sent.len());
+    write(corpus, "server", name, &sent);
+}
+
+fn write(corpus: &Path, target: &str, name: &str, data: &[u8]) {
+    let directory = corpus.join(target);
+    fs::create_dir_all(&directory).unwrap();
+    fs::write(directory.join(name), data).unwrap();
+}
+
+// Frame Type/Codec ID | AVC Packet Type | Composition Time | Body
+fn flv_video(byte0: u8, packet_type: u8, body: &[u8]) -> Vec<u8> {
+    [&[byte0, packet_type, 0, 0, 0][..], body].concat()
+}
+
+fn length_prefixed(units: &[&[u8]]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for unit in units {
+        data.extend(&(unit.len() as u32).to_be_bytes());
+        data.extend(*unit);
+    }
+    data
+}
+
+fn avcc() -> Vec<u8> {
+    let mut data = vec![0x01, AVC_SPS[1], AVC_SPS[2], AVC_SPS[3], 0xff, 0xe1];
+    data.extend(&(AVC_SPS.len() as u16).to_be_bytes());
+    data.extend(AVC_SPS);
+    data.push(0x01);
+    data.extend(&(AVC_PPS.len() as u16).to_be_bytes());
+    data.extend(AVC_PPS);
+    data
+}
+
+fn hvcc() -> Vec<u8> {
+    let mut data = vec![0x01];
+    data.extend(HEVC_PROFILE);
+    data.extend(&[0xf0, 0x00, 0xfc, 0xfd, 0xf8, 0xf8, 0x1e, 0x00, 0x0f, 0x03]);
+    for unit in &[HEVC_VPS, HEVC_SPS, HEVC_PPS] {
+        data.push(0x80 | ((unit[0] >> 1) & 0x3f));
+        data.extend(&1u16.to_be_bytes());
+        data.extend(&(unit.len() as u16).to_be_bytes());
+        data.extend(*unit);
+    }
+    data
+}
+
+// x264 在第一個 keyframe 前送出的 user data unregistered
+fn sei() -> Vec<u8> {
+    let text = b"x264 - core 164 - H.264/MPEG-4 AVC codec\0";
+    let mut data = vec![0x06, 0x05, (16 + text.len()) as u8];
+    data.extend(&[0xdc, 0x45, 0xe9, 0xbd, 0xe6, 0xd9, 0x48, 0xb7, 0x96, 0x2c, 0xd8, 0x20, 0xd9, 0x23, 0xee, 0xef]);
+    data.extend(&text[..]);
+    data.push(0x80);
+    data
+}
+
+// CEA-608 pop-on 字幕 "HELLO" (RCL, PAC 第 15 列, 文字, EOC), 控制碼各送兩次
+fn caption_sei() -> Vec<u8> {
+    let pairs: &[[u8; 2]] = &[[0x14, 0x20], [0x14, 0x20], [0x14, 0x70], [0x14, 0x70], *b"HE", *b"LL", [b'O', 0x00], [0x14, 0x2f], [0x14, 0x2f]];
+    let mut data = vec![0x06, 0x04, (10 + pairs.len() * 3 + 1) as u8, 0xb5, 0x00, 0x31];
+    data.extend(b"GA94");
+    data.extend(&[0x03, 0x40 | pairs.len() as u8, 0xff]);
+    for pair in pairs {
+        data.extend(&[0xfc, parity(pair[0]), parity(pair[1])]);
+    }
+    data.extend(&[0xff, 0x80]);
+    data
+}
+
+// 奇同位
+fn parity(byte: u8) -> u8 {
+    if byte.count_ones() & 1 == 0 {
+        byte | 0x80
+    } else {
+        byte
+    }
+}
+
+fn slice(header0: u8, header1: u8, length: usize) -> Vec<u8> {
+    let mut data = vec![header0, header1];
+    data.extend((0..length).map(|i| (i * 7 + 1) as u8 | 1));
+    data
+}
+
+// 見 fuzz_targets/nalu.rs
+fn nalu_input(selector: u8, header: &[u8], frame: &[u8]) -> Vec<u8> {
+    [&[selector][..], &(header.len() as u16).to_be_bytes(), header, frame].concat()
+}
+
+fn metadata(video_codec: &str, audio_codec: &str) -> StreamMetadata {
+    StreamMetadata {
+        video_width: Some(1280),
+        video_height: Some(720),
+        video_codec: Some(video_codec.to_string()),
+        video_frame_rate: Some(30.0),
+        video_bitrate_kbps: Some(2500),
+        audio_codec: Some(audio_codec.to_string()),
+        audio_bitrate_kbps: Some(160),
+        audio_sample_rate: Some(48000),
+        audio_channels: Some(2),
+        audio_is_stereo: Some(true),
+        encoder: Some(String::from("obs-output module (libobs version 30.0.0)")),
+    }
+}
+
+// 在同一個程序內執行 client 與 server, 記錄 client 送出的 chunk stream
+struct Publisher {
+    client: ClientSession,
+    server: Server,
+    sent: Vec<u8>,
+}
+
+impl Publisher {
+    // 實際推流只取前兩秒或 1 MB, 以 4096 bytes 為一次讀取
+    const CAPTURE_DURATION: u32 = 2000;
+    const CAPTURE_SIZE: usize = 1 << 20;
+    const CAPTURE_READ: usize = 4096;
+
+    fn new() -> Publisher {
+        let (client, results) = ClientSession::new(ClientSessionConfig::new()).unwrap();
+        let playlists = Arc::new(Mutex::new(PlayLists::new()));
+        let mut server = Server::new(playlists, String::from("seed"));
+        let responses = server.handle_handshake_bytes(&[]).unwrap();
+
+        let mut publisher = Publisher { client, server, sent: Vec::new() };
+        publisher.exchange(results.into_iter().collect(), responses);
+        publisher
+    }
+
+    fn publish(&mut self, stream_key: &str, metadata: Option<StreamMetadata>) {
+        let result = self.client.request_connection(String::from("live")).unwrap();
+        self.exchange(VecDeque::from(vec![result]), Vec::new());
+        let result = self.client.request_publishing(stream_key.to_string(), PublishRequestType::Live).unwrap();
+        self.exchange(VecDeque::from(vec![result]), Vec::new());
+        if let Some(metadata) = metadata {
+            let result = self.client.publish_metadata(&metadata).unwrap();
+            self.exchange(VecDeque::from(vec![result]), Vec::new());
+        }
+    }
+
+    fn video(&mut self, timestamp: u32, data: &[u8]) {
+        let result = self.client.publish_video_data(Bytes::copy_from_slice(data), RtmpTimestamp::new(timestamp), false).unwrap();
+        self.exchange(VecDeque::from(vec![result]), Vec::new());
+    }
+
+    fn audio(&mut self, timestamp: u32, data: &[u8]) {
+        let result = self.client.publish_audio_data(Bytes::copy_from_slice(data), RtmpTimestamp::new(timestamp), false).unwrap();
+        self.exchange(VecDeque::from(vec![result]), Vec::new());
+    }
+
+    // 互相轉送封包直到雙方都沒有回應
+    fn exchange(&mut self, mut outbound: VecDeque<ClientSessionResult>, mut responses: Vec<ServerResult>) {
+        loop {
+            for response in responses.drain(..) {
+                if let ServerResult::Response { packet } = response {
+                    outbound.extend(self.client.handle_input(&packet.bytes).unwrap());
+                }
+            }
+            match outbound.pop_front() {
+                Some(ClientSessionResult::OutboundResponse(packet)) => {
+                    // 見 fuzz_targets/server.rs
+                    self.sent.extend(&(packet.bytes.len() as u16).to_be_bytes());
+                    self.sent.extend(&packet.bytes);
+                    responses = self.server.handle_bytes(&packet.bytes).unwrap();
+                }
+                Some(_) => (),
+                None => break,
+            }
+        }
+    }
+}
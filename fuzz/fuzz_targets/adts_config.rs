@@ -0,0 +1,16 @@
+#![no_main]
+use bytes::Bytes;
+use libfuzzer_sys::fuzz_target;
+use mock_yo_stream::stream::server::adts::{Adts, AdtsConfig};
+
+// AAC sequence header 的 AudioSpecificConfig
+fuzz_target!(|data: &[u8]| {
+    let mut config = AdtsConfig::new();
+    if config.set(Bytes::copy_from_slice(data)).is_ok() {
+        let _ = config.codecs();
+        let _ = config.channels();
+        if config.is_adts() {
+            let _ = Adts::to_es_layer(&config, vec![0; 16]);
+        }
+    }
+});
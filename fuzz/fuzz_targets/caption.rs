@@ -0,0 +1,30 @@
+#![no_main]
+use bytes::Bytes;
+use libfuzzer_sys::fuzz_target;
+use mock_yo_stream::stream::server::caption::Captions;
+use mock_yo_stream::stream::server::flv::VideoCodec;
+use mock_yo_stream::stream::server::nalu::Nalu;
+
+// Selector  u8     bit 0: 編碼, bit 1: 之後為 cc_data ([u8; 3] * n) 而不是 nalu
+// Frame     [u8]   length-prefixed nalu (4 bytes)
+fuzz_target!(|data: &[u8]| {
+    let (selector, frame) = match data.split_first() {
+        Some((selector, frame)) => (*selector, frame),
+        None => return,
+    };
+    let codec = if selector & 1 == 0 { VideoCodec::Avc } else { VideoCodec::Hevc };
+
+    let cc_data = if selector & 0b10 == 0 {
+        match Nalu::read(Bytes::copy_from_slice(frame), 4, codec) {
+            Ok(nalu) => Captions::read_cc_data(&nalu, codec),
+            Err(_) => return,
+        }
+    } else {
+        frame.chunks_exact(3).map(|data| [data[0] & 0x03, data[1], data[2]]).collect()
+    };
+
+    let mut captions = Captions::new();
+    captions.push(0, 0, cc_data);
+    let _ = captions.segment(1000);
+    let _ = captions.last_segment();
+});
@@ -0,0 +1,9 @@
+#![no_main]
+use bytes::Bytes;
+use libfuzzer_sys::fuzz_target;
+use mock_yo_stream::stream::server::flv::Flv;
+
+// RTMP video message 的 body (FLV video tag data)
+fuzz_target!(|data: &[u8]| {
+    let _ = Flv::read_video(Bytes::copy_from_slice(data));
+});
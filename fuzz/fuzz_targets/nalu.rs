@@ -0,0 +1,31 @@
+#![no_main]
+use bytes::Bytes;
+use libfuzzer_sys::fuzz_target;
+use mock_yo_stream::stream::server::flv::VideoCodec;
+use mock_yo_stream::stream::server::nalu::{Nalu, NaluConfig, NaluFilter};
+
+// Selector         u8      bit 0: 編碼, bit 1~2: sequence header 無效時使用的 nalu length - 1, bit 3: 移除 filler 與 sei
+// Config Length    u16
+// Sequence Header  [u8]    avcC / hvcC
+// Frame            [u8]    length-prefixed nalu
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 3 {
+        return;
+    }
+    let selector = data[0];
+    let codec = if selector & 1 == 0 { VideoCodec::Avc } else { VideoCodec::Hevc };
+    let length = (u16::from_be_bytes([data[1], data[2]]) as usize).min(data.len() - 3);
+    let (header, frame) = data[3..].split_at(length);
+
+    // sequence header 無效時以空的設定處理, 相當於 keyframe 比 sequence header 先到
+    let mut config = NaluConfig::new();
+    config.codec = codec;
+    if config.set(codec, Bytes::copy_from_slice(header)).is_err() {
+        config.nalu_size = ((selector >> 1) & 0b11) + 1;
+    }
+
+    if let Ok(nalu) = Nalu::read(Bytes::copy_from_slice(frame), config.nalu_size, codec) {
+        let filter = if selector & 0b1000 == 0 { NaluFilter::new() } else { NaluFilter::parse("filler,sei") };
+        let _ = Nalu::to_es_layer(&config, &filter, nalu);
+    }
+});
@@ -0,0 +1,21 @@
+#![no_main]
+use bytes::Bytes;
+use libfuzzer_sys::fuzz_target;
+use mock_yo_stream::stream::server::flv::VideoCodec;
+use mock_yo_stream::stream::server::nalu::NaluConfig;
+
+// 第一個 byte 選擇編碼, 之後為 avcC / hvcC
+fuzz_target!(|data: &[u8]| {
+    let (selector, data) = match data.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+    let codec = if selector & 1 == 0 { VideoCodec::Avc } else { VideoCodec::Hevc };
+
+    let mut config = NaluConfig::new();
+    if config.set(codec, Bytes::copy_from_slice(data)).is_ok() {
+        let _ = config.codecs();
+        let _ = config.resolution();
+        let _ = config.frame_rate();
+    }
+});
@@ -0,0 +1,55 @@
+#![no_main]
+use std::cell::Cell;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, Once};
+use std::{env, fs};
+use libfuzzer_sys::fuzz_target;
+use mock_yo_stream::playlist::PlayLists;
+use mock_yo_stream::stream::server::Server;
+
+static INIT: Once = Once::new();
+
+thread_local! {
+    static RML_RTMP_PANIC: Cell<bool> = Cell::new(false);
+}
+
+// 握手完成後的 RTMP chunk stream, 以 u16 長度分成多次讀取
+// 推流端會等待 server 回應, connect 與 publish 在同一次讀取時不會被接受
+fuzz_target!(|data: &[u8]| {
+    // 推流時會寫入 ./video, 改在暫存資料夾執行
+    // rml_rtmp 的 chunk 解析在錯誤的輸入下會 panic, 連線的 thread 會結束推流, 這裡只略過這類 panic
+    // libfuzzer 的 panic hook 會直接 abort, 改為記錄 panic 的位置, 其他 panic 仍以預設的 hook 回報
+    INIT.call_once(|| {
+        let _ = panic::take_hook();
+        let default_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            let rml_rtmp = info.location().is_some_and(|location| location.file().contains("rml_rtmp"));
+            RML_RTMP_PANIC.with(|panic| panic.set(rml_rtmp));
+            if !rml_rtmp {
+                default_hook(info);
+            }
+        }));
+        let directory = env::temp_dir().join("mock-yo-stream-fuzz");
+        fs::create_dir_all(&directory).unwrap();
+        env::set_current_dir(&directory).unwrap();
+    });
+
+    let playlists = Arc::new(Mutex::new(PlayLists::new()));
+    let mut server = Server::new(playlists, String::from("fuzz"));
+    if server.handle_handshake_bytes(&[]).is_err() {
+        return;
+    }
+
+    let mut data = data;
+    while data.len() >= 2 {
+        let length = (u16::from_be_bytes([data[0], data[1]]) as usize).min(data.len() - 2);
+        let (bytes, rest) = data[2..].split_at(length);
+        match panic::catch_unwind(AssertUnwindSafe(|| server.handle_bytes(bytes))) {
+            Ok(Ok(_)) => (),
+            Ok(Err(_)) => return,
+            Err(_) if RML_RTMP_PANIC.with(Cell::get) => return,
+            Err(payload) => panic::resume_unwind(payload),
+        }
+        data = rest;
+    }
+});
@@ -1,86 +1,255 @@
+mod connection;
+mod flood;
+pub mod identity;
+mod moderation;
+mod protocol;
+mod room;
+
 use slab::Slab;
-use std::collections::HashSet;
+use std::collections::HashMap;
+use std::env;
+use std::net::IpAddr;
 use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
-use websocket::sync::Server;
-use websocket::OwnedMessage;
-use super::playlist::PlayList;
+use std::time::{Duration, Instant};
+use hyper::{Body, Request as HttpRequest, Response};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::Sender;
+use tokio_tungstenite::tungstenite::Message;
+use super::playlist::PlayLists;
+use super::stats::{StreamMetadata, Viewers};
+use super::metrics;
+use flood::Flood;
+use moderation::Command;
+use protocol::{Event, Mode, ProtocolError, Request};
+use room::{Room, Subject};
+pub use protocol::now;
+pub use room::{log_enabled, read_log};
 
+// 通知對應 stream key 的聊天室
 pub enum ServerMessage {
-    Off,
-    Live,
+    Off(String),
+    Live(String),
+    ViewerCount(String, Viewers),
+    Metadata(String, StreamMetadata),
+    Title(String, Option<String>),
 }
 
-struct ClientMessage {
-    pub id: usize,
-    pub message: OwnedMessage,
+// 聊天室可由 media server 的 /chat 連線, 或另外開啟的 port (CHAT_PORT, 預設 4343, 0 為關閉)
+#[derive(Clone)]
+pub struct ChatServer {
+    playlists: Arc<Mutex<PlayLists>>,
+    state: Arc<Mutex<State>>,
 }
 
-pub struct ChatServer {}
-
 impl ChatServer {
-    pub fn start(playlist: Arc<Mutex<PlayList>>) {
-        let address = "0.0.0.0:4343";
-        let server = Server::bind(address).unwrap();
-        let (tx, rx) = mpsc::channel();
-        let connections_map = Arc::new(Mutex::new(Slab::new()));
-        let connections = Arc::new(Mutex::new(HashSet::new()));
-        handle_message(connections_map.clone(), connections.clone(), rx);
-        handle_status(playlist.clone(), connections_map.clone(), connections.clone());
-
-        thread::spawn(move || {
-            for request in server.filter_map(Result::ok) {
-                if !request.protocols().contains(&String::from("yo-websocket")) {
-                    request.reject().unwrap();
-                    continue;
-                }
+    pub fn start(playlists: Arc<Mutex<PlayLists>>) -> ChatServer {
+        let mut state = State::default();
+        if room::log_enabled() {
+            state.log = Some(room::start_log());
+        }
+        let state = Arc::new(Mutex::new(state));
+        handle_status(playlists.clone(), state.clone());
+        count_viewers(playlists.clone());
+
+        let chat = ChatServer { playlists, state };
+        match env::var("CHAT_PORT").ok().map(|port| port.parse::<u16>()) {
+            Some(Ok(0)) => (),
+            Some(Ok(port)) => chat.listen(format!("0.0.0.0:{}", port)),
+            Some(Err(_)) => println!("invalid CHAT_PORT, standalone chat server disabled"),
+            None => chat.listen(String::from("0.0.0.0:4343")),
+        }
+        chat
+    }
 
-                let client = request.use_protocol("yo-websocket").accept().unwrap();
-                let (mut receiver, sender) = client.split().unwrap();
-                let mut map = connections_map.lock().unwrap();
-                let mut ids = connections.lock().unwrap();
-                let id = map.insert(sender);
-                ids.insert(id);
-
-                let tx_copy = mpsc::Sender::clone(&tx);
-                thread::spawn(move || {
-                    for message in receiver.incoming_messages() {
-                        match message {
-                            Err(_) => return,
-                            Ok(message) => tx_copy.send(ClientMessage { id, message }).unwrap(),
-                        }
+    // GET /chat 或 /chat/{stream key} 的 WebSocket upgrade
+    pub fn upgrade(&self, req: HttpRequest<Body>, address: IpAddr) -> Response<Body> {
+        connection::upgrade(req, address, self.playlists.clone(), self.state.clone())
+    }
+
+    fn listen(&self, address: String) {
+        let playlists = self.playlists.clone();
+        let state = self.state.clone();
+        tokio::spawn(async move {
+            let listener = match TcpListener::bind(&address).await {
+                Ok(listener) => listener,
+                Err(e) => {
+                    println!("chat server error: {}", e);
+                    return;
+                }
+            };
+            println!("chat server on ws://{}", address);
+            loop {
+                match listener.accept().await {
+                    Ok((stream, peer)) => {
+                        tokio::spawn(connection::accept(stream, peer.ip(), playlists.clone(), state.clone()));
                     }
-                });
-                println!("new chat connection!");
+                    Err(e) => println!("chat accept error: {}", e),
+                }
             }
         });
-        println!("chat server on ws://{}", address);
     }
 }
 
-type Sender = websocket::sender::Writer<std::net::TcpStream>;
+const DEFAULT_ROOM: &str = "lobby";
+const VIEWER_COUNT_INTERVAL: Duration = Duration::from_secs(10);
+
+type Rooms = HashMap<String, Room>;
+
+#[derive(Default)]
+struct State {
+    clients: Slab<Client>,
+    rooms: Rooms,
+    message_id: u64,
+    // slab 的 id 會重複使用, 以連線編號確認是同一個連線
+    connections: u64,
+    // CHAT_LOG 時寫入聊天紀錄的 thread
+    log: Option<mpsc::Sender<(String, String)>>,
+}
+
+impl State {
+    fn is_current(&self, id: usize, connection: u64) -> bool {
+        self.clients.get(id).is_some_and(|client| client.connection == connection)
+    }
+}
+
+struct Client {
+    sender: Sender<Message>,
+    connection: u64,
+    // 送出佇列已滿或已關閉, 等待移除
+    dropped: bool,
+    mode: Mode,
+    joined: bool,
+    name: String,
+    verified: bool,
+    moderator: bool,
+    room: String,
+    address: IpAddr,
+    last_message: Option<Instant>,
+    flood: Flood,
+}
+
+impl Client {
+    fn subject(&self) -> Subject {
+        Subject::new(&self.name, self.verified, self.address)
+    }
+}
+
+fn send(client: &mut Client, event: &Event) {
+    if let Some(text) = event.encode(client.mode) {
+        push(client, Message::Text(text));
+    }
+}
+
+// 讀取太慢的使用者不會拖慢其他人, 佇列滿了就斷線
+fn push(client: &mut Client, message: Message) {
+    if client.sender.try_send(message).is_err() {
+        client.dropped = true;
+    }
+}
+
+fn broadcast(map: &mut Slab<Client>, room: &Room, event: &Event) {
+    for &id in &room.clients {
+        if let Some(client) = map.get_mut(id) {
+            send(client, event);
+        }
+    }
+}
+
+fn room_from_path(path: &str) -> Option<String> {
+    let path = path.split('?').next().unwrap_or("").trim_matches('/');
+    if path.is_empty() {
+        None
+    } else {
+        Some(path.to_string())
+    }
+}
+
+fn enter(map: &mut Slab<Client>, rooms: &mut Rooms, id: usize, room: String) {
+    leave(rooms, &map[id].room, id);
+    rooms.entry(room.clone()).or_default().clients.insert(id);
+    map[id].room = room;
+}
+
+fn leave(rooms: &mut Rooms, room: &str, id: usize) {
+    if let Some(room) = rooms.get_mut(room) {
+        room.clients.remove(&id);
+    }
+}
 
-fn handle_status(playlist: Arc<Mutex<PlayList>>, connections_map: Arc<Mutex<Slab<Sender>>>, connections: Arc<Mutex<HashSet<usize>>>) {
+// 移除後送出佇列關閉, 由連線的 task 關閉 socket
+fn disconnect(map: &mut Slab<Client>, rooms: &mut Rooms, id: usize) {
+    let client = map.remove(id);
+    let _ = client.sender.try_send(Message::Close(None));
+    leave(rooms, &client.room, id);
+    metrics::CHAT_ACTIVE_CONNECTIONS.dec();
+}
+
+fn sweep(map: &mut Slab<Client>, rooms: &mut Rooms, playlists: &Mutex<PlayLists>) {
+    let dropped: Vec<usize> = map.iter().filter(|(_, client)| client.dropped).map(|(id, _)| id).collect();
+    if dropped.is_empty() {
+        return;
+    }
+    for id in dropped {
+        disconnect(map, rooms, id);
+        println!("chat client dropped!");
+    }
+    update_viewers(playlists, rooms);
+}
+
+fn replay(client: &mut Client, room: &Room) {
+    for event in room.history() {
+        send(client, event);
+    }
+}
+
+// 連線變動時移除沒有使用者且沒有直播的聊天室, 避免任意的房間名稱讓 rooms 無限增長
+fn update_viewers(playlists: &Mutex<PlayLists>, rooms: &mut Rooms) {
+    let mut playlists = playlists.lock().unwrap();
+    rooms.retain(|name, room| !room.clients.is_empty() || playlists.streams.get(name).is_some_and(|playlist| playlist.live));
+    playlists.chat_connections = rooms.iter().map(|(name, room)| (name.clone(), room.clients.len())).collect();
+}
+
+// stream server 的通知轉為送到該聊天室的事件
+fn relay(server_message: ServerMessage) -> (String, Event) {
+    match server_message {
+        ServerMessage::Live(room) => (room, Event::Live),
+        ServerMessage::Off(room) => (room, Event::Off),
+        ServerMessage::ViewerCount(room, viewers) => (
+            room,
+            Event::ViewerCount {
+                count: viewers.hls + viewers.chat,
+                hls: viewers.hls,
+                chat: viewers.chat,
+            },
+        ),
+        ServerMessage::Metadata(room, metadata) => (room, Event::Metadata(metadata)),
+        ServerMessage::Title(room, title) => (room, Event::Title { title }),
+    }
+}
+
+fn handle_status(playlists: Arc<Mutex<PlayLists>>, state: Arc<Mutex<State>>) {
     thread::spawn(move || {
         let rx = {
-            let playlist = playlist.lock().unwrap();
-            playlist.rx.clone()
+            let playlists = playlists.lock().unwrap();
+            playlists.rx.clone()
         };
         loop {
             match rx.lock().unwrap().recv() {
-                Ok(server_message) => match server_message {
-                    ServerMessage::Live => {
-                        let mut map = connections_map.lock().unwrap();
-                        let ids = connections.lock().unwrap();
-                        for &id in &*ids {
-                            let sender = map.get_mut(id).unwrap();
-                            let message = OwnedMessage::Text(String::from("server@;live"));
-                            sender.send_message(&message).unwrap();
-                        }
-                        println!("live!");
+                Ok(server_message) => {
+                    let (room, event) = relay(server_message);
+                    let mut state = state.lock().unwrap();
+                    let State { clients: map, rooms, .. } = &mut *state;
+                    if let Some(room) = rooms.get(&room) {
+                        broadcast(map, room, &event);
+                    }
+                    sweep(map, rooms, &playlists);
+                    match event {
+                        Event::Live => println!("{} live!", room),
+                        Event::Off => println!("{} off!", room),
+                        _ => (),
                     }
-                    ServerMessage::Off => println!("off!"),
-                },
+                }
                 Err(mpsc::RecvError) => {
                     println!("chat status channel closed!");
                     return;
@@ -90,41 +259,246 @@ fn handle_status(playlist: Arc<Mutex<PlayList>>, connections_map: Arc<Mutex<Slab
     });
 }
 
-fn handle_message(connections_map: Arc<Mutex<Slab<Sender>>>, connections: Arc<Mutex<HashSet<usize>>>, rx: mpsc::Receiver<ClientMessage>) {
+// 定時通知各直播的觀看人數 (HLS + 聊天室)
+fn count_viewers(playlists: Arc<Mutex<PlayLists>>) {
     thread::spawn(move || loop {
-        match rx.recv() {
-            Err(mpsc::RecvError) => {
-                println!("chat channel closed!");
-                return;
-            }
-            Ok(client_message) => {
-                let mut map = connections_map.lock().unwrap();
-                let mut ids = connections.lock().unwrap();
-                match client_message.message {
-                    OwnedMessage::Close(_) => {
-                        let message = OwnedMessage::Close(None);
-                        let sender = map.get_mut(client_message.id).unwrap();
-                        sender.send_message(&message).unwrap();
-                        map.remove(client_message.id);
-                        ids.remove(&client_message.id);
-                        println!("chat client disconnected!");
-                    }
-                    OwnedMessage::Ping(ping) => {
-                        let message = OwnedMessage::Pong(ping);
-                        let sender = map.get_mut(client_message.id).unwrap();
-                        sender.send_message(&message).unwrap();
+        thread::sleep(VIEWER_COUNT_INTERVAL);
+        let playlists = playlists.lock().unwrap();
+        for playlist in playlists.streams.values().filter(|p| p.live) {
+            let viewers = Viewers {
+                hls: playlist.hls_viewers(),
+                chat: playlists.chat_viewers(&playlist.name),
+            };
+            playlists.tx.send(ServerMessage::ViewerCount(playlist.name.clone(), viewers)).unwrap();
+        }
+    });
+}
+
+// 回傳 false 代表連線已被移除
+fn handle_frame(playlists: &Mutex<PlayLists>, state: &Mutex<State>, id: usize, connection: u64, message: Message) -> bool {
+    let mut state = state.lock().unwrap();
+    if !state.is_current(id, connection) {
+        return false;
+    }
+    let State { clients: map, rooms, message_id, log, .. } = &mut *state;
+    match message {
+        Message::Text(text) => {
+            metrics::CHAT_MESSAGES.inc();
+            let mode = map[id].mode;
+            let result = map[id].flood.frame(text.len()).and_then(|_| Request::decode(&text, mode));
+            match result.and_then(|request| handle_request(map, rooms, id, request, message_id)) {
+                Ok(mut event) => {
+                    update_viewers(playlists, rooms);
+                    let room = map[id].room.clone();
+                    let (live, now, recording) = match playlists.lock().unwrap().streams.get(&room) {
+                        Some(playlist) => (playlist.live, playlist.media_time(), playlist.recording()),
+                        None => (false, None, String::from("")),
+                    };
+                    if let Event::Message { media_time, .. } = &mut event {
+                        *media_time = now.filter(|_| live);
+                        rooms.get_mut(&room).unwrap().push(&event);
                     }
-                    OwnedMessage::Text(message) => {
-                        for &id in &*ids {
-                            let sender = map.get_mut(id).unwrap();
-                            sender.send_message(&OwnedMessage::from(format!("client@;{}", message))).unwrap()
-                        }
+                    if let Some(log) = log.as_ref().filter(|_| live) {
+                        room::log(log, &recording, &event, *message_id);
                     }
-                    _ => {
-                        println!("unhandle message");
+                    broadcast(map, &rooms[&room], &event);
+                }
+                Err(error) => reject(map, rooms, playlists, id, error),
+            }
+        }
+        Message::Binary(_) => {
+            let error = ProtocolError::new("binary-not-supported", "binary frames are not supported");
+            reject(map, rooms, playlists, id, error);
+        }
+        // ping 由 tungstenite 自動回應
+        _ => {}
+    }
+    sweep(map, rooms, playlists);
+    state.is_current(id, connection)
+}
+
+// 違規太多次的使用者會被斷線
+fn reject(map: &mut Slab<Client>, rooms: &mut Rooms, playlists: &Mutex<PlayLists>, id: usize, error: ProtocolError) {
+    let abusive = flood::is_violation(&error) && map[id].flood.strike();
+    send(&mut map[id], &Event::from(error));
+    if abusive {
+        send(&mut map[id], &Event::from(ProtocolError::new("flooding", "disconnected for flooding")));
+        disconnect(map, rooms, id);
+        update_viewers(playlists, rooms);
+        println!("chat client disconnected for flooding!");
+    }
+}
+
+// 暱稱不分大小寫, 同一聊天室內不能重複
+fn name_taken(map: &Slab<Client>, rooms: &Rooms, room: &str, id: usize, name: &str) -> bool {
+    let name = name.to_lowercase();
+    let room = match rooms.get(room) {
+        Some(room) => room,
+        None => return false,
+    };
+    room.clients.iter().any(|&other| other != id && map[other].joined && map[other].name.to_lowercase() == name)
+}
+
+fn handle_request(map: &mut Slab<Client>, rooms: &mut Rooms, id: usize, request: Request, message_id: &mut u64) -> Result<Event, ProtocolError> {
+    match request {
+        Request::Join { .. } if map[id].joined => Err(ProtocolError::new("already-joined", "already joined")),
+        Request::Join { name, token, room, key } => {
+            let room = room.unwrap_or_else(|| map[id].room.clone());
+            if !PlayLists::is_valid_key(&room) {
+                return Err(ProtocolError::new("invalid-room", &format!("'{}' is not a valid room", room)));
+            }
+            let (name, verified, mut moderator) = match (token, name) {
+                (Some(token), _) => {
+                    let claims = identity::verify(&token)?;
+                    (claims.name, true, claims.moderator)
+                }
+                (None, Some(name)) => {
+                    identity::validate_name(&name, false)?;
+                    (name, false, false)
+                }
+                (None, None) => (identity::guest_name(id), false, false),
+            };
+            if let Some(key) = key {
+                if !identity::is_admin_key(&key) {
+                    return Err(ProtocolError::new("invalid-key", "admin key is invalid"));
+                }
+                moderator = true;
+            }
+            if name_taken(map, rooms, &room, id, &name) {
+                return Err(ProtocolError::new("name-taken", &format!("'{}' is already in use", name)));
+            }
+            if !moderator && rooms.get(&room).is_some_and(|r| r.is_banned(&Subject::new(&name, verified, map[id].address))) {
+                return Err(ProtocolError::new("banned", "you are banned from this room"));
+            }
+
+            enter(map, rooms, id, room.clone());
+            let client = &mut map[id];
+            client.joined = true;
+            client.name = name.clone();
+            client.verified = verified;
+            client.moderator = moderator;
+            let text = format!("welcome {}! {} people in {}", name, rooms[&room].clients.len(), room);
+            send(client, &Event::System { text, time: protocol::now() });
+            replay(client, &rooms[&room]);
+            Ok(Event::Join { name, verified, time: protocol::now() })
+        }
+        _ if !map[id].joined => Err(ProtocolError::new("not-joined", "send a join message first")),
+        Request::Nick { .. } if map[id].verified => Err(ProtocolError::new("name-locked", "verified names cannot be changed")),
+        Request::Nick { name } => {
+            identity::validate_name(&name, false)?;
+            if name_taken(map, rooms, &map[id].room, id, &name) {
+                return Err(ProtocolError::new("name-taken", &format!("'{}' is already in use", name)));
+            }
+            let old = std::mem::replace(&mut map[id].name, name.clone());
+            Ok(Event::System {
+                text: format!("{} is now known as {}", old, name),
+                time: protocol::now(),
+            })
+        }
+        Request::Delete { id: message_id } if map[id].moderator => moderate(map, rooms, id, Command::Delete(message_id)),
+        Request::Message { text } if Command::is_command(&text) && map[id].moderator => moderate(map, rooms, id, Command::parse(&text)?),
+        Request::Delete { .. } => Err(ProtocolError::new("forbidden", "only moderators can delete messages")),
+        Request::Message { text } if Command::is_command(&text) => Err(ProtocolError::new("forbidden", "only moderators can use commands")),
+        Request::Message { text } => {
+            let client = &map[id];
+            let room = rooms.get_mut(&client.room).unwrap();
+            if !client.moderator {
+                if let Some(secs) = room.timed_out(&client.subject()) {
+                    return Err(ProtocolError::new("timed-out", &format!("you are timed out for {}s", secs)));
+                }
+                if let (Some(slow), Some(last)) = (room.slow, client.last_message) {
+                    let elapsed = last.elapsed();
+                    if elapsed < slow {
+                        return Err(ProtocolError::new("slow-mode", &format!("wait {}s before sending another message", (slow - elapsed).as_secs() + 1)));
                     }
                 }
             }
+
+            let client = &mut map[id];
+            client.flood.message(&text)?;
+            client.last_message = Some(Instant::now());
+            *message_id += 1;
+            Ok(Event::Message {
+                id: *message_id,
+                from: client.name.clone(),
+                verified: client.verified,
+                text,
+                time: protocol::now(),
+                media_time: None,
+            })
         }
-    });
+    }
+}
+
+// 管理員指令, 回傳要廣播到聊天室的事件
+fn moderate(map: &mut Slab<Client>, rooms: &mut Rooms, id: usize, command: Command) -> Result<Event, ProtocolError> {
+    let room_name = map[id].room.clone();
+    let moderator = map[id].name.clone();
+    let targets = |map: &Slab<Client>, rooms: &Rooms, name: &str| -> Vec<usize> {
+        let name = name.to_lowercase();
+        rooms[&room_name].clients.iter().copied().filter(|&other| map[other].joined && map[other].name.to_lowercase() == name).collect()
+    };
+    // 線上使用者的對象, 加上同名的 token 使用者 (不在線上也會生效)
+    let subjects = |map: &Slab<Client>, targets: &[usize], name: &str| -> Vec<Subject> {
+        let mut subjects: Vec<Subject> = targets.iter().map(|&target| map[target].subject()).collect();
+        subjects.push(Subject::User(name.to_lowercase()));
+        subjects
+    };
+    let system = |text: String| Event::System { text, time: protocol::now() };
+
+    match command {
+        Command::Ban(name) | Command::Timeout(name, _) if targets(map, rooms, &name).iter().any(|&target| map[target].moderator) => {
+            Err(ProtocolError::new("forbidden", "moderators cannot be banned or timed out"))
+        }
+        Command::Ban(name) => {
+            let targets = targets(map, rooms, &name);
+            let subjects = subjects(map, &targets, &name);
+            rooms.get_mut(&room_name).unwrap().ban(&name, subjects);
+            for target in targets {
+                disconnect(map, rooms, target);
+            }
+            Ok(system(format!("{} was banned by {}", name, moderator)))
+        }
+        Command::Unban(name) => match rooms.get_mut(&room_name).unwrap().unban(&name) {
+            true => Ok(system(format!("{} was unbanned by {}", name, moderator))),
+            false => Err(ProtocolError::new("not-banned", &format!("{} is not banned", name))),
+        },
+        Command::Timeout(name, secs) => {
+            let subjects = subjects(map, &targets(map, rooms, &name), &name);
+            rooms.get_mut(&room_name).unwrap().timeout(subjects, secs);
+            Ok(system(format!("{} was timed out for {}s by {}", name, secs, moderator)))
+        }
+        Command::Slow(0) => {
+            rooms.get_mut(&room_name).unwrap().slow = None;
+            Ok(system(String::from("slow mode is off")))
+        }
+        Command::Slow(secs) => {
+            rooms.get_mut(&room_name).unwrap().slow = Some(Duration::from_secs(secs));
+            Ok(system(format!("slow mode: one message every {}s", secs)))
+        }
+        Command::Clear => {
+            rooms.get_mut(&room_name).unwrap().clear();
+            Ok(Event::Clear)
+        }
+        Command::Delete(message_id) => {
+            rooms.get_mut(&room_name).unwrap().delete(message_id);
+            Ok(Event::Delete { id: message_id })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relays_viewer_count_and_title() {
+        let (room, event) = relay(ServerMessage::ViewerCount(String::from("show"), Viewers { hls: 2, chat: 3 }));
+        assert_eq!(room, "show");
+        assert!(matches!(event, Event::ViewerCount { count: 5, hls: 2, chat: 3 }));
+        let (room, event) = relay(ServerMessage::Title(String::from("show"), Some(String::from("hi"))));
+        assert_eq!(room, "show");
+        assert!(matches!(event, Event::Title { title: Some(title) } if title == "hi"));
+        assert!(matches!(relay(ServerMessage::Off(String::from("show"))), (_, Event::Off)));
+    }
 }
@@ -0,0 +1,236 @@
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use futures_util::{SinkExt, StreamExt};
+use hyper::header::{CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_PROTOCOL, SEC_WEBSOCKET_VERSION, UPGRADE};
+use hyper::{Body, HeaderMap, StatusCode};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::protocol::{Role, WebSocketConfig};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+use super::super::metrics;
+use super::super::playlist::PlayLists;
+use super::{Client, Flood, Mode, State, Subject};
+
+// 每個使用者最多暫存的送出訊息數, 超過代表讀取太慢
+const QUEUE_SIZE: usize = 256;
+const PING_INTERVAL: Duration = Duration::from_secs(20);
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+pub fn config() -> WebSocketConfig {
+    WebSocketConfig {
+        max_message_size: Some(MAX_MESSAGE_SIZE),
+        max_frame_size: Some(MAX_MESSAGE_SIZE),
+        ..WebSocketConfig::default()
+    }
+}
+
+pub async fn accept(stream: TcpStream, address: IpAddr, playlists: Arc<Mutex<PlayLists>>, state: Arc<Mutex<State>>) {
+    let mut handshake = None;
+    // ErrorResponse 的型別由 tungstenite 決定
+    #[allow(clippy::result_large_err)]
+    let callback = |request: &Request, mut response: Response| -> Result<Response, ErrorResponse> {
+        match negotiate(request.uri().path(), request.headers(), address, &playlists, &state) {
+            Ok((mode, protocol, room)) => {
+                if let Some(protocol) = protocol {
+                    response.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(protocol));
+                }
+                handshake = Some((mode, room));
+                Ok(response)
+            }
+            Err(status) => {
+                let mut response = ErrorResponse::new(None);
+                *response.status_mut() = status;
+                Err(response)
+            }
+        }
+    };
+    let socket = match tokio_tungstenite::accept_hdr_async_with_config(stream, callback, Some(config())).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            println!("chat handshake failed: {}", e);
+            return;
+        }
+    };
+    if let Some((mode, room)) = handshake {
+        serve(socket, mode, room, address, playlists, state).await;
+    }
+}
+
+// media server 上的 /chat/{stream key}
+pub fn upgrade(req: hyper::Request<Body>, address: IpAddr, playlists: Arc<Mutex<PlayLists>>, state: Arc<Mutex<State>>) -> hyper::Response<Body> {
+    let headers = req.headers();
+    let is_upgrade = header_contains(headers, CONNECTION, "upgrade") && header_contains(headers, UPGRADE, "websocket");
+    let key = match headers.get(SEC_WEBSOCKET_KEY) {
+        Some(key) if is_upgrade && headers.get(SEC_WEBSOCKET_VERSION).is_some_and(|v| v == "13") => derive_accept_key(key.as_bytes()),
+        _ => return status_response(StatusCode::BAD_REQUEST),
+    };
+    let path = req.uri().path().trim_start_matches("/chat");
+    let (mode, protocol, room) = match negotiate(path, headers, address, &playlists, &state) {
+        Ok(handshake) => handshake,
+        Err(status) => return status_response(status),
+    };
+
+    tokio::spawn(async move {
+        match hyper::upgrade::on(req).await {
+            Ok(upgraded) => {
+                let socket = WebSocketStream::from_raw_socket(upgraded, Role::Server, Some(config())).await;
+                serve(socket, mode, room, address, playlists, state).await;
+            }
+            Err(e) => println!("chat upgrade failed: {}", e),
+        }
+    });
+
+    let mut response = hyper::Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(CONNECTION, "Upgrade")
+        .header(UPGRADE, "websocket")
+        .header(SEC_WEBSOCKET_ACCEPT, key);
+    if let Some(protocol) = protocol {
+        response = response.header(SEC_WEBSOCKET_PROTOCOL, protocol);
+    }
+    response.body(Body::empty()).unwrap()
+}
+
+fn header_contains(headers: &HeaderMap, name: hyper::header::HeaderName, token: &str) -> bool {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case(token))
+}
+
+fn status_response(status: StatusCode) -> hyper::Response<Body> {
+    hyper::Response::builder().status(status).body(Body::empty()).unwrap()
+}
+
+// 決定子協定與聊天室, ws://{host}/{stream key} 指定聊天室, 未指定時為目前的串流
+// 沒有指定子協定時使用 JSON 格式, 回應也不帶子協定
+pub fn negotiate(path: &str, headers: &HeaderMap, address: IpAddr, playlists: &Mutex<PlayLists>, state: &Mutex<State>) -> Result<(Mode, Option<&'static str>, String), StatusCode> {
+    let protocols: Vec<String> = headers
+        .get_all(SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|protocol| protocol.trim().to_string())
+        .collect();
+    let (mode, protocol) = match Mode::negotiate(&protocols) {
+        Some(mode) => (mode, Some(mode.protocol())),
+        None if protocols.is_empty() => (Mode::Json, None),
+        None => return Err(StatusCode::BAD_REQUEST),
+    };
+
+    let room = match super::room_from_path(path) {
+        Some(room) if !PlayLists::is_valid_key(&room) => return Err(StatusCode::BAD_REQUEST),
+        Some(room) => room,
+        None => playlists.lock().unwrap().current.clone().unwrap_or_else(|| super::DEFAULT_ROOM.to_string()),
+    };
+    // JSON 使用者在 join 時檢查, 管理員不受限制
+    if mode == Mode::Legacy && state.lock().unwrap().rooms.get(&room).is_some_and(|r| r.is_banned(&Subject::Address(address))) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok((mode, protocol, room))
+}
+
+pub async fn serve<S>(socket: WebSocketStream<S>, mode: Mode, room: String, address: IpAddr, playlists: Arc<Mutex<PlayLists>>, state: Arc<Mutex<State>>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut write, mut read) = socket.split();
+    let (tx, mut rx) = mpsc::channel(QUEUE_SIZE);
+    let (id, connection) = {
+        let mut state = state.lock().unwrap();
+        state.connections += 1;
+        let connection = state.connections;
+        let State { clients: map, rooms, .. } = &mut *state;
+        // 舊版頁面沒有 join, 連線即加入
+        let entry = map.vacant_entry();
+        let id = entry.key();
+        entry.insert(Client {
+            sender: tx,
+            connection,
+            dropped: false,
+            mode,
+            joined: mode == Mode::Legacy,
+            name: super::identity::guest_name(id),
+            verified: false,
+            moderator: false,
+            room: room.clone(),
+            address,
+            last_message: None,
+            flood: Flood::new(),
+        });
+        let room = rooms.entry(room).or_default();
+        room.clients.insert(id);
+        if mode == Mode::Legacy {
+            super::replay(&mut map[id], room);
+        }
+        super::update_viewers(&playlists, rooms);
+        (id, connection)
+    };
+    metrics::CHAT_CONNECTIONS.inc();
+    metrics::CHAT_ACTIVE_CONNECTIONS.inc();
+    println!("new chat connection!");
+
+    // 佇列關閉(使用者被移除)時送出 close 並結束
+    tokio::spawn(async move {
+        while let Some(message) = rx.recv().await {
+            let close = matches!(message, Message::Close(_));
+            if write.send(message).await.is_err() || close {
+                break;
+            }
+        }
+        let _ = write.close().await;
+    });
+
+    let mut heartbeat = tokio::time::interval_at(tokio::time::Instant::now() + PING_INTERVAL, PING_INTERVAL);
+    let mut last_seen = Instant::now();
+    loop {
+        tokio::select! {
+            frame = read.next() => match frame {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(message)) => {
+                    last_seen = Instant::now();
+                    if !super::handle_frame(&playlists, &state, id, connection, message) {
+                        break;
+                    }
+                }
+            },
+            _ = heartbeat.tick() => {
+                if last_seen.elapsed() > IDLE_TIMEOUT {
+                    println!("chat client timed out!");
+                    break;
+                }
+                if !ping(&state, id, connection) {
+                    break;
+                }
+            }
+        }
+    }
+
+    // 被管理員或洗版斷線時已經移除
+    let mut state = state.lock().unwrap();
+    if state.is_current(id, connection) {
+        let State { clients: map, rooms, .. } = &mut *state;
+        super::disconnect(map, rooms, id);
+        super::update_viewers(&playlists, rooms);
+        println!("chat client disconnected!");
+    }
+}
+
+fn ping(state: &Mutex<State>, id: usize, connection: u64) -> bool {
+    let mut state = state.lock().unwrap();
+    if !state.is_current(id, connection) {
+        return false;
+    }
+    let client = &mut state.clients[id];
+    super::push(client, Message::Ping(Vec::new()));
+    !client.dropped
+}
@@ -0,0 +1,143 @@
+use std::env;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use super::protocol::ProtocolError;
+
+// 洗版限制, 以環境變數設定:
+// CHAT_RATE 每秒可送出的訊息數, CHAT_BURST 可連續送出的訊息數, CHAT_MAX_LENGTH 訊息長度上限,
+// CHAT_DUPLICATE_WINDOW 幾秒內不能重複相同訊息, CHAT_MAX_STRIKES 違規幾次後斷線
+pub struct Limits {
+    rate: f64,
+    burst: f64,
+    max_length: usize,
+    duplicate_window: Duration,
+    max_strikes: u32,
+}
+
+pub fn limits() -> &'static Limits {
+    static LIMITS: OnceLock<Limits> = OnceLock::new();
+    LIMITS.get_or_init(|| Limits {
+        rate: read_env("CHAT_RATE", 1.0),
+        burst: read_env("CHAT_BURST", 5.0),
+        max_length: read_env("CHAT_MAX_LENGTH", 500),
+        duplicate_window: Duration::from_secs(read_env("CHAT_DUPLICATE_WINDOW", 30)),
+        max_strikes: read_env("CHAT_MAX_STRIKES", 10),
+    })
+}
+
+fn read_env<T: std::str::FromStr>(name: &str, default: T) -> T {
+    env::var(name).ok().and_then(|value| value.parse().ok()).unwrap_or(default)
+}
+
+// 違規的錯誤會累計次數
+const VIOLATIONS: &[&str] = &["rate-limited", "frame-too-large", "message-too-long", "duplicate-message", "binary-not-supported"];
+
+pub fn is_violation(error: &ProtocolError) -> bool {
+    VIOLATIONS.contains(&error.code)
+}
+
+// 每個連線一個 token bucket
+pub struct Flood {
+    tokens: f64,
+    updated: Instant,
+    last_message: Option<(String, Instant)>,
+    strikes: u32,
+}
+
+impl Flood {
+    pub fn new() -> Flood {
+        Flood {
+            tokens: limits().burst,
+            updated: Instant::now(),
+            last_message: None,
+            strikes: 0,
+        }
+    }
+
+    // 每個 text frame 都會檢查
+    pub fn frame(&mut self, length: usize) -> Result<(), ProtocolError> {
+        let limits = limits();
+        // JSON 包裝與跳脫字元的空間
+        if length > limits.max_length * 4 + 1024 {
+            return Err(ProtocolError::new("frame-too-large", "frame is too large"));
+        }
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limits.rate).min(limits.burst);
+        self.updated = now;
+        if self.tokens < 1.0 {
+            return Err(ProtocolError::new("rate-limited", "too many messages, slow down"));
+        }
+        self.tokens -= 1.0;
+        Ok(())
+    }
+
+    pub fn message(&mut self, text: &str) -> Result<(), ProtocolError> {
+        let limits = limits();
+        if text.chars().count() > limits.max_length {
+            return Err(ProtocolError::new("message-too-long", &format!("message must be at most {} characters", limits.max_length)));
+        }
+
+        let normalized = text.trim().to_lowercase();
+        if let Some((last, time)) = &self.last_message {
+            if *last == normalized && time.elapsed() < limits.duplicate_window {
+                return Err(ProtocolError::new("duplicate-message", "message is the same as your last one"));
+            }
+        }
+        self.last_message = Some((normalized, Instant::now()));
+        self.strikes = self.strikes.saturating_sub(1);
+        Ok(())
+    }
+
+    // 回傳 true 時應斷線
+    pub fn strike(&mut self) -> bool {
+        self.strikes += 1;
+        self.strikes >= limits().max_strikes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 預設限制: 每秒 1 則, 可連續 5 則, 10 次違規斷線
+    #[test]
+    fn refills_tokens_up_to_burst() {
+        let mut flood = Flood::new();
+        for _ in 0..5 {
+            assert!(flood.frame(10).is_ok());
+        }
+        assert_eq!(flood.frame(10).err().unwrap().code, "rate-limited");
+
+        flood.updated -= Duration::from_millis(2500);
+        assert!(flood.frame(10).is_ok());
+        assert!(flood.frame(10).is_ok());
+        assert_eq!(flood.frame(10).err().unwrap().code, "rate-limited");
+
+        flood.updated -= Duration::from_secs(60);
+        for _ in 0..5 {
+            assert!(flood.frame(10).is_ok());
+        }
+        assert!(flood.frame(10).is_err());
+        assert_eq!(Flood::new().frame(500 * 4 + 1025).err().unwrap().code, "frame-too-large");
+    }
+
+    #[test]
+    fn escalates_strikes() {
+        let mut flood = Flood::new();
+        for _ in 0..9 {
+            assert!(!flood.strike());
+        }
+        // 正常的訊息會抵銷一次違規
+        assert!(flood.message("hi").is_ok());
+        assert!(!flood.strike());
+        assert!(flood.strike());
+
+        let error = flood.message(" HI ").err().unwrap();
+        assert_eq!(error.code, "duplicate-message");
+        assert!(is_violation(&error));
+        assert_eq!(flood.message(&"a".repeat(501)).err().unwrap().code, "message-too-long");
+        assert!(!is_violation(&ProtocolError::new("not-joined", "send a join message first")));
+    }
+}
@@ -0,0 +1,158 @@
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::sync::OnceLock;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use super::protocol::{self, ProtocolError};
+
+// 暱稱規則: 1~24 個字, 只能是文字、數字、_ 或 -, 保留名稱不分大小寫
+const MAX_NAME_LENGTH: usize = 24;
+const RESERVED_NAMES: &[&str] = &["server", "system", "admin", "administrator", "moderator", "mod", "root"];
+const GUEST_PREFIX: &str = "guest-";
+
+pub fn guest_name(id: usize) -> String {
+    format!("{}{}", GUEST_PREFIX, id)
+}
+
+// 由 token 取得的名稱可以使用保留名稱
+pub fn validate_name(name: &str, allow_reserved: bool) -> Result<(), ProtocolError> {
+    let length = name.chars().count();
+    if length == 0 || length > MAX_NAME_LENGTH {
+        return Err(ProtocolError::new("invalid-name", &format!("name must be 1 to {} characters", MAX_NAME_LENGTH)));
+    }
+    if !name.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-') {
+        return Err(ProtocolError::new("invalid-name", "name may only contain letters, digits, '_' and '-'"));
+    }
+    let lowercase = name.to_lowercase();
+    if allow_reserved {
+        return Ok(());
+    }
+    if RESERVED_NAMES.contains(&lowercase.as_str()) || lowercase.starts_with(GUEST_PREFIX) {
+        return Err(ProtocolError::new("reserved-name", &format!("'{}' is reserved", name)));
+    }
+    Ok(())
+}
+
+// Token: base64url(claims json) + "." + base64url(hmac-sha256(claims json))
+// 由 media server 的 POST /chat/token 發行, 需要 CHAT_ADMIN_KEY
+#[derive(Serialize, Deserialize)]
+pub struct Claims {
+    pub name: String,
+    pub exp: u64,
+    #[serde(default)]
+    pub moderator: bool,
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+// CHAT_TOKEN_SECRET 未設定時每次啟動隨機產生, 重啟後舊 token 失效
+fn secret() -> &'static [u8] {
+    static SECRET: OnceLock<Vec<u8>> = OnceLock::new();
+    SECRET.get_or_init(|| match env::var("CHAT_TOKEN_SECRET") {
+        Ok(secret) if !secret.is_empty() => secret.into_bytes(),
+        _ => {
+            let mut secret = vec![0; 32];
+            File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut secret)).expect("failed to generate chat token secret");
+            secret
+        }
+    })
+}
+
+pub fn admin_key() -> Option<String> {
+    env::var("CHAT_ADMIN_KEY").ok().filter(|key| !key.is_empty())
+}
+
+// 與 CHAT_ADMIN_KEY 比對, 未設定時一律不符
+pub fn is_admin_key(key: &str) -> bool {
+    admin_key().is_some_and(|admin_key| keys_match(&admin_key, key))
+}
+
+// 比對兩者的 HMAC, verify_slice 為固定時間比較, 也不會因長度不同提早結束
+fn keys_match(expected: &str, key: &str) -> bool {
+    let expected = sign(expected.as_bytes()).finalize().into_bytes();
+    sign(key.as_bytes()).verify_slice(&expected).is_ok()
+}
+
+fn sign(payload: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret()).unwrap();
+    mac.update(payload);
+    mac
+}
+
+pub fn issue(claims: &Claims) -> String {
+    let payload = serde_json::to_vec(claims).unwrap();
+    let signature = sign(&payload).finalize().into_bytes();
+    format!("{}.{}", URL_SAFE_NO_PAD.encode(&payload), URL_SAFE_NO_PAD.encode(signature))
+}
+
+pub fn verify(token: &str) -> Result<Claims, ProtocolError> {
+    let invalid = || ProtocolError::new("invalid-token", "token is invalid");
+    let mut parts = token.splitn(2, '.');
+    let payload = URL_SAFE_NO_PAD.decode(parts.next().unwrap_or("")).map_err(|_| invalid())?;
+    let signature = URL_SAFE_NO_PAD.decode(parts.next().unwrap_or("")).map_err(|_| invalid())?;
+    sign(&payload).verify_slice(&signature).map_err(|_| invalid())?;
+
+    let claims: Claims = serde_json::from_slice(&payload).map_err(|_| invalid())?;
+    if claims.exp < protocol::now() / 1000 {
+        return Err(ProtocolError::new("expired-token", "token has expired"));
+    }
+    Ok(claims)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims(name: &str, exp: u64) -> Claims {
+        Claims { name: name.to_string(), exp, moderator: true }
+    }
+
+    #[test]
+    fn issues_and_verifies_tokens() {
+        let token = issue(&claims("yo", protocol::now() / 1000 + 60));
+        let verified = verify(&token).ok().unwrap();
+        assert_eq!(verified.name, "yo");
+        assert!(verified.moderator);
+
+        // 竄改 claims 或簽章
+        let (payload, signature) = token.split_once('.').unwrap();
+        let forged = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims("admin", u64::MAX)).unwrap());
+        assert_eq!(verify(&format!("{}.{}", forged, signature)).err().unwrap().code, "invalid-token");
+        assert_eq!(verify(&format!("{}.{}", payload, &signature[1..])).err().unwrap().code, "invalid-token");
+        assert_eq!(verify(payload).err().unwrap().code, "invalid-token");
+        assert_eq!(verify("").err().unwrap().code, "invalid-token");
+    }
+
+    #[test]
+    fn rejects_expired_tokens() {
+        let token = issue(&claims("yo", protocol::now() / 1000 - 1));
+        assert_eq!(verify(&token).err().unwrap().code, "expired-token");
+    }
+
+    #[test]
+    fn validates_names() {
+        assert!(validate_name("yo_2-x", false).is_ok());
+        assert!(validate_name("名稱", false).is_ok());
+        assert_eq!(validate_name("", false).err().unwrap().code, "invalid-name");
+        assert_eq!(validate_name(&"a".repeat(25), false).err().unwrap().code, "invalid-name");
+        assert!(validate_name(&"a".repeat(24), false).is_ok());
+        assert_eq!(validate_name("yo yo", false).err().unwrap().code, "invalid-name");
+        assert_eq!(validate_name("Admin", false).err().unwrap().code, "reserved-name");
+        assert_eq!(validate_name("MOD", false).err().unwrap().code, "reserved-name");
+        assert_eq!(validate_name("guest-3", false).err().unwrap().code, "reserved-name");
+        assert!(validate_name("Admin", true).is_ok());
+        assert_eq!(validate_name("Ad min", true).err().unwrap().code, "invalid-name");
+    }
+
+    #[test]
+    fn compares_admin_keys() {
+        assert!(keys_match("secret-key", "secret-key"));
+        assert!(!keys_match("secret-key", "secret-kez"));
+        assert!(!keys_match("secret-key", "secret"));
+        assert!(!keys_match("secret-key", ""));
+    }
+}
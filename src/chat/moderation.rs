@@ -0,0 +1,60 @@
+use super::protocol::ProtocolError;
+
+// 管理員指令, 以 / 開頭的訊息:
+// /ban {暱稱} /unban {暱稱} /timeout {暱稱} {秒數} /slow {秒數, 0 為關閉} /clear /delete {訊息 id}
+pub enum Command {
+    Ban(String),
+    Unban(String),
+    Timeout(String, u64),
+    Slow(u64),
+    Clear,
+    Delete(u64),
+}
+
+impl Command {
+    // /timeout 與 /slow 的上限 (30 天), 避免 Instant 與 Duration 的運算溢位
+    const MAX_SECS: u64 = 30 * 24 * 60 * 60;
+
+    pub fn is_command(text: &str) -> bool {
+        text.starts_with('/')
+    }
+
+    pub fn parse(text: &str) -> Result<Command, ProtocolError> {
+        let mut args = text.split_whitespace();
+        let command = args.next().unwrap_or("");
+        let args: Vec<&str> = args.collect();
+        let usage = |usage: &str| ProtocolError::new("invalid-command", &format!("usage: {}", usage));
+        let secs = |secs: &str| secs.parse().ok().filter(|secs| *secs <= Command::MAX_SECS);
+        let secs_usage = |command: &str| usage(&format!("{} (secs <= {})", command, Command::MAX_SECS));
+
+        match (command, args.as_slice()) {
+            ("/ban", [name]) => Ok(Command::Ban(name.to_string())),
+            ("/ban", _) => Err(usage("/ban <name>")),
+            ("/unban", [name]) => Ok(Command::Unban(name.to_string())),
+            ("/unban", _) => Err(usage("/unban <name>")),
+            ("/timeout", [name, value]) => secs(value).map(|secs| Command::Timeout(name.to_string(), secs)).ok_or_else(|| secs_usage("/timeout <name> <secs>")),
+            ("/timeout", _) => Err(secs_usage("/timeout <name> <secs>")),
+            ("/slow", [value]) => secs(value).map(Command::Slow).ok_or_else(|| secs_usage("/slow <secs>")),
+            ("/slow", _) => Err(secs_usage("/slow <secs>")),
+            ("/clear", []) => Ok(Command::Clear),
+            ("/clear", _) => Err(usage("/clear")),
+            ("/delete", [id]) => id.parse().map(Command::Delete).map_err(|_| usage("/delete <message id>")),
+            ("/delete", _) => Err(usage("/delete <message id>")),
+            _ => Err(ProtocolError::new("unknown-command", &format!("unknown command {}", command))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounds_timeout_and_slow() {
+        assert!(matches!(Command::parse("/timeout alice 600"), Ok(Command::Timeout(name, 600)) if name == "alice"));
+        assert!(matches!(Command::parse("/slow 2592000"), Ok(Command::Slow(2592000))));
+        assert!(Command::parse("/timeout alice 18446744073709551615").is_err());
+        assert!(Command::parse("/slow 18446744073709551615").is_err());
+        assert!(Command::parse("/slow 2592001").is_err());
+    }
+}
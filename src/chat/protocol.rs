@@ -0,0 +1,240 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use super::super::stats::StreamMetadata;
+
+// 聊天室訊息格式 (JSON), 每個訊息都帶版本號 v
+// client -> server: {"v":1,"type":"join","room":"{stream key}","name":"..."} / {"v":1,"type":"message","text":"..."} / {"v":1,"type":"nick","name":"..."}
+//                   {"v":1,"type":"delete","id":1} (管理員)
+// server -> client: {"v":1,"type":"message","id":1,"from":"...","verified":false,"text":"...","time":1620000000000} ...
+//
+// 舊版頁面使用 yo-websocket 子協定, 以字串溝通:
+// client -> server: 訊息內容
+// server -> client: client@;{訊息內容} / server@;live / server@;off
+pub const VERSION: u8 = 1;
+pub const PROTOCOL: &str = "yo-chat";
+pub const LEGACY_PROTOCOL: &str = "yo-websocket";
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Mode {
+    Json,
+    Legacy,
+}
+
+impl Mode {
+    // 優先使用 JSON 格式
+    pub fn negotiate(protocols: &[String]) -> Option<Mode> {
+        if protocols.iter().any(|p| p == PROTOCOL) {
+            Some(Mode::Json)
+        } else if protocols.iter().any(|p| p == LEGACY_PROTOCOL) {
+            Some(Mode::Legacy)
+        } else {
+            None
+        }
+    }
+
+    pub fn protocol(&self) -> &'static str {
+        match self {
+            Mode::Json => PROTOCOL,
+            Mode::Legacy => LEGACY_PROTOCOL,
+        }
+    }
+}
+
+#[derive(Serialize, Clone)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Event {
+    Join { name: String, verified: bool, time: u64 },
+    // media_time: 直播中送出時的串流時間(ms), 與 ts 檔名的時間相同
+    Message {
+        id: u64,
+        from: String,
+        verified: bool,
+        text: String,
+        time: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        media_time: Option<u64>,
+    },
+    System { text: String, time: u64 },
+    Delete { id: u64 },
+    Clear,
+    Live,
+    Off,
+    ViewerCount { count: usize, hls: usize, chat: usize },
+    Metadata(StreamMetadata),
+    Title { title: Option<String> },
+    Error { code: &'static str, message: String },
+}
+
+#[derive(Serialize)]
+struct OutgoingEnvelope<'a> {
+    v: u8,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Request {
+    Join {
+        #[serde(default)]
+        name: Option<String>,
+        #[serde(default)]
+        token: Option<String>,
+        #[serde(default)]
+        room: Option<String>,
+        // CHAT_ADMIN_KEY, 以管理員身分加入
+        #[serde(default)]
+        key: Option<String>,
+    },
+    Message { text: String },
+    Nick { name: String },
+    Delete { id: u64 },
+}
+
+#[derive(Deserialize)]
+struct IncomingEnvelope {
+    v: u8,
+    #[serde(flatten)]
+    request: Request,
+}
+
+pub struct ProtocolError {
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl ProtocolError {
+    pub fn new(code: &'static str, message: &str) -> ProtocolError {
+        ProtocolError { code, message: message.to_string() }
+    }
+}
+
+impl From<ProtocolError> for Event {
+    fn from(error: ProtocolError) -> Event {
+        Event::Error {
+            code: error.code,
+            message: error.message,
+        }
+    }
+}
+
+impl Event {
+    // 舊版格式無法表示的事件回傳 None
+    pub fn encode(&self, mode: Mode) -> Option<String> {
+        match mode {
+            Mode::Json => serde_json::to_string(&OutgoingEnvelope { v: VERSION, event: self }).ok(),
+            Mode::Legacy => match self {
+                Event::Message { text, .. } => Some(format!("client@;{}", text)),
+                Event::Live => Some(String::from("server@;live")),
+                Event::Off => Some(String::from("server@;off")),
+                _ => None,
+            },
+        }
+    }
+}
+
+impl Request {
+    pub fn decode(text: &str, mode: Mode) -> Result<Request, ProtocolError> {
+        let request = match mode {
+            Mode::Legacy => Request::Message { text: text.to_string() },
+            Mode::Json => {
+                let value: serde_json::Value = serde_json::from_str(text).map_err(|e| ProtocolError::new("invalid-message", &e.to_string()))?;
+                Request::check_fields(&value)?;
+                let envelope: IncomingEnvelope = serde_json::from_value(value).map_err(|e| ProtocolError::new("invalid-message", &e.to_string()))?;
+                if envelope.v != VERSION {
+                    return Err(ProtocolError::new("unsupported-version", &format!("protocol version {} is not supported", envelope.v)));
+                }
+                envelope.request
+            }
+        };
+
+        if let Request::Message { text } = &request {
+            if text.trim().is_empty() {
+                return Err(ProtocolError::new("empty-message", "message text is empty"));
+            }
+        }
+        Ok(request)
+    }
+
+    // serde 的 flatten 不支援 deny_unknown_fields, 另外檢查欄位; 未知的 type 由 serde 回報
+    fn check_fields(value: &serde_json::Value) -> Result<(), ProtocolError> {
+        let object = match value.as_object() {
+            Some(object) => object,
+            None => return Err(ProtocolError::new("invalid-message", "message must be a JSON object")),
+        };
+        let fields: &[&str] = match object.get("type").and_then(|t| t.as_str()) {
+            Some("join") => &["name", "token", "room", "key"],
+            Some("message") => &["text"],
+            Some("nick") => &["name"],
+            Some("delete") => &["id"],
+            _ => return Ok(()),
+        };
+        match object.keys().find(|k| !matches!(k.as_str(), "v" | "type") && !fields.contains(&k.as_str())) {
+            Some(field) => Err(ProtocolError::new("invalid-message", &format!("unknown field `{}`", field))),
+            None => Ok(()),
+        }
+    }
+}
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json(event: &Event) -> serde_json::Value {
+        serde_json::from_str(&event.encode(Mode::Json).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn encodes_events_with_version_and_type() {
+        let message = Event::Message { id: 7, from: String::from("yo"), verified: true, text: String::from("hi"), time: 1620000000000, media_time: None };
+        assert_eq!(
+            json(&message),
+            serde_json::json!({ "v": 1, "type": "message", "id": 7, "from": "yo", "verified": true, "text": "hi", "time": 1620000000000u64 })
+        );
+        let message = Event::Message { id: 7, from: String::from("yo"), verified: false, text: String::from("hi"), time: 0, media_time: Some(4000) };
+        assert_eq!(json(&message)["media_time"], 4000);
+        assert_eq!(json(&Event::Clear), serde_json::json!({ "v": 1, "type": "clear" }));
+        assert_eq!(json(&Event::ViewerCount { count: 3, hls: 2, chat: 1 }), serde_json::json!({ "v": 1, "type": "viewer-count", "count": 3, "hls": 2, "chat": 1 }));
+        assert_eq!(json(&Event::Title { title: None }), serde_json::json!({ "v": 1, "type": "title", "title": null }));
+        let error = Event::from(ProtocolError::new("empty-message", "message text is empty"));
+        assert_eq!(json(&error), serde_json::json!({ "v": 1, "type": "error", "code": "empty-message", "message": "message text is empty" }));
+    }
+
+    #[test]
+    fn decodes_requests() {
+        let join = Request::decode(r#"{"v":1,"type":"join","room":"show","name":"yo","token":"t","key":"k"}"#, Mode::Json);
+        assert!(matches!(join, Ok(Request::Join { name: Some(n), token: Some(t), room: Some(r), key: Some(k) }) if n == "yo" && t == "t" && r == "show" && k == "k"));
+        let join = Request::decode(r#"{"v":1,"type":"join"}"#, Mode::Json);
+        assert!(matches!(join, Ok(Request::Join { name: None, token: None, room: None, key: None })));
+        assert!(matches!(Request::decode(r#"{"v":1,"type":"message","text":"hi"}"#, Mode::Json), Ok(Request::Message { text }) if text == "hi"));
+        assert!(matches!(Request::decode(r#"{"v":1,"type":"nick","name":"yo"}"#, Mode::Json), Ok(Request::Nick { name }) if name == "yo"));
+        assert!(matches!(Request::decode(r#"{"v":1,"type":"delete","id":3}"#, Mode::Json), Ok(Request::Delete { id: 3 })));
+    }
+
+    #[test]
+    fn rejects_invalid_requests() {
+        let code = |text: &str| Request::decode(text, Mode::Json).err().map(|e| e.code);
+        assert_eq!(code(r#"{"v":1,"type":"message","text":"hi","from":"admin"}"#), Some("invalid-message"));
+        assert_eq!(code(r#"{"v":1,"type":"join","verified":true}"#), Some("invalid-message"));
+        assert_eq!(code(r#"{"v":1,"type":"shout","text":"hi"}"#), Some("invalid-message"));
+        assert_eq!(code(r#"{"type":"message","text":"hi"}"#), Some("invalid-message"));
+        assert_eq!(code(r#"["message"]"#), Some("invalid-message"));
+        assert_eq!(code(r#"{"v":2,"type":"message","text":"hi"}"#), Some("unsupported-version"));
+        assert_eq!(code(r#"{"v":1,"type":"message","text":"  "}"#), Some("empty-message"));
+    }
+
+    #[test]
+    fn legacy_round_trip() {
+        assert!(matches!(Request::decode("hello", Mode::Legacy), Ok(Request::Message { text }) if text == "hello"));
+        assert!(Request::decode(" ", Mode::Legacy).is_err());
+        let message = Event::Message { id: 1, from: String::from("yo"), verified: false, text: String::from("hello"), time: 0, media_time: None };
+        assert_eq!(message.encode(Mode::Legacy).as_deref(), Some("client@;hello"));
+        assert_eq!(Event::Live.encode(Mode::Legacy).as_deref(), Some("server@;live"));
+        assert_eq!(Event::Off.encode(Mode::Legacy).as_deref(), Some("server@;off"));
+        assert_eq!(Event::Clear.encode(Mode::Legacy), None);
+    }
+}
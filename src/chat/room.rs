@@ -0,0 +1,230 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::net::IpAddr;
+use std::sync::{mpsc, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+use super::protocol::{Event, Mode};
+
+// 新加入的使用者會收到最近的訊息, 數量由 CHAT_HISTORY 設定
+const DEFAULT_HISTORY: usize = 50;
+
+// ban 與 timeout 的對象: 有 token 的使用者以 token 中的名稱(小寫), 訪客以 IP, 改暱稱無法避開
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Subject {
+    User(String),
+    Address(IpAddr),
+}
+
+impl Subject {
+    pub fn new(name: &str, verified: bool, address: IpAddr) -> Subject {
+        match verified {
+            true => Subject::User(name.to_lowercase()),
+            false => Subject::Address(address),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Room {
+    pub clients: HashSet<usize>,
+    history: VecDeque<Event>,
+    // /ban 使用的暱稱(小寫) -> 被 ban 的對象, /unban 時以暱稱解除
+    bans: HashMap<String, Vec<Subject>>,
+    timeouts: HashMap<Subject, Instant>,
+    pub slow: Option<Duration>,
+}
+
+impl Room {
+    pub fn push(&mut self, event: &Event) {
+        let capacity = history_capacity();
+        if capacity == 0 {
+            return;
+        }
+        if self.history.len() >= capacity {
+            self.history.pop_front();
+        }
+        self.history.push_back(event.clone());
+    }
+
+    pub fn history(&self) -> impl Iterator<Item = &Event> {
+        self.history.iter()
+    }
+
+    pub fn delete(&mut self, message_id: u64) {
+        self.history.retain(|event| !matches!(event, Event::Message { id, .. } if *id == message_id));
+    }
+
+    pub fn clear(&mut self) {
+        self.history.clear();
+    }
+
+    pub fn ban(&mut self, name: &str, subjects: Vec<Subject>) {
+        self.bans.entry(name.to_lowercase()).or_default().extend(subjects);
+    }
+
+    pub fn unban(&mut self, name: &str) -> bool {
+        self.bans.remove(&name.to_lowercase()).is_some()
+    }
+
+    pub fn is_banned(&self, subject: &Subject) -> bool {
+        self.bans.values().any(|subjects| subjects.contains(subject))
+    }
+
+    pub fn timeout(&mut self, subjects: Vec<Subject>, secs: u64) {
+        for subject in subjects {
+            if secs == 0 {
+                self.timeouts.remove(&subject);
+            } else if let Some(until) = Instant::now().checked_add(Duration::from_secs(secs)) {
+                self.timeouts.insert(subject, until);
+            }
+        }
+    }
+
+    // 回傳剩餘秒數
+    pub fn timed_out(&mut self, subject: &Subject) -> Option<u64> {
+        let until = *self.timeouts.get(subject)?;
+        let now = Instant::now();
+        if until <= now {
+            self.timeouts.remove(subject);
+            return None;
+        }
+        Some((until - now).as_secs() + 1)
+    }
+}
+
+fn history_capacity() -> usize {
+    static HISTORY: OnceLock<usize> = OnceLock::new();
+    *HISTORY.get_or_init(|| env::var("CHAT_HISTORY").ok().and_then(|n| n.parse().ok()).unwrap_or(DEFAULT_HISTORY))
+}
+
+// CHAT_LOG=1 時, 直播中的聊天訊息以 JSON Lines 附加到該次直播的錄影資料夾 (PlayList::recording) 中的 chat.jsonl
+// 刪除與清除另外寫入 chat-deleted.jsonl, 回放時讀到時間範圍的結尾就能停止
+pub fn log_enabled() -> bool {
+    matches!(env::var("CHAT_LOG").as_deref(), Ok("1") | Ok("true"))
+}
+
+// 檔案由另一個 thread 寫入, 持有聊天室的 lock 時只送出 (檔案, 內容), 寫入順序與送出的順序相同
+pub fn start_log() -> mpsc::Sender<(String, String)> {
+    let (tx, rx) = mpsc::channel::<(String, String)>();
+    thread::spawn(move || {
+        for (path, line) in rx {
+            let result = OpenOptions::new().create(true).append(true).open(&path).and_then(|mut file| writeln!(file, "{}", line));
+            if let Err(e) = result {
+                println!("failed to write chat log {}: {}", path, e);
+            }
+        }
+    });
+    tx
+}
+
+// clear 記錄當時最後的訊息 id, 回放時 id 不大於它的訊息都已被清除
+pub fn log(log: &mpsc::Sender<(String, String)>, recording: &str, event: &Event, last_message_id: u64) {
+    let (file, line) = match event {
+        Event::Message { .. } => ("chat.jsonl", event.encode(Mode::Json)),
+        Event::Delete { .. } => ("chat-deleted.jsonl", event.encode(Mode::Json)),
+        Event::Clear => ("chat-deleted.jsonl", Some(serde_json::json!({ "v": 1, "type": "clear", "id": last_message_id }).to_string())),
+        _ => return,
+    };
+    if let Some(line) = line {
+        let _ = log.send((format!("{}/{}", recording, file), line));
+    }
+}
+
+// 回放錄影時使用, 回傳 media_time 在 [from, to) 之間的訊息(ms), 已刪除的訊息不會出現
+// 同一次直播的 media_time 依序遞增, 讀到 to 之後的訊息就停止
+pub fn read_log(recording: &str, from: u64, to: u64) -> Option<Vec<serde_json::Value>> {
+    let file = File::open(format!("{}/chat.jsonl", recording)).ok()?;
+    let mut deleted = HashSet::new();
+    let mut cleared = 0;
+    for line in fs::read_to_string(format!("{}/chat-deleted.jsonl", recording)).unwrap_or_default().lines() {
+        let event: serde_json::Value = match serde_json::from_str(line) {
+            Ok(event) => event,
+            Err(_) => continue,
+        };
+        match (event["type"].as_str(), event["id"].as_u64()) {
+            (Some("delete"), Some(id)) => {
+                deleted.insert(id);
+            }
+            (Some("clear"), Some(id)) => cleared = cleared.max(id),
+            _ => (),
+        }
+    }
+
+    let mut messages: Vec<serde_json::Value> = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        let event: serde_json::Value = match serde_json::from_str(&line) {
+            Ok(event) => event,
+            Err(_) => continue,
+        };
+        let (id, time) = match (event["id"].as_u64(), event["media_time"].as_u64()) {
+            (Some(id), Some(time)) => (id, time),
+            _ => continue,
+        };
+        if time >= to {
+            break;
+        }
+        if time >= from && id > cleared && !deleted.contains(&id) {
+            messages.push(event);
+        }
+    }
+    Some(messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bans_and_timeouts_follow_subjects() {
+        let guest: IpAddr = "10.0.0.1".parse().unwrap();
+        let mut room = Room::default();
+        room.ban("Yo", vec![Subject::new("yo", false, guest), Subject::User(String::from("yo"))]);
+        // 訪客改暱稱仍以 IP 判斷, token 使用者不受同 IP 的訪客影響
+        assert!(room.is_banned(&Subject::new("other", false, guest)));
+        assert!(room.is_banned(&Subject::new("YO", true, "10.0.0.2".parse().unwrap())));
+        assert!(!room.is_banned(&Subject::new("other", true, guest)));
+        assert!(room.unban("yo"));
+        assert!(!room.is_banned(&Subject::new("other", false, guest)));
+
+        room.timeout(vec![Subject::Address(guest)], 60);
+        assert_eq!(room.timed_out(&Subject::new("renamed", false, guest)), Some(60));
+        room.timeout(vec![Subject::Address(guest)], 0);
+        assert_eq!(room.timed_out(&Subject::Address(guest)), None);
+    }
+
+    #[test]
+    fn reads_log_until_window_ends() {
+        let recording = std::env::temp_dir().join(format!("mock-yo-stream-chat-log-{}", std::process::id()));
+        fs::create_dir_all(&recording).unwrap();
+        let recording = recording.to_string_lossy().to_string();
+        let message = |id: u64, media_time: u64| Event::Message { id, from: String::from("yo"), verified: false, text: id.to_string(), time: 0, media_time: Some(media_time) };
+        let (tx, rx) = mpsc::channel();
+        for id in 1..=6 {
+            log(&tx, &recording, &message(id, id * 1000), id);
+        }
+        log(&tx, &recording, &Event::Clear, 2);
+        log(&tx, &recording, &Event::Delete { id: 4 }, 6);
+        log(&tx, &recording, &Event::Live, 6);
+        drop(tx);
+        for (path, line) in rx {
+            let mut file = OpenOptions::new().create(true).append(true).open(path).unwrap();
+            writeln!(file, "{}", line).unwrap();
+        }
+        // 結尾之後的壞資料不會被讀到
+        writeln!(OpenOptions::new().append(true).open(format!("{}/chat.jsonl", recording)).unwrap(), "not json").unwrap();
+
+        let ids = |from: u64, to: u64| -> Vec<u64> { read_log(&recording, from, to).unwrap().iter().map(|m| m["id"].as_u64().unwrap()).collect() };
+        assert_eq!(ids(0, u64::MAX), vec![3, 5, 6]);
+        assert_eq!(ids(3000, 6000), vec![3, 5]);
+        assert_eq!(ids(5500, 6000), Vec::<u64>::new());
+        fs::remove_dir_all(&recording).unwrap();
+        assert!(read_log(&recording, 0, u64::MAX).is_none());
+    }
+}
@@ -0,0 +1,9 @@
+// 各結構以 new() 建立, 不另外實作 Default
+#![allow(clippy::new_without_default)]
+
+pub mod chat;
+pub mod media;
+mod metrics;
+pub mod playlist;
+mod stats;
+pub mod stream;
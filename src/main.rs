@@ -1,14 +1,10 @@
-mod chat;
-mod media;
-mod playlist;
-mod stream;
-
 use std::sync::{Arc, Mutex};
+use mock_yo_stream::{chat, media, playlist, stream};
 
 #[tokio::main]
 async fn main() {
-    let playlist = Arc::new(Mutex::new(playlist::PlayList::new()));
-    stream::StreamServer::start(playlist.clone());
-    chat::ChatServer::start(playlist.clone());
-    media::MediaServer::start(playlist.clone()).await;
+    let playlists = Arc::new(Mutex::new(playlist::PlayLists::new()));
+    stream::StreamServer::start(playlists.clone());
+    let chat = chat::ChatServer::start(playlists.clone());
+    media::MediaServer::start(playlists.clone(), chat).await;
 }
@@ -1,17 +1,27 @@
+use std::fs;
+use std::net::IpAddr;
 use std::sync::{Arc, Mutex};
 use tokio::fs::File;
 use tokio_util::codec::{BytesCodec, FramedRead};
+use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Method, Request, Response, Server, StatusCode};
-use super::playlist::PlayList;
+use serde::Deserialize;
+use super::chat::identity::{self, Claims};
+use super::chat::ChatServer;
+use super::playlist::{PlayList, PlayLists, SpliceRequest};
+use super::stats::StreamInfo;
+use super::metrics;
 
 pub struct MediaServer {}
 impl MediaServer {
-    pub async fn start(playlist: Arc<Mutex<PlayList>>) {
+    pub async fn start(playlists: Arc<Mutex<PlayLists>>, chat: ChatServer) {
         let address = "0.0.0.0:1337".parse().unwrap();
-        let make_service = make_service_fn(move |_| {
-            let playlist = playlist.clone();
-            async { Ok::<_, hyper::Error>(service_fn(move |request| handle_request(request, playlist.clone()))) }
+        let make_service = make_service_fn(move |conn: &AddrStream| {
+            let playlists = playlists.clone();
+            let chat = chat.clone();
+            let address = conn.remote_addr().ip();
+            async move { Ok::<_, hyper::Error>(service_fn(move |request| handle_request(request, playlists.clone(), chat.clone(), address))) }
         });
         let server = Server::bind(&address).serve(make_service);
         println!("media server on http://{}", address);
@@ -21,39 +31,396 @@ impl MediaServer {
     }
 }
 
-async fn handle_request(req: Request<Body>, playlist: Arc<Mutex<PlayList>>) -> Result<Response<Body>, hyper::Error> {
+async fn handle_request(req: Request<Body>, playlists: Arc<Mutex<PlayLists>>, chat: ChatServer, address: IpAddr) -> Result<Response<Body>, hyper::Error> {
+    let route = route_name(req.method(), req.uri().path());
+    let response = route_request(req, playlists, chat, address).await?;
+    metrics::http_request(route, response.status().as_u16());
+    Ok(response)
+}
+
+// metrics 用的 route label, 不含 stream key 等變動部分
+// 與 route_request 的順序相同, GET /chat/token 是聊天室的 upgrade
+fn route_name(method: &Method, path: &str) -> &'static str {
+    match path {
+        "/status" => "/status",
+        "/metrics" => "/metrics",
+        "/streams" => "/streams",
+        "/chat/token" if method == Method::POST => "/chat/token",
+        _ if path == "/chat" || path.starts_with("/chat/") => "/chat",
+        _ if path.starts_with("/streams/") && path.ends_with("/title") => "/streams/{key}/title",
+        _ if path.starts_with("/streams/") && path.ends_with("/chat") => "/streams/{key}/chat",
+        _ if path.starts_with("/streams/") && path.ends_with("/metadata") => "/streams/{key}/metadata",
+        _ if path.starts_with("/streams/") && path.ends_with("/cue") => "/streams/{key}/cue",
+        _ if path.starts_with("/streams/") && path.ends_with("/recordings") => "/streams/{key}/recordings",
+        _ if path.starts_with("/streams/") => "/streams/{key}",
+        _ if path.starts_with("/recordings/") => "/recordings",
+        _ if path.ends_with(".m3u8") => "playlist",
+        _ if path.ends_with(".ts") || path.ends_with(".vtt") => "segment",
+        _ => "other",
+    }
+}
+
+async fn route_request(req: Request<Body>, playlists: Arc<Mutex<PlayLists>>, chat: ChatServer, address: IpAddr) -> Result<Response<Body>, hyper::Error> {
     match (req.method(), req.uri().path()) {
+        (&Method::GET, path) if path == "/chat" || path.starts_with("/chat/") => Ok(chat.upgrade(req, address)),
         (&Method::GET, "/status") => {
-            let playlist = playlist.lock().unwrap();
-            let json = format!("{{\"live\": {}}}", playlist.live);
-            Ok(Response::builder()
-                .status(StatusCode::OK)
-                .header("Access-Control-Allow-Origin", "*")
-                .header("content-type", "application/json")
-                .body(json.into())
-                .unwrap())
+            let playlists = playlists.lock().unwrap();
+            let live = playlists.current().is_some_and(|p| p.live);
+            let json = format!("{{\"live\": {}}}", live);
+            Ok(json_response(json))
+        }
+        (&Method::GET, "/metrics") => Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header("content-type", "text/plain; version=0.0.4")
+            .body(metrics::render().into())
+            .unwrap()),
+        (&Method::GET, "/streams") => {
+            let playlists = playlists.lock().unwrap();
+            let mut infos: Vec<StreamInfo> = playlists.streams.values().map(|p| StreamInfo::new(p, playlists.chat_viewers(&p.name))).collect();
+            infos.sort_by(|a, b| a.key.cmp(&b.key));
+            Ok(json_response(serde_json::to_string(&infos).unwrap()))
         }
-        (&Method::GET, "/video.m3u8") => {
-            let playlist = playlist.lock().unwrap();
-            if playlist.live {
-                let m3u8 = playlist.m3u8.clone();
-                return Ok(Response::builder()
-                    .status(StatusCode::OK)
-                    .header("Access-Control-Allow-Origin", "*")
-                    .header("Cache-Control", "no-cache, no-store, must-revalidate")
-                    .header("Pragma", "no-cache")
-                    .header("Expires", "0")
-                    .header("content-type", "application/vnd.apple.mpegurl")
-                    .body(m3u8.into())
-                    .unwrap());
+        (&Method::GET, path) if path.starts_with("/streams/") && path.ends_with("/chat") => {
+            let key = &path["/streams/".len()..path.len() - "/chat".len()];
+            Ok(chat_replay(&req, key))
+        }
+        (&Method::GET, path) if path.starts_with("/streams/") && path.ends_with("/recordings") => {
+            let key = &path["/streams/".len()..path.len() - "/recordings".len()];
+            Ok(recordings(key))
+        }
+        (&Method::GET, path) if path.starts_with("/streams/") => {
+            let playlists = playlists.lock().unwrap();
+            match playlists.streams.get(&path["/streams/".len()..]) {
+                Some(playlist) => Ok(json_response(serde_json::to_string(&StreamInfo::new(playlist, playlists.chat_viewers(&playlist.name))).unwrap())),
+                None => Ok(file_not_found()),
             }
-            Ok(file_not_found())
         }
+        (&Method::POST, "/chat/token") => issue_token(req).await,
+        (&Method::PUT, path) if path.starts_with("/streams/") && path.ends_with("/title") => {
+            let key = path["/streams/".len()..path.len() - "/title".len()].to_string();
+            set_title(req, playlists, &key).await
+        }
+        (&Method::POST, path) if path.starts_with("/streams/") && path.ends_with("/metadata") => {
+            let key = path["/streams/".len()..path.len() - "/metadata".len()].to_string();
+            push_metadata(req, playlists, &key).await
+        }
+        (&Method::POST, path) if path.starts_with("/streams/") && path.ends_with("/cue") => {
+            let key = path["/streams/".len()..path.len() - "/cue".len()].to_string();
+            push_cue(req, playlists, &key).await
+        }
+        (&Method::GET, path) if path.starts_with("/recordings/") && !path.contains("..") => file_response(&format!(".{}", path)).await,
+        (&Method::GET, path) if path.ends_with(".m3u8") => {
+            let mut playlists = playlists.lock().unwrap();
+            match find_m3u8(&mut playlists, path, address) {
+                Some(m3u8) => Ok(m3u8_response(m3u8)),
+                None => Ok(file_not_found()),
+            }
+        }
+        (&Method::GET, path) if path.contains("..") => Ok(file_not_found()),
         (&Method::GET, _) => file_response(&format!("./video/{}", req.uri().path())).await,
         _ => Ok(file_not_found()),
     }
 }
 
+// /{file}.m3u8 為目前的串流, /{stream key}/{file}.m3u8 為指定串流, /{abr group}/master.m3u8 為 abr group
+fn find_m3u8(playlists: &mut PlayLists, path: &str, address: IpAddr) -> Option<String> {
+    let parts: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+    let (name, file) = match parts.as_slice() {
+        [file] => (playlists.current.clone()?, *file),
+        [name, "master.m3u8"] if playlists.groups.contains_key(*name) => return playlists.group_master(name),
+        [name, file] => (name.to_string(), *file),
+        _ => return None,
+    };
+
+    let playlist = playlists.streams.get_mut(&name)?;
+    if !playlist.live {
+        return None;
+    }
+    let m3u8 = match file {
+        "master.m3u8" => playlist.master.clone(),
+        "video.m3u8" => playlist.m3u8.clone(),
+        "audio.m3u8" if playlist.audio_codecs.is_some() => playlist.audio_m3u8.clone(),
+        "subtitles.m3u8" if playlist.subtitles => playlist.subtitles_m3u8.clone(),
+        _ => return None,
+    };
+    playlist.watch(address);
+    Some(m3u8)
+}
+
+#[derive(Deserialize)]
+struct TokenRequest {
+    name: String,
+    #[serde(default = "default_ttl")]
+    ttl: u64,
+    #[serde(default)]
+    moderator: bool,
+}
+
+fn default_ttl() -> u64 {
+    24 * 60 * 60
+}
+
+const MAX_TTL: u64 = 365 * 24 * 60 * 60;
+
+// 管理用 API 需要 Authorization: Bearer {CHAT_ADMIN_KEY}, 未設定時停用; 未通過時回傳錯誤 response
+fn unauthorized(req: &Request<Body>) -> Option<Response<Body>> {
+    if identity::admin_key().is_none() {
+        return Some(error_response(StatusCode::NOT_FOUND, "admin api is disabled"));
+    }
+    let key = req.headers().get("authorization").and_then(|value| value.to_str().ok()).and_then(|value| value.strip_prefix("Bearer "));
+    let authorized = key.is_some_and(identity::is_admin_key);
+    if !authorized {
+        return Some(error_response(StatusCode::UNAUTHORIZED, "invalid admin key"));
+    }
+    None
+}
+
+// POST /chat/token, body: {"name":"...","ttl":秒數,"moderator":false}
+async fn issue_token(req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    if let Some(response) = unauthorized(&req) {
+        return Ok(response);
+    }
+
+    let body = hyper::body::to_bytes(req.into_body()).await?;
+    let request: TokenRequest = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(e) => return Ok(error_response(StatusCode::BAD_REQUEST, &e.to_string())),
+    };
+    if let Err(e) = identity::validate_name(&request.name, true) {
+        return Ok(error_response(StatusCode::BAD_REQUEST, &e.message));
+    }
+    if request.ttl > MAX_TTL {
+        return Ok(error_response(StatusCode::BAD_REQUEST, &format!("ttl must be at most {} seconds", MAX_TTL)));
+    }
+
+    let exp = super::chat::now() / 1000 + request.ttl;
+    let token = identity::issue(&Claims {
+        name: request.name,
+        exp,
+        moderator: request.moderator,
+    });
+    Ok(json_response(serde_json::json!({ "token": token, "exp": exp }).to_string()))
+}
+
+#[derive(Deserialize)]
+struct TitleRequest {
+    title: Option<String>,
+}
+
+const MAX_TITLE_LENGTH: usize = 140;
+
+// PUT /streams/{stream key}/title, body: {"title":"..."}, null 或空字串為清除
+async fn set_title(req: Request<Body>, playlists: Arc<Mutex<PlayLists>>, key: &str) -> Result<Response<Body>, hyper::Error> {
+    if let Some(response) = unauthorized(&req) {
+        return Ok(response);
+    }
+    if !PlayLists::is_valid_key(key) {
+        return Ok(file_not_found());
+    }
+
+    let body = hyper::body::to_bytes(req.into_body()).await?;
+    let request: TitleRequest = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(e) => return Ok(error_response(StatusCode::BAD_REQUEST, &e.to_string())),
+    };
+    let title = request.title.map(|title| title.trim().to_string()).filter(|title| !title.is_empty());
+    if title.as_ref().is_some_and(|title| title.chars().count() > MAX_TITLE_LENGTH) {
+        return Ok(error_response(StatusCode::BAD_REQUEST, &format!("title must be at most {} characters", MAX_TITLE_LENGTH)));
+    }
+
+    playlists.lock().unwrap().stream_mut(key).set_title(title.clone());
+    Ok(json_response(serde_json::json!({ "title": title }).to_string()))
+}
+
+const MAX_METADATA_LENGTH: usize = 4096;
+
+// POST /streams/{stream key}/metadata, body: {"TIT2":"...","poll":"..."}, 以 ID3 timed metadata 寫入直播中的 ts
+async fn push_metadata(req: Request<Body>, playlists: Arc<Mutex<PlayLists>>, key: &str) -> Result<Response<Body>, hyper::Error> {
+    if let Some(response) = unauthorized(&req) {
+        return Ok(response);
+    }
+
+    let body = match read_body(req.into_body(), MAX_METADATA_LENGTH).await? {
+        Some(body) => body,
+        None => return Ok(error_response(StatusCode::PAYLOAD_TOO_LARGE, &format!("metadata must be at most {} bytes", MAX_METADATA_LENGTH))),
+    };
+    let request: serde_json::Map<String, serde_json::Value> = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(e) => return Ok(error_response(StatusCode::BAD_REQUEST, &e.to_string())),
+    };
+    let mut fields = Vec::new();
+    for (name, value) in request {
+        let value = match value {
+            serde_json::Value::String(value) => value,
+            serde_json::Value::Number(value) => value.to_string(),
+            serde_json::Value::Bool(value) => value.to_string(),
+            _ => return Ok(error_response(StatusCode::BAD_REQUEST, &format!("{} must be a string, number or boolean", name))),
+        };
+        fields.push((name, value));
+    }
+    if fields.is_empty() {
+        return Ok(error_response(StatusCode::BAD_REQUEST, "metadata is empty"));
+    }
+
+    let mut playlists = playlists.lock().unwrap();
+    match playlists.streams.get_mut(key) {
+        Some(playlist) if playlist.live => {
+            playlist.timed_metadata.push(fields);
+            Ok(json_response(serde_json::json!({ "queued": playlist.timed_metadata.len() }).to_string()))
+        }
+        _ => Ok(file_not_found()),
+    }
+}
+
+#[derive(Deserialize)]
+struct CueRequest {
+    #[serde(default = "default_out")]
+    out: bool,
+    duration: Option<f64>,
+    id: Option<u32>,
+}
+
+fn default_out() -> bool {
+    true
+}
+
+const MAX_CUE_LENGTH: usize = 1024;
+
+// POST /streams/{stream key}/cue, body: {"out":true,"duration":30,"id":1}, 在下一個 keyframe 插入 SCTE-35 splice_insert
+async fn push_cue(req: Request<Body>, playlists: Arc<Mutex<PlayLists>>, key: &str) -> Result<Response<Body>, hyper::Error> {
+    if let Some(response) = unauthorized(&req) {
+        return Ok(response);
+    }
+
+    let body = match read_body(req.into_body(), MAX_CUE_LENGTH).await? {
+        Some(body) => body,
+        None => return Ok(error_response(StatusCode::PAYLOAD_TOO_LARGE, &format!("cue must be at most {} bytes", MAX_CUE_LENGTH))),
+    };
+    let request: CueRequest = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(e) => return Ok(error_response(StatusCode::BAD_REQUEST, &e.to_string())),
+    };
+    if request.duration.is_some_and(|duration| !duration.is_finite() || duration <= 0.0) {
+        return Ok(error_response(StatusCode::BAD_REQUEST, "duration must be positive"));
+    }
+
+    let mut playlists = playlists.lock().unwrap();
+    match playlists.streams.get_mut(key) {
+        Some(playlist) if playlist.live => {
+            playlist.splice_requests.push(SpliceRequest {
+                out: request.out,
+                duration: request.duration,
+                id: request.id,
+            });
+            Ok(json_response(serde_json::json!({ "out": request.out, "duration": request.duration, "id": request.id }).to_string()))
+        }
+        _ => Ok(file_not_found()),
+    }
+}
+
+// 超過 limit 時停止讀取, 不把整個 body 放進記憶體
+async fn read_body(mut body: Body, limit: usize) -> Result<Option<Vec<u8>>, hyper::Error> {
+    use hyper::body::HttpBody;
+
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if bytes.len() + chunk.len() > limit {
+            return Ok(None);
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(Some(bytes))
+}
+
+// GET /streams/{stream key}/chat?from={ms}&to={ms}&recording={id}, 回放錄影時取得該段時間的聊天訊息, 需要 CHAT_LOG
+// 未指定 recording 時為最近一次開播
+fn chat_replay(req: &Request<Body>, key: &str) -> Response<Body> {
+    if !PlayLists::is_valid_key(key) {
+        return file_not_found();
+    }
+    let from = match query_param(req, "from").map(str::parse).transpose() {
+        Ok(from) => from.unwrap_or(0),
+        Err(_) => return error_response(StatusCode::BAD_REQUEST, "from must be milliseconds"),
+    };
+    let to = match query_param(req, "to").map(str::parse).transpose() {
+        Ok(to) => to.unwrap_or(u64::MAX),
+        Err(_) => return error_response(StatusCode::BAD_REQUEST, "to must be milliseconds"),
+    };
+    let recording = match query_param(req, "recording").map(str::parse).transpose() {
+        Ok(Some(recording)) => recording,
+        Ok(None) => match PlayLists::recordings(key).last() {
+            Some(recording) => *recording,
+            None => return file_not_found(),
+        },
+        Err(_) => return error_response(StatusCode::BAD_REQUEST, "recording must be an id from /streams/{key}/recordings"),
+    };
+    match super::chat::read_log(&PlayList::recording_directory(key, recording), from, to) {
+        Some(messages) => json_response(serde_json::to_string(&messages).unwrap()),
+        None => file_not_found(),
+    }
+}
+
+// GET /streams/{stream key}/recordings, 每次開播的 VOD playlist
+// start 為第一個 segment 的串流時間(ms), 播放位置加上 start 即為聊天訊息的 media_time
+fn recordings(key: &str) -> Response<Body> {
+    if !PlayLists::is_valid_key(key) {
+        return file_not_found();
+    }
+    let recordings: Vec<serde_json::Value> = PlayLists::recordings(key)
+        .into_iter()
+        .map(|id| {
+            let directory = PlayList::recording_directory(key, id);
+            let start = fs::read_to_string(format!("{}/video.m3u8", directory))
+                .ok()
+                .and_then(|m3u8| m3u8.lines().find_map(|line| line.strip_suffix(".ts")?.parse::<u64>().ok()));
+            serde_json::json!({
+                "id": id,
+                "start": start,
+                "playlist": format!("{}/video.m3u8", &directory[1..]),
+                "chat": format!("/streams/{}/chat?recording={}", key, id),
+            })
+        })
+        .collect();
+    json_response(serde_json::to_string(&recordings).unwrap())
+}
+
+fn query_param<'a>(req: &'a Request<Body>, name: &str) -> Option<&'a str> {
+    req.uri().query()?.split('&').find_map(|pair| {
+        let mut pair = pair.splitn(2, '=');
+        (pair.next() == Some(name)).then(|| pair.next().unwrap_or(""))
+    })
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(serde_json::json!({ "error": message }).to_string().into())
+        .unwrap()
+}
+
+fn json_response(json: String) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Access-Control-Allow-Origin", "*")
+        .header("content-type", "application/json")
+        .body(json.into())
+        .unwrap()
+}
+
+fn m3u8_response(m3u8: String) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Cache-Control", "no-cache, no-store, must-revalidate")
+        .header("Pragma", "no-cache")
+        .header("Expires", "0")
+        .header("content-type", "application/vnd.apple.mpegurl")
+        .body(m3u8.into())
+        .unwrap()
+}
+
 fn file_not_found() -> Response<Body> {
     Response::builder().status(StatusCode::NOT_FOUND).body("404 NOT FOUND".into()).unwrap()
 }
@@ -66,3 +433,22 @@ async fn file_response(path: &str) -> Result<Response<Body>, hyper::Error> {
     }
     Ok(file_not_found())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn route_names_do_not_contain_keys() {
+        assert_eq!(route_name(&Method::POST, "/chat/token"), "/chat/token");
+        assert_eq!(route_name(&Method::GET, "/chat/token"), "/chat");
+        assert_eq!(route_name(&Method::GET, "/chat/show"), "/chat");
+        assert_eq!(route_name(&Method::GET, "/streams/show/recordings"), "/streams/{key}/recordings");
+        assert_eq!(route_name(&Method::GET, "/streams/show/chat"), "/streams/{key}/chat");
+        assert_eq!(route_name(&Method::GET, "/streams/show"), "/streams/{key}");
+        assert_eq!(route_name(&Method::GET, "/recordings/show/1792354115211/video.m3u8"), "/recordings");
+        assert_eq!(route_name(&Method::GET, "/recordings/show/1792354115211/2000.ts"), "/recordings");
+        assert_eq!(route_name(&Method::GET, "/show/video.m3u8"), "playlist");
+        assert_eq!(route_name(&Method::GET, "/show/2000.ts"), "segment");
+    }
+}
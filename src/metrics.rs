@@ -0,0 +1,150 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Mutex;
+
+// Prometheus text format: https://prometheus.io/docs/instrumenting/exposition_formats/
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub const fn new() -> Counter {
+        Counter(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, value: u64) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub const fn new() -> Gauge {
+        Gauge(AtomicI64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+struct HistogramData {
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+pub struct Histogram {
+    buckets: &'static [f64],
+    data: Mutex<HistogramData>,
+}
+
+impl Histogram {
+    pub const fn new(buckets: &'static [f64]) -> Histogram {
+        Histogram {
+            buckets,
+            data: Mutex::new(HistogramData { counts: Vec::new(), sum: 0.0, count: 0 }),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        let mut data = self.data.lock().unwrap();
+        if data.counts.is_empty() {
+            data.counts = vec![0; self.buckets.len()];
+        }
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            if value <= *bucket {
+                data.counts[i] += 1;
+            }
+        }
+        data.sum += value;
+        data.count += 1;
+    }
+
+    fn render(&self, name: &str, out: &mut String) {
+        let data = self.data.lock().unwrap();
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            let count = data.counts.get(i).copied().unwrap_or(0);
+            out.push_str(&format!("{}_bucket{{le=\"{}\"}} {}\n", name, bucket, count));
+        }
+        out.push_str(&format!("{}_bucket{{le=\"+Inf\"}} {}\n", name, data.count));
+        out.push_str(&format!("{}_sum {}\n", name, data.sum));
+        out.push_str(&format!("{}_count {}\n", name, data.count));
+    }
+}
+
+const SEGMENT_BUCKETS: &[f64] = &[0.5, 1.0, 2.0, 3.0, 4.0, 6.0, 8.0, 10.0, 15.0, 30.0];
+const LATENCY_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0];
+
+pub static RTMP_CONNECTIONS: Counter = Counter::new();
+pub static RTMP_ACTIVE_CONNECTIONS: Gauge = Gauge::new();
+pub static RTMP_HANDSHAKES: Counter = Counter::new();
+pub static RTMP_HANDSHAKE_FAILURES: Counter = Counter::new();
+pub static RTMP_FAILURES: Counter = Counter::new();
+pub static INGEST_BYTES: Counter = Counter::new();
+pub static MALFORMED_PACKETS: Counter = Counter::new();
+pub static SEGMENTS_WRITTEN: Counter = Counter::new();
+pub static SEGMENT_DURATION: Histogram = Histogram::new(SEGMENT_BUCKETS);
+pub static PLAYLIST_UPDATE: Histogram = Histogram::new(LATENCY_BUCKETS);
+pub static CHAT_CONNECTIONS: Counter = Counter::new();
+pub static CHAT_ACTIVE_CONNECTIONS: Gauge = Gauge::new();
+pub static CHAT_MESSAGES: Counter = Counter::new();
+static HTTP_REQUESTS: Mutex<BTreeMap<(&'static str, u16), u64>> = Mutex::new(BTreeMap::new());
+
+pub fn http_request(route: &'static str, status: u16) {
+    *HTTP_REQUESTS.lock().unwrap().entry((route, status)).or_insert(0) += 1;
+}
+
+pub fn render() -> String {
+    let mut out = String::new();
+
+    counter(&mut out, "rtmp_connections_total", "Accepted RTMP connections.", &RTMP_CONNECTIONS);
+    gauge(&mut out, "rtmp_connections_active", "Open RTMP connections.", &RTMP_ACTIVE_CONNECTIONS);
+    counter(&mut out, "rtmp_handshakes_total", "Completed RTMP handshakes.", &RTMP_HANDSHAKES);
+    counter(&mut out, "rtmp_handshake_failures_total", "Failed RTMP handshakes.", &RTMP_HANDSHAKE_FAILURES);
+    counter(&mut out, "rtmp_failures_total", "RTMP connections closed by a socket or session error.", &RTMP_FAILURES);
+    counter(&mut out, "rtmp_ingest_bytes_total", "Bytes read from RTMP publishers.", &INGEST_BYTES);
+    counter(&mut out, "rtmp_malformed_packets_total", "Audio/video packets dropped because they could not be parsed.", &MALFORMED_PACKETS);
+    counter(&mut out, "hls_segments_written_total", "Transport stream segments written.", &SEGMENTS_WRITTEN);
+    histogram(&mut out, "hls_segment_duration_seconds", "Duration of written transport stream segments.", &SEGMENT_DURATION);
+    histogram(&mut out, "hls_playlist_update_seconds", "Time from segment cut to playlist update.", &PLAYLIST_UPDATE);
+
+    out.push_str("# HELP http_requests_total HTTP requests by route and status.\n");
+    out.push_str("# TYPE http_requests_total counter\n");
+    for ((route, status), count) in HTTP_REQUESTS.lock().unwrap().iter() {
+        out.push_str(&format!("http_requests_total{{route=\"{}\",status=\"{}\"}} {}\n", route, status, count));
+    }
+
+    counter(&mut out, "chat_connections_total", "Accepted chat WebSocket connections.", &CHAT_CONNECTIONS);
+    gauge(&mut out, "chat_connections_active", "Open chat WebSocket connections.", &CHAT_ACTIVE_CONNECTIONS);
+    counter(&mut out, "chat_messages_total", "Chat messages received.", &CHAT_MESSAGES);
+    out
+}
+
+fn counter(out: &mut String, name: &str, help: &str, counter: &Counter) {
+    out.push_str(&format!("# HELP {} {}\n# TYPE {} counter\n{} {}\n", name, help, name, name, counter.get()));
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, gauge: &Gauge) {
+    out.push_str(&format!("# HELP {} {}\n# TYPE {} gauge\n{} {}\n", name, help, name, name, gauge.get()));
+}
+
+fn histogram(out: &mut String, name: &str, help: &str, histogram: &Histogram) {
+    out.push_str(&format!("# HELP {} {}\n# TYPE {} histogram\n", name, help, name));
+    histogram.render(name, out);
+}
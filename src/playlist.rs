@@ -1,92 +1,649 @@
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs;
+use std::net::IpAddr;
 use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
 use super::chat::ServerMessage;
+use super::stats::{StreamMetadata, StreamStats};
+
+// 所有串流的 playlist, 以 stream key 區分
+pub struct PlayLists {
+    pub streams: HashMap<String, PlayList>,
+    pub groups: HashMap<String, Vec<String>>,
+    pub group_clocks: HashMap<String, GroupClock>,
+    pub current: Option<String>,
+    pub chat_connections: HashMap<String, usize>,
+    pub tx: mpsc::Sender<ServerMessage>,
+    pub rx: Arc<Mutex<mpsc::Receiver<ServerMessage>>>,
+}
+
+impl PlayLists {
+    pub fn new() -> PlayLists {
+        let (tx, rx) = mpsc::channel();
+
+        PlayLists {
+            streams: HashMap::new(),
+            groups: PlayLists::read_groups(),
+            group_clocks: HashMap::new(),
+            current: None,
+            chat_connections: HashMap::new(),
+            tx,
+            rx: Arc::new(Mutex::new(rx)),
+        }
+    }
+
+    // ABR_GROUPS="show=show_1080,show_720,show_480;news=news_720,news_360"
+    fn read_groups() -> HashMap<String, Vec<String>> {
+        let mut groups = HashMap::new();
+        let value = env::var("ABR_GROUPS").unwrap_or_default();
+        for group in value.split(';') {
+            let mut pair = group.splitn(2, '=');
+            let name = pair.next().unwrap_or("").trim();
+            let keys: Vec<String> = pair.next().unwrap_or("").split(',').map(|k| k.trim().to_string()).filter(|k| !k.is_empty()).collect();
+            if !name.is_empty() && !keys.is_empty() {
+                println!("abr group '{}': {}", name, keys.join(", "));
+                groups.insert(name.to_string(), keys);
+            }
+        }
+        groups
+    }
+
+    // 已保存的錄影, 由舊到新, 見 PlayList::recording
+    pub fn recordings(key: &str) -> Vec<u64> {
+        let mut recordings: Vec<u64> = fs::read_dir(format!("{}/{}", PlayList::RECORDINGS, key))
+            .map(|entries| entries.filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok()).collect())
+            .unwrap_or_default();
+        recordings.sort_unstable();
+        recordings
+    }
+
+    // stream key 會成為資料夾與網址的一部分
+    pub fn is_valid_key(key: &str) -> bool {
+        !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    }
+
+    pub fn stream_mut(&mut self, key: &str) -> &mut PlayList {
+        let tx = self.tx.clone();
+        self.streams.entry(key.to_string()).or_insert_with(|| PlayList::new(key.to_string(), tx))
+    }
+
+    pub fn current(&self) -> Option<&PlayList> {
+        self.streams.get(self.current.as_ref()?)
+    }
+
+    pub fn chat_viewers(&self, key: &str) -> usize {
+        self.chat_connections.get(key).copied().unwrap_or(0)
+    }
+
+    pub fn group_of(&self, key: &str) -> Option<&String> {
+        self.groups.iter().find(|(_, keys)| keys.iter().any(|k| k == key)).map(|(name, _)| name)
+    }
+
+    // 第一個 keyframe 時決定 RTMP timestamp 換算成 abr group 時間要加上的差, 為 duration 的倍數
+    // 各 publish 的 timestamp 來自同一個時鐘時為 0, 各自從 0 開始時依開始 publish 的時間推算
+    pub fn start_segments(&mut self, key: &str, timestamp: u32, duration: u32) -> u32 {
+        let offset = match self.group_of(key).cloned() {
+            Some(group) => {
+                let publishing = self.groups[&group].iter().any(|k| k != key && self.streams.get(k).is_some_and(|p| p.publishing));
+                if !publishing || !self.group_clocks.contains_key(&group) {
+                    self.group_clocks.insert(group.clone(), GroupClock::new(timestamp));
+                }
+                self.group_clocks[&group].offset(timestamp, duration)
+            }
+            None => 0,
+        };
+        let playlist = self.stream_mut(key);
+        playlist.segment_start = timestamp;
+        playlist.time_offset = offset;
+        offset
+    }
+
+    // 下一個 segment 的 media sequence
+    // 同一 abr group 中 group 時間相同的 segment 使用相同的 media sequence, 之後加入的 publish 接續 group 目前的 sequence
+    pub fn next_sequence(&mut self, key: &str) -> usize {
+        let (start, next) = {
+            let playlist = self.stream_mut(key);
+            (playlist.segment_start.saturating_add(playlist.time_offset), playlist.ts.last().map(|ts| ts.sequence + 1))
+        };
+        let clock = match self.group_of(key).cloned().and_then(|group| self.group_clocks.get_mut(&group)) {
+            Some(clock) => clock,
+            None => return next.unwrap_or(0),
+        };
+        let sequence = next
+            .or_else(|| clock.sequences.get(&start).copied())
+            .unwrap_or_else(|| clock.sequences.values().next_back().map_or(0, |sequence| sequence + 1));
+        clock.sequences.entry(start).or_insert(sequence);
+        while clock.sequences.len() > GroupClock::MAX_SEGMENTS {
+            clock.sequences.pop_first();
+        }
+        sequence
+    }
+
+    // 每個 publish 一個 variant, 依 bandwidth 由高到低
+    pub fn group_master(&self, name: &str) -> Option<String> {
+        let keys = self.groups.get(name)?;
+        let mut variants: Vec<&PlayList> = keys.iter().filter_map(|k| self.streams.get(k)).filter(|p| p.live).collect();
+        if variants.is_empty() {
+            return None;
+        }
+        variants.sort_by_key(|p| std::cmp::Reverse(p.bandwidth));
+
+        let mut m3u8 = String::from("");
+        m3u8 = format!("{}#EXTM3U\r\n", m3u8);
+        m3u8 = format!("{}#EXT-X-VERSION:3\r\n", m3u8);
+        // 各 publish 的字幕各自為一個 group
+        for playlist in variants.iter().filter(|p| p.subtitles) {
+            m3u8 = format!("{}{}\r\n", m3u8, playlist.subtitles_media(&playlist.name));
+        }
+        for playlist in variants {
+            let subtitles = if playlist.subtitles { Some(playlist.name.as_str()) } else { None };
+            m3u8 = format!("{}{}\r\n", m3u8, playlist.stream_inf(subtitles));
+            m3u8 = format!("{}{}/{}/video.m3u8\r\n", m3u8, PlayList::URL, playlist.name);
+        }
+        Some(m3u8)
+    }
+}
+
+// abr group 共用的時鐘, 以 group 中第一個 publish 的 timestamp 與開始時間為準
+pub struct GroupClock {
+    timestamp: u32,
+    started: Instant,
+    // segment 開始時間(group 時間) 對應的 media sequence
+    sequences: BTreeMap<u32, usize>,
+}
+
+impl GroupClock {
+    const MAX_SEGMENTS: usize = 64;
+
+    fn new(timestamp: u32) -> GroupClock {
+        GroupClock { timestamp, started: Instant::now(), sequences: BTreeMap::new() }
+    }
+
+    // 目前的 group 時間與 timestamp 的差, 取最接近的 duration 倍數
+    fn offset(&self, timestamp: u32, duration: u32) -> u32 {
+        let now = self.timestamp as i64 + self.started.elapsed().as_millis() as i64;
+        let segments = ((now - timestamp as i64) as f64 / duration as f64).round().max(0.0);
+        (segments as u32).saturating_mul(duration)
+    }
+}
+
+// 廣告插入點, 由 stream server 在 splice 的 keyframe 切出 segment 後設定, 套用在下一個 segment
+pub enum CueTag {
+    // 進入廣告, duration 為預計長度(秒), scte35 為 splice_info_section
+    Out { id: u32, duration: Option<f64>, scte35: String },
+    // 回到節目, 依 break_duration 自動回到節目時沒有 scte35
+    In { scte35: Option<String> },
+}
+
+// 管理 API 或 onCuePoint 的廣告插入請求, 在下一個 keyframe 執行
+pub struct SpliceRequest {
+    pub out: bool,
+    pub duration: Option<f64>,
+    pub id: Option<u32>,
+}
+
+// segment 在廣告中的位置
+enum Cue {
+    Out { id: u32, duration: Option<f64>, scte35: String },
+    // elapsed 為進入廣告後經過的時間(ms)
+    Continue { elapsed: u32, duration: Option<f64> },
+    // out 為進入廣告的 segment 開始時間(ms)
+    In { id: u32, out: u32, scte35: Option<String> },
+}
+
+struct Segment {
+    sequence: usize,
+    duration: u32,
+    filename: String,
+    // 是否在此之前加上 EXT-X-DISCONTINUITY
+    discontinuity: bool,
+    // 開始時間, 串流時間(ms)
+    start: u32,
+    cue: Option<Cue>,
+}
+
+// 目前的廣告: event id、開始時間(ms)與預計長度(秒)
+struct CueOut {
+    id: u32,
+    start: u32,
+    duration: Option<f64>,
+}
 
 pub struct PlayList {
+    pub name: String,
+    // playlist 中第一個 segment 的 media sequence
     pub sequence: usize,
     pub m3u8: String,
-    pub ts: Vec<(u32, String)>,
+    pub audio_m3u8: String,
+    pub subtitles_m3u8: String,
+    pub master: String,
+    ts: Vec<Segment>,
     pub timestamp: Vec<u32>,
+    // 已移出 playlist 的 discontinuity 數量
+    pub discontinuity_sequence: usize,
+    // 下一個 segment 的編碼設定與前一個不同
+    pub pending_discontinuity: bool,
+    // 下一個 segment 從 splice 點開始
+    pub pending_cue: Option<CueTag>,
+    cue_out: Option<CueOut>,
+    // 下一個 segment 的開始時間(ms)
+    segment_start: u32,
+    // RTMP timestamp 加上 time_offset 為 abr group 時間
+    pub time_offset: u32,
+    // publish 中, 結束後到 live 變為 false 之前為 false
+    pub publishing: bool,
+    // 開播時間(unix ms), 用於 EXT-X-PROGRAM-DATE-TIME
+    date: u64,
     pub live: bool,
+    // 收到 CEA-608/708 字幕後在 master playlist 加入字幕
+    pub subtitles: bool,
+    pub video_codecs: Option<String>,
+    pub audio_codecs: Option<String>,
+    pub resolution: Option<(u32, u32)>,
+    pub bandwidth: u64,
+    pub audio_bandwidth: u64,
+    pub stats: StreamStats,
+    pub hls_sessions: HashMap<IpAddr, Instant>,
+    pub title: Option<String>,
+    pub metadata: Option<StreamMetadata>,
+    // 最近收到的 RTMP timestamp 與收到的時間
+    pub media_time: Option<(u32, Instant)>,
+    // 管理 API 送來的 ID3 欄位, 由 stream server 每秒取出寫入 ts
+    pub timed_metadata: Vec<Vec<(String, String)>>,
+    // 管理 API 送來的廣告插入請求, 由 stream server 每秒取出
+    pub splice_requests: Vec<SpliceRequest>,
     pub tx: mpsc::Sender<ServerMessage>,
-    pub rx: Arc<Mutex<mpsc::Receiver<ServerMessage>>>,
 }
 
 impl PlayList {
     const COUNT: usize = 2;
+    const URL: &'static str = "http://127.0.0.1:1337";
+    const HLS_SESSION_TIMEOUT: Duration = Duration::from_secs(30);
+    const SUBTITLES_GROUP: &'static str = "subtitles";
+    pub const RECORDINGS: &'static str = "./recordings";
 
-    pub fn new() -> PlayList {
-        let (tx, rx) = mpsc::channel();
-
+    pub fn new(name: String, tx: mpsc::Sender<ServerMessage>) -> PlayList {
         PlayList {
+            name,
             sequence: 0,
             m3u8: String::from(""),
+            audio_m3u8: String::from(""),
+            subtitles_m3u8: String::from(""),
+            master: String::from(""),
             ts: vec![],
             timestamp: vec![0],
+            discontinuity_sequence: 0,
+            pending_discontinuity: false,
+            pending_cue: None,
+            cue_out: None,
+            segment_start: 0,
+            time_offset: 0,
+            publishing: false,
+            date: 0,
             live: false,
+            subtitles: false,
+            video_codecs: None,
+            audio_codecs: None,
+            resolution: None,
+            bandwidth: 0,
+            audio_bandwidth: 0,
+            stats: StreamStats::new(String::from(""), String::from("")),
+            hls_sessions: HashMap::new(),
+            title: None,
+            metadata: None,
+            media_time: None,
+            timed_metadata: Vec::new(),
+            splice_requests: Vec::new(),
             tx,
-            rx: Arc::new(Mutex::new(rx)),
         }
     }
 
-    pub fn push(&mut self, timestamp: u32, filename: String, end: bool) -> u64 {
+    // bytes: (影音 ts 大小, 純音訊 ts 大小)
+    pub fn push(&mut self, sequence: usize, timestamp: u32, filename: String, bytes: (usize, usize), end: bool) -> u64 {
+        let raw_timestamp = timestamp;
         let mut timestamp = timestamp / 1000 + 1;
         let mut duration = timestamp;
         if let Some(t) = self.timestamp.last() {
             if end {
                 if let Some(d) = self.ts.last() {
-                    timestamp = d.0 + t;
-                    duration = d.0;
+                    timestamp = d.duration + t;
+                    duration = d.duration;
                 }
             } else {
                 duration = timestamp - t;
             }
         }
 
-        self.ts.push((duration, filename));
+        let seconds = duration.max(1) as u64;
+        self.bandwidth = self.bandwidth.max(bytes.0 as u64 * 8 / seconds);
+        self.audio_bandwidth = self.audio_bandwidth.max(bytes.1 as u64 * 8 / seconds);
+
+        let start = self.segment_start;
+        self.segment_start = if end { start } else { raw_timestamp };
+        let cue = self.cue(start);
+        self.ts.push(Segment {
+            sequence,
+            duration,
+            filename,
+            discontinuity: std::mem::take(&mut self.pending_discontinuity),
+            start,
+            cue,
+        });
         self.timestamp.push(timestamp);
         self.update(end);
         duration as u64
     }
 
+    // 依 pending_cue 與目前是否在廣告中決定 segment 的 cue tag
+    fn cue(&mut self, start: u32) -> Option<Cue> {
+        match self.pending_cue.take() {
+            Some(CueTag::Out { id, duration, scte35 }) => {
+                self.cue_out = Some(CueOut { id, start, duration });
+                Some(Cue::Out { id, duration, scte35 })
+            }
+            Some(CueTag::In { scte35 }) => self.cue_out.take().map(|out| Cue::In { id: out.id, out: out.start, scte35 }),
+            None => self.cue_out.as_ref().map(|out| Cue::Continue {
+                elapsed: start.saturating_sub(out.start),
+                duration: out.duration,
+            }),
+        }
+    }
+
     pub fn update(&mut self, end: bool) {
         if self.ts.len() >= PlayList::COUNT {
             if self.ts.len() == PlayList::COUNT + 1 && !end {
-                self.ts.remove(0);
+                if self.ts.remove(0).discontinuity {
+                    self.discontinuity_sequence += 1;
+                }
                 self.timestamp.remove(0);
             }
-            let mut target_duration = 0;
-            let mut list = String::from("");
-            for ts in &self.ts {
-                list = format!("{}#EXTINF:{}.0000\r\n", list, ts.0);
-                list = format!("{}http://127.0.0.1:1337/{}\r\n", list, ts.1);
-                target_duration = if target_duration <= ts.0 { ts.0 + 1 } else { target_duration }
+            self.sequence = self.ts[0].sequence;
+            self.m3u8 = self.media_playlist("", "ts", end);
+            self.audio_m3u8 = self.media_playlist("audio/", "ts", end);
+            self.subtitles_m3u8 = self.media_playlist("subtitles/", "vtt", end);
+            self.master = self.master_playlist();
+            if !self.live {
+                self.live = true;
+                self.tx.send(ServerMessage::Live(self.name.clone())).unwrap();
             }
-
-            let mut m3u8 = String::from("");
-            m3u8 = format!("{}#EXTM3U\r\n", m3u8);
-            m3u8 = format!("{}#EXT-X-VERSION:3\r\n", m3u8);
-            m3u8 = format!("{}#EXT-X-TARGETDURATION:{}\r\n", m3u8, target_duration);
-            m3u8 = format!("{}#EXT-X-MEDIA-SEQUENCE:{}\r\n", m3u8, self.sequence);
-            m3u8 = format!("{}{}", m3u8, list);
             if end {
-                m3u8 = format!("{}#EXT-X-ENDLIST\r\n", m3u8);
+                self.tx.send(ServerMessage::Off(self.name.clone())).unwrap();
             }
-            self.m3u8 = m3u8;
-            if self.sequence == 0 {
-                self.live = true;
-                self.tx.send(ServerMessage::Live).unwrap();
+        }
+    }
+
+    // 各 playlist 的 segment 檔名相同, 只有副檔名不同
+    fn media_playlist(&self, directory: &str, extension: &str, end: bool) -> String {
+        let mut target_duration = 0;
+        let mut list = String::from("");
+        for (i, ts) in self.ts.iter().enumerate() {
+            if ts.discontinuity {
+                list = format!("{}#EXT-X-DISCONTINUITY\r\n", list);
             }
-            if end {
-                self.tx.send(ServerMessage::Off).unwrap();
+            // EXT-X-DATERANGE 需要 EXT-X-PROGRAM-DATE-TIME
+            if i == 0 || ts.discontinuity || matches!(ts.cue, Some(Cue::Out { .. }) | Some(Cue::In { .. })) {
+                list = format!("{}#EXT-X-PROGRAM-DATE-TIME:{}\r\n", list, self.date_time(ts.start));
             }
-            self.sequence += 1;
+            match &ts.cue {
+                Some(Cue::Out { id, duration, scte35 }) => {
+                    let mut daterange = format!("#EXT-X-DATERANGE:ID=\"splice-{}\",START-DATE=\"{}\"", id, self.date_time(ts.start));
+                    if let Some(duration) = duration {
+                        daterange = format!("{},PLANNED-DURATION={:.3}", daterange, duration);
+                    }
+                    list = format!("{}{},SCTE35-OUT={}\r\n", list, daterange, scte35);
+                    match duration {
+                        Some(duration) => list = format!("{}#EXT-X-CUE-OUT:DURATION={:.3}\r\n", list, duration),
+                        None => list = format!("{}#EXT-X-CUE-OUT\r\n", list),
+                    }
+                }
+                Some(Cue::Continue { elapsed, duration }) => {
+                    let mut cont = format!("#EXT-X-CUE-OUT-CONT:ElapsedTime={:.3}", *elapsed as f64 / 1000.0);
+                    if let Some(duration) = duration {
+                        cont = format!("{},Duration={:.3}", cont, duration);
+                    }
+                    list = format!("{}{}\r\n", list, cont);
+                }
+                Some(Cue::In { id, out, scte35 }) => {
+                    let mut daterange = format!("#EXT-X-DATERANGE:ID=\"splice-{}\",START-DATE=\"{}\"", id, self.date_time(*out));
+                    daterange = format!("{},DURATION={:.3}", daterange, ts.start.saturating_sub(*out) as f64 / 1000.0);
+                    if let Some(scte35) = scte35 {
+                        daterange = format!("{},SCTE35-IN={}", daterange, scte35);
+                    }
+                    list = format!("{}{}\r\n#EXT-X-CUE-IN\r\n", list, daterange);
+                }
+                None => (),
+            }
+            list = format!("{}#EXTINF:{}.0000\r\n", list, ts.duration);
+            list = format!("{}{}/{}/{}{}.{}\r\n", list, PlayList::URL, self.name, directory, ts.filename.trim_end_matches(".ts"), extension);
+            target_duration = if target_duration <= ts.duration { ts.duration + 1 } else { target_duration }
+        }
+
+        let mut m3u8 = String::from("");
+        m3u8 = format!("{}#EXTM3U\r\n", m3u8);
+        m3u8 = format!("{}#EXT-X-VERSION:3\r\n", m3u8);
+        m3u8 = format!("{}#EXT-X-TARGETDURATION:{}\r\n", m3u8, target_duration);
+        m3u8 = format!("{}#EXT-X-MEDIA-SEQUENCE:{}\r\n", m3u8, self.sequence);
+        if self.discontinuity_sequence > 0 {
+            m3u8 = format!("{}#EXT-X-DISCONTINUITY-SEQUENCE:{}\r\n", m3u8, self.discontinuity_sequence);
+        }
+        m3u8 = format!("{}{}", m3u8, list);
+        if end {
+            m3u8 = format!("{}#EXT-X-ENDLIST\r\n", m3u8);
+        }
+        m3u8
+    }
+
+    // 串流時間(ms)轉成 ISO 8601 (UTC)
+    fn date_time(&self, timestamp: u32) -> String {
+        let ms = self.date + timestamp as u64;
+        let seconds = ms / 1000;
+        let (days, time) = ((seconds / 86400) as i64, seconds % 86400);
+        // civil from days, 1970-01-01 起的天數轉成年月日
+        let z = days + 719468;
+        let era = z / 146097;
+        let day_of_era = z - era * 146097;
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let mp = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+        format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z", year, month, day, time / 3600, time / 60 % 60, time % 60, ms % 1000)
+    }
+
+    fn stream_inf(&self, subtitles: Option<&str>) -> String {
+        let codecs: Vec<&str> = self.video_codecs.iter().chain(self.audio_codecs.iter()).map(|c| c.as_str()).collect();
+
+        let mut stream_inf = format!("#EXT-X-STREAM-INF:BANDWIDTH={}", self.bandwidth);
+        if !codecs.is_empty() {
+            stream_inf = format!("{},CODECS=\"{}\"", stream_inf, codecs.join(","));
+        }
+        if let Some((width, height)) = self.resolution {
+            stream_inf = format!("{},RESOLUTION={}x{}", stream_inf, width, height);
         }
+        if let Some(group) = subtitles {
+            stream_inf = format!("{},SUBTITLES=\"{}\"", stream_inf, group);
+        }
+        stream_inf
+    }
+
+    fn subtitles_media(&self, group: &str) -> String {
+        format!("#EXT-X-MEDIA:TYPE=SUBTITLES,GROUP-ID=\"{}\",NAME=\"CC1\",DEFAULT=YES,AUTOSELECT=YES,FORCED=NO,URI=\"{}/{}/subtitles.m3u8\"", group, PlayList::URL, self.name)
+    }
+
+    // 完整影音與純音訊兩個 variant
+    fn master_playlist(&self) -> String {
+        let mut m3u8 = String::from("");
+        m3u8 = format!("{}#EXTM3U\r\n", m3u8);
+        m3u8 = format!("{}#EXT-X-VERSION:3\r\n", m3u8);
+        let subtitles = if self.subtitles { Some(PlayList::SUBTITLES_GROUP) } else { None };
+        if let Some(group) = subtitles {
+            m3u8 = format!("{}{}\r\n", m3u8, self.subtitles_media(group));
+        }
+        m3u8 = format!("{}{}\r\n", m3u8, self.stream_inf(subtitles));
+        m3u8 = format!("{}{}/{}/video.m3u8\r\n", m3u8, PlayList::URL, self.name);
+        if let Some(audio_codecs) = &self.audio_codecs {
+            m3u8 = format!("{}#EXT-X-STREAM-INF:BANDWIDTH={},CODECS=\"{}\"\r\n", m3u8, self.audio_bandwidth, audio_codecs);
+            m3u8 = format!("{}{}/{}/audio.m3u8\r\n", m3u8, PlayList::URL, self.name);
+        }
+        m3u8
     }
 
     pub fn reset(&mut self) {
         self.sequence = 0;
         self.m3u8 = String::from("");
+        self.audio_m3u8 = String::from("");
+        self.subtitles_m3u8 = String::from("");
+        self.master = String::from("");
         self.ts.clear();
         self.timestamp = vec![0];
+        self.discontinuity_sequence = 0;
+        self.pending_discontinuity = false;
+        self.pending_cue = None;
+        self.cue_out = None;
+        self.segment_start = 0;
+        self.time_offset = 0;
+        self.date = super::chat::now();
+        self.subtitles = false;
+        self.video_codecs = None;
+        self.audio_codecs = None;
+        self.resolution = None;
+        self.bandwidth = 0;
+        self.audio_bandwidth = 0;
+        self.hls_sessions.clear();
+        self.metadata = None;
+        self.media_time = None;
+        self.timed_metadata.clear();
+        self.splice_requests.clear();
+    }
+
+    pub fn set_title(&mut self, title: Option<String>) {
+        self.title = title.clone();
+        self.tx.send(ServerMessage::Title(self.name.clone(), title)).unwrap();
+    }
+
+    pub fn set_metadata(&mut self, metadata: StreamMetadata) {
+        self.metadata = Some(metadata.clone());
+        self.tx.send(ServerMessage::Metadata(self.name.clone(), metadata)).unwrap();
+    }
+
+    // CHAT_LOG 時每次開播的錄影與聊天紀錄, 以開播時間(unix ms)區分
+    pub fn recording(&self) -> String {
+        PlayList::recording_directory(&self.name, self.date)
+    }
+
+    pub fn recording_directory(key: &str, id: u64) -> String {
+        format!("{}/{}/{}", PlayList::RECORDINGS, key, id)
+    }
+
+    pub fn set_media_time(&mut self, timestamp: u32) {
+        self.media_time = Some((timestamp, Instant::now()));
+    }
+
+    // 目前的串流時間(ms), 以最近的 RTMP timestamp 加上經過的時間推算
+    pub fn media_time(&self) -> Option<u64> {
+        self.media_time.map(|(timestamp, updated)| timestamp as u64 + updated.elapsed().as_millis() as u64)
+    }
+
+    // 每次取得 m3u8 時更新, 一段時間沒有再取得視為離開
+    pub fn watch(&mut self, address: IpAddr) {
+        let now = Instant::now();
+        self.hls_sessions.insert(address, now);
+        self.hls_sessions.retain(|_, last| now.duration_since(*last) < PlayList::HLS_SESSION_TIMEOUT);
+    }
+
+    pub fn hls_viewers(&self) -> usize {
+        self.hls_sessions.values().filter(|last| last.elapsed() < PlayList::HLS_SESSION_TIMEOUT).count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn group() -> PlayLists {
+        let mut playlists = PlayLists::new();
+        playlists.groups.insert("show".to_string(), vec!["show_720".to_string(), "show_360".to_string()]);
+        playlists
+    }
+
+    fn cut(playlists: &mut PlayLists, key: &str, timestamp: u32) -> usize {
+        let sequence = playlists.next_sequence(key);
+        playlists.stream_mut(key).push(sequence, timestamp, format!("{}.ts", timestamp), (1000, 100), false);
+        sequence
+    }
+
+    fn start(playlists: &mut PlayLists, key: &str, timestamp: u32) -> u32 {
+        playlists.stream_mut(key).publishing = true;
+        playlists.start_segments(key, timestamp, 2000)
+    }
+
+    #[test]
+    fn sequence_without_group() {
+        let mut playlists = group();
+        assert_eq!(start(&mut playlists, "solo", 500), 0);
+        assert_eq!(cut(&mut playlists, "solo", 2500), 0);
+        assert_eq!(cut(&mut playlists, "solo", 4500), 1);
+        assert_eq!(cut(&mut playlists, "solo", 6500), 2);
+        assert!(playlists.streams["solo"].m3u8.contains("#EXT-X-MEDIA-SEQUENCE:1\r\n"));
+    }
+
+    #[test]
+    fn group_shares_sequence_from_same_clock() {
+        let mut playlists = group();
+        assert_eq!(start(&mut playlists, "show_720", 0), 0);
+        assert_eq!(start(&mut playlists, "show_360", 0), 0);
+        for (i, timestamp) in [2000, 4000, 6000].iter().enumerate() {
+            assert_eq!(cut(&mut playlists, "show_720", *timestamp), i);
+            assert_eq!(cut(&mut playlists, "show_360", *timestamp), i);
+        }
+        assert_eq!(playlists.streams["show_720"].m3u8, playlists.streams["show_360"].m3u8.replace("show_360", "show_720"));
+    }
+
+    #[test]
+    fn late_publish_continues_group_sequence() {
+        let mut playlists = group();
+        start(&mut playlists, "show_720", 0);
+        playlists.group_clocks.get_mut("show").unwrap().started = Instant::now() - Duration::from_millis(10300);
+        for timestamp in [2000, 4000, 6000, 8000, 10000, 12000].iter() {
+            cut(&mut playlists, "show_720", *timestamp);
+        }
+
+        // timestamp 從 0 開始, 換算成 group 時間為 10000
+        assert_eq!(start(&mut playlists, "show_360", 0), 10000);
+        assert_eq!(cut(&mut playlists, "show_360", 2000), 5);
+        assert_eq!(cut(&mut playlists, "show_360", 4000), 6);
+        assert_eq!(cut(&mut playlists, "show_720", 14000), 6);
+        assert!(playlists.streams["show_720"].m3u8.contains("#EXT-X-MEDIA-SEQUENCE:5\r\n"));
+        assert!(playlists.streams["show_360"].m3u8.contains("#EXT-X-MEDIA-SEQUENCE:5\r\n"));
+
+        // timestamp 與第一個 publish 同一個時鐘時不需要換算
+        playlists.stream_mut("show_360").publishing = false;
+        playlists.stream_mut("show_360").reset();
+        assert_eq!(start(&mut playlists, "show_360", 10300), 0);
+    }
+
+    #[test]
+    fn discontinuity_sequence_counts_removed_segments() {
+        let mut playlists = group();
+        start(&mut playlists, "solo", 500);
+        cut(&mut playlists, "solo", 2500);
+        playlists.stream_mut("solo").pending_discontinuity = true;
+        cut(&mut playlists, "solo", 4500);
+        let m3u8 = &playlists.streams["solo"].m3u8;
+        let discontinuity = m3u8.find("#EXT-X-DISCONTINUITY\r\n").unwrap();
+        assert!(m3u8.find("/2500.ts").unwrap() < discontinuity && discontinuity < m3u8.find("/4500.ts").unwrap());
+        assert!(!m3u8.contains("#EXT-X-DISCONTINUITY-SEQUENCE"));
+
+        // 移出 playlist 的是 discontinuity 之前的 segment
+        cut(&mut playlists, "solo", 6500);
+        assert!(playlists.streams["solo"].m3u8.contains("#EXT-X-DISCONTINUITY\r\n"));
+        assert!(!playlists.streams["solo"].m3u8.contains("#EXT-X-DISCONTINUITY-SEQUENCE"));
+
+        cut(&mut playlists, "solo", 8500);
+        let m3u8 = &playlists.streams["solo"].m3u8;
+        assert!(m3u8.contains("#EXT-X-DISCONTINUITY-SEQUENCE:1\r\n"));
+        assert!(!m3u8.contains("#EXT-X-DISCONTINUITY\r\n"));
     }
 }
@@ -0,0 +1,290 @@
+use std::time::{Duration, Instant};
+use rml_rtmp::sessions::StreamMetadata as RtmpMetadata;
+use serde::Serialize;
+use super::playlist::PlayList;
+
+// 由 stream server 的 handle_video / handle_audio 更新, 每秒同步到 playlist 一次
+#[derive(Clone)]
+pub struct StreamStats {
+    pub publisher: String,
+    pub app_name: String,
+    pub started: Instant,
+    pub video_bytes: u64,
+    pub audio_bytes: u64,
+    pub video_frames: u64,
+    pub audio_frames: u64,
+    pub video_bitrate: u64,
+    pub audio_bitrate: u64,
+    pub frame_rate: f64,
+    pub declared_frame_rate: Option<f64>,
+    pub keyframe_interval: u32,
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u8>,
+    pub dropped_packets: u64,
+    // 無法解析而丟棄的封包
+    pub malformed_packets: u64,
+    pub late_packets: u64,
+    last_keyframe: Option<u32>,
+    last_video_timestamp: u32,
+    last_audio_timestamp: u32,
+    window_start: Instant,
+    window_video_bytes: u64,
+    window_audio_bytes: u64,
+    window_frames: u64,
+}
+
+impl StreamStats {
+    const WINDOW: Duration = Duration::from_secs(1);
+
+    pub fn new(publisher: String, app_name: String) -> StreamStats {
+        let now = Instant::now();
+        StreamStats {
+            publisher,
+            app_name,
+            started: now,
+            video_bytes: 0,
+            audio_bytes: 0,
+            video_frames: 0,
+            audio_frames: 0,
+            video_bitrate: 0,
+            audio_bitrate: 0,
+            frame_rate: 0.0,
+            declared_frame_rate: None,
+            keyframe_interval: 0,
+            sample_rate: None,
+            channels: None,
+            dropped_packets: 0,
+            malformed_packets: 0,
+            late_packets: 0,
+            last_keyframe: None,
+            last_video_timestamp: 0,
+            last_audio_timestamp: 0,
+            window_start: now,
+            window_video_bytes: 0,
+            window_audio_bytes: 0,
+            window_frames: 0,
+        }
+    }
+
+    pub fn video(&mut self, timestamp: u32, bytes: usize, is_keyframe: bool) {
+        if timestamp < self.last_video_timestamp {
+            self.late_packets += 1;
+        }
+        self.last_video_timestamp = timestamp;
+
+        if is_keyframe {
+            if let Some(last) = self.last_keyframe {
+                self.keyframe_interval = timestamp.saturating_sub(last);
+            }
+            self.last_keyframe = Some(timestamp);
+        }
+
+        self.video_bytes += bytes as u64;
+        self.video_frames += 1;
+        self.window_video_bytes += bytes as u64;
+        self.window_frames += 1;
+    }
+
+    pub fn audio(&mut self, timestamp: u32, bytes: usize) {
+        if timestamp < self.last_audio_timestamp {
+            self.late_packets += 1;
+        }
+        self.last_audio_timestamp = timestamp;
+
+        self.audio_bytes += bytes as u64;
+        self.audio_frames += 1;
+        self.window_audio_bytes += bytes as u64;
+    }
+
+    pub fn last_timestamp(&self) -> u32 {
+        self.last_video_timestamp.max(self.last_audio_timestamp)
+    }
+
+    pub fn drop_packet(&mut self) {
+        self.dropped_packets += 1;
+    }
+
+    pub fn malformed_packet(&mut self) {
+        self.malformed_packets += 1;
+    }
+
+    // 滿一秒時計算 bitrate 與 frame rate, 回傳 true 表示需要同步
+    pub fn tick(&mut self) -> bool {
+        let elapsed = self.window_start.elapsed();
+        if elapsed < StreamStats::WINDOW {
+            return false;
+        }
+
+        let seconds = elapsed.as_secs_f64();
+        self.video_bitrate = (self.window_video_bytes as f64 * 8.0 / seconds) as u64;
+        self.audio_bitrate = (self.window_audio_bytes as f64 * 8.0 / seconds) as u64;
+        self.frame_rate = self.window_frames as f64 / seconds;
+        self.window_start = Instant::now();
+        self.window_video_bytes = 0;
+        self.window_audio_bytes = 0;
+        self.window_frames = 0;
+        true
+    }
+}
+
+#[derive(Serialize)]
+pub struct StreamInfo {
+    pub live: bool,
+    pub app: String,
+    pub key: String,
+    pub publisher: String,
+    pub uptime: u64,
+    pub sequence: usize,
+    pub video: VideoInfo,
+    pub audio: AudioInfo,
+    pub dropped_packets: u64,
+    pub malformed_packets: u64,
+    pub late_packets: u64,
+    pub viewers: Viewers,
+    pub title: Option<String>,
+    pub metadata: Option<StreamMetadata>,
+}
+
+#[derive(Serialize)]
+pub struct VideoInfo {
+    pub codecs: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub bitrate: u64,
+    pub frame_rate: f64,
+    pub declared_frame_rate: Option<f64>,
+    pub keyframe_interval: u32,
+    pub frames: u64,
+    pub bytes: u64,
+}
+
+#[derive(Serialize)]
+pub struct AudioInfo {
+    pub codecs: Option<String>,
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u8>,
+    pub bitrate: u64,
+    pub frames: u64,
+    pub bytes: u64,
+}
+
+#[derive(Serialize, Clone, Copy)]
+pub struct Viewers {
+    pub hls: usize,
+    pub chat: usize,
+}
+
+// 推流端 @setDataFrame onMetaData 宣告的內容
+#[derive(Serialize, Clone)]
+pub struct StreamMetadata {
+    pub encoder: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub frame_rate: Option<f32>,
+    pub video_codec: Option<String>,
+    pub video_bitrate_kbps: Option<u32>,
+    pub audio_codec: Option<String>,
+    pub audio_bitrate_kbps: Option<u32>,
+    pub audio_sample_rate: Option<u32>,
+    pub audio_channels: Option<u32>,
+}
+
+impl From<&RtmpMetadata> for StreamMetadata {
+    fn from(metadata: &RtmpMetadata) -> StreamMetadata {
+        StreamMetadata {
+            encoder: metadata.encoder.clone(),
+            width: metadata.video_width,
+            height: metadata.video_height,
+            frame_rate: metadata.video_frame_rate,
+            video_codec: metadata.video_codec.clone(),
+            video_bitrate_kbps: metadata.video_bitrate_kbps,
+            audio_codec: metadata.audio_codec.clone(),
+            audio_bitrate_kbps: metadata.audio_bitrate_kbps,
+            audio_sample_rate: metadata.audio_sample_rate,
+            audio_channels: metadata.audio_channels,
+        }
+    }
+}
+
+impl StreamInfo {
+    pub fn new(playlist: &PlayList, chat_viewers: usize) -> StreamInfo {
+        let stats = &playlist.stats;
+        StreamInfo {
+            live: playlist.live,
+            app: stats.app_name.clone(),
+            key: playlist.name.clone(),
+            publisher: stats.publisher.clone(),
+            uptime: if playlist.live { stats.started.elapsed().as_secs() } else { 0 },
+            sequence: playlist.sequence,
+            video: VideoInfo {
+                codecs: playlist.video_codecs.clone(),
+                width: playlist.resolution.map(|r| r.0),
+                height: playlist.resolution.map(|r| r.1),
+                bitrate: stats.video_bitrate,
+                frame_rate: stats.frame_rate,
+                declared_frame_rate: stats.declared_frame_rate,
+                keyframe_interval: stats.keyframe_interval,
+                frames: stats.video_frames,
+                bytes: stats.video_bytes,
+            },
+            audio: AudioInfo {
+                codecs: playlist.audio_codecs.clone(),
+                sample_rate: stats.sample_rate,
+                channels: stats.channels,
+                bitrate: stats.audio_bitrate,
+                frames: stats.audio_frames,
+                bytes: stats.audio_bytes,
+            },
+            dropped_packets: stats.dropped_packets,
+            malformed_packets: stats.malformed_packets,
+            late_packets: stats.late_packets,
+            viewers: Viewers {
+                hls: playlist.hls_viewers(),
+                chat: chat_viewers,
+            },
+            title: playlist.title.clone(),
+            metadata: playlist.metadata.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn serializes_stream_info() {
+        let (tx, _rx) = mpsc::channel();
+        let mut playlist = PlayList::new(String::from("show"), tx);
+        playlist.stats = StreamStats::new(String::from("127.0.0.1:5000"), String::from("live"));
+        playlist.stats.video(0, 1000, true);
+        playlist.stats.video(2000, 500, true);
+        playlist.stats.video(1000, 500, false);
+        playlist.stats.audio(0, 200);
+        playlist.stats.malformed_packet();
+        playlist.video_codecs = Some(String::from("avc1.64001f"));
+        playlist.resolution = Some((1280, 720));
+        playlist.title = Some(String::from("hi"));
+
+        let info = serde_json::to_value(StreamInfo::new(&playlist, 3)).unwrap();
+        assert_eq!(info["live"], false);
+        assert_eq!(info["uptime"], 0);
+        assert_eq!(info["app"], "live");
+        assert_eq!(info["key"], "show");
+        assert_eq!(info["publisher"], "127.0.0.1:5000");
+        assert_eq!(
+            info["video"],
+            serde_json::json!({
+                "codecs": "avc1.64001f", "width": 1280, "height": 720, "bitrate": 0, "frame_rate": 0.0,
+                "declared_frame_rate": null, "keyframe_interval": 2000, "frames": 3, "bytes": 2000
+            })
+        );
+        assert_eq!(info["audio"], serde_json::json!({ "codecs": null, "sample_rate": null, "channels": null, "bitrate": 0, "frames": 1, "bytes": 200 }));
+        assert_eq!(info["malformed_packets"], 1);
+        assert_eq!(info["late_packets"], 1);
+        assert_eq!(info["viewers"], serde_json::json!({ "hls": 0, "chat": 3 }));
+        assert_eq!(info["title"], "hi");
+        assert_eq!(info["metadata"], serde_json::Value::Null);
+    }
+}
@@ -1,23 +1,26 @@
 mod connection;
-mod server;
+// fuzz 需要直接呼叫 server 與其中的 parser
+pub mod server;
 
 use std::sync::{Arc, Mutex};
 use std::net::TcpListener;
 use std::thread;
 use connection::Connection;
-use super::playlist::PlayList;
+use super::playlist::PlayLists;
+use super::metrics;
 
 pub struct StreamServer {}
 
 impl StreamServer {
-    pub fn start(playlist: Arc<Mutex<PlayList>>) {
+    pub fn start(playlists: Arc<Mutex<PlayLists>>) {
         let address = "0.0.0.0:1935";
         let listener = TcpListener::bind(address).unwrap();
         println!("stream server on rtmp://{}", address);
 
         thread::spawn(move || {
             for stream in listener.incoming() {
-                Connection::new(stream.unwrap(), playlist.clone());
+                metrics::RTMP_CONNECTIONS.inc();
+                Connection::start(stream.unwrap(), playlists.clone());
                 println!("new stream connection!");
             }
         });
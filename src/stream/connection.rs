@@ -4,7 +4,8 @@ use std::io::{Read, Write};
 use std::net::TcpStream;
 use std::thread;
 use super::server::{Server, ServerResult};
-use super::PlayList;
+use super::PlayLists;
+use super::super::metrics;
 
 pub struct Connection {
     socket: TcpStream,
@@ -16,16 +17,19 @@ pub struct Connection {
 impl Connection {
     const BUFFER_SIZE: usize = 4096;
 
-    pub fn new(socket: TcpStream, playlist: Arc<Mutex<PlayList>>) {
+    pub fn start(socket: TcpStream, playlists: Arc<Mutex<PlayLists>>) {
         // let mut socket = socket.try_clone().unwrap();
+        let address = socket.peer_addr().map(|address| address.ip().to_string()).unwrap_or_default();
         thread::spawn(|| {
             let mut connection = Connection {
-                socket: socket,
+                socket,
                 handshake: Handshake::new(PeerType::Server),
                 handshake_completed: false,
-                server: Server::new(playlist),
+                server: Server::new(playlists, address),
             };
+            metrics::RTMP_ACTIVE_CONNECTIONS.inc();
             connection.start_socket_reader();
+            metrics::RTMP_ACTIVE_CONNECTIONS.dec();
         });
     }
 
@@ -38,6 +42,7 @@ impl Connection {
                     return;
                 }
                 Ok(count) => {
+                    metrics::INGEST_BYTES.add(count as u64);
                     if self.handshake_completed {
                         self.server.handle_bytes(&buffer[..count])
                     } else {
@@ -46,6 +51,7 @@ impl Connection {
                 }
                 Err(error) => {
                     println!("Error occurred reading from socket: {:?}", error);
+                    metrics::RTMP_FAILURES.inc();
                     self.server.end_stream();
                     return;
                 }
@@ -55,6 +61,8 @@ impl Connection {
                 Ok(results) => results,
                 Err(error) => {
                     println!("Input caused the following server error: {}", error);
+                    metrics::RTMP_FAILURES.inc();
+                    self.server.end_stream();
                     return;
                 }
             };
@@ -72,7 +80,7 @@ impl Connection {
     }
 
     pub fn write(&mut self, bytes: Vec<u8>) {
-        match self.socket.write(&bytes) {
+        match self.socket.write_all(&bytes) {
             Ok(_) => (),
             Err(error) => {
                 println!("Error writing to socket: {:?}", error);
@@ -85,13 +93,14 @@ impl Connection {
             Ok(result) => result,
             Err(error) => {
                 println!("Handshake error: {:?}", error);
+                metrics::RTMP_HANDSHAKE_FAILURES.inc();
                 return Err(error.to_string());
             }
         };
 
         match result {
             HandshakeProcessResult::InProgress { response_bytes } => {
-                if response_bytes.len() > 0 {
+                if !response_bytes.is_empty() {
                     self.write(response_bytes);
                 }
                 Ok(vec![])
@@ -99,7 +108,8 @@ impl Connection {
 
             HandshakeProcessResult::Completed { response_bytes, remaining_bytes } => {
                 println!("Handshake successful!");
-                if response_bytes.len() > 0 {
+                metrics::RTMP_HANDSHAKES.inc();
+                if !response_bytes.is_empty() {
                     self.write(response_bytes);
                 }
 
@@ -109,3 +119,12 @@ impl Connection {
         }
     }
 }
+
+impl Drop for Connection {
+    // rml_rtmp 的 chunk 解析在錯誤的輸入下可能 panic, 結束推流以免 playlist 停在直播中
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.server.end_stream();
+        }
+    }
+}
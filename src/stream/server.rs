@@ -1,19 +1,47 @@
-mod adts;
-mod flv;
-mod nalu;
+pub mod adts;
+mod bits;
+pub mod caption;
+mod data_message;
+mod error;
+pub mod flv;
+mod hevc;
+mod id3;
+mod loas;
+mod mp3;
+pub mod nalu;
+mod opus;
+mod recording;
+mod scte35;
+mod sps;
 mod ts;
 
 use rml_rtmp::chunk_io::Packet;
 use rml_rtmp::sessions::{ServerSession, ServerSessionConfig, ServerSessionEvent, ServerSessionResult};
 use rml_rtmp::time::RtmpTimestamp;
 use std::sync::{Arc, Mutex};
-use std::{fs, thread};
+use std::time::Instant;
+use std::{fs, io, thread};
 use bytes::Bytes;
-use ts::TransportStream;
-use flv::{Flv, DataType};
-use nalu::{Nalu, NaluConfig};
+use ts::{AudioStream, TransportStream};
+use flv::{AudioCodec, Flv, VideoCodec};
+use mpeg2ts::es::StreamType;
+use nalu::{Nalu, NaluConfig, NaluFilter};
 use adts::{Adts, AdtsConfig};
-use super::PlayList;
+use caption::Captions;
+use recording::Recording;
+use data_message::{DataMessage, DataMessages, Input};
+use id3::Id3;
+use error::ParseError;
+use loas::Loas;
+use mp3::Mp3Config;
+use opus::{Opus, OpusConfig};
+use scte35::SpliceInsert;
+use rml_amf0::Amf0Value;
+use super::PlayLists;
+use super::super::playlist::{CueTag, SpliceRequest};
+use super::super::stats::{StreamMetadata, StreamStats};
+use super::super::metrics;
+use super::super::chat;
 
 pub enum ServerResult {
     Disconnect,
@@ -21,29 +49,63 @@ pub enum ServerResult {
 }
 
 pub struct Server {
-    flv: Flv,
+    // CHAT_LOG=1 時保存整場直播, 聊天紀錄回放時使用
+    recording: Option<Recording>,
     ts: TransportStream,
+    audio_ts: TransportStream,
     video_config: NaluConfig,
+    nalu_filter: NaluFilter,
+    captions: Captions,
     audio_config: AdtsConfig,
+    opus_config: OpusConfig,
+    // 目前的音訊編碼, AAC 與 Opus 在 sequence header 時決定, MP3 在第一個 frame 時決定
+    audio_codec: Option<AudioCodec>,
+    unsupported_audio: bool,
     has_keyframe: bool,
     session: Option<ServerSession>,
-    playlist: Arc<Mutex<PlayList>>,
+    data_messages: DataMessages,
+    playlists: Arc<Mutex<PlayLists>>,
+    stream_key: String,
+    address: String,
+    stats: StreamStats,
+    // 下一個切點, abr group 時間(ms)
     next_write: u32,
+    // 第一個 keyframe 時決定, RTMP timestamp 加上此值為 abr group 時間
+    time_offset: Option<u32>,
+    // 下一個 keyframe 要執行的廣告插入
+    pending_splice: Option<SpliceRequest>,
+    // 廣告中: (event id, 預計結束時間(ms))
+    splice_out: Option<(u32, Option<u32>)>,
+    splice_event_id: u32,
 }
 
 impl Server {
     const WRITE_DURATION: u32 = 2000;
 
-    pub fn new(playlist: Arc<Mutex<PlayList>>) -> Server {
+    pub fn new(playlists: Arc<Mutex<PlayLists>>, address: String) -> Server {
         Server {
-            flv: Flv::new(),
+            recording: None,
             ts: TransportStream::new(),
+            audio_ts: TransportStream::audio_only(),
             video_config: NaluConfig::new(),
+            nalu_filter: NaluFilter::from_env(),
+            captions: Captions::new(),
             audio_config: AdtsConfig::new(),
+            opus_config: OpusConfig::new(),
+            audio_codec: None,
+            unsupported_audio: false,
             has_keyframe: false,
             session: None,
-            playlist,
+            data_messages: DataMessages::new(),
+            playlists,
+            stream_key: String::from(""),
+            stats: StreamStats::new(address.clone(), String::from("")),
+            address,
             next_write: Server::WRITE_DURATION,
+            time_offset: None,
+            pending_splice: None,
+            splice_out: None,
+            splice_event_id: 0,
         }
     }
 
@@ -69,12 +131,15 @@ impl Server {
 
     pub fn handle_bytes(&mut self, bytes: &[u8]) -> Result<Vec<ServerResult>, String> {
         let mut server_results = Vec::new();
-        let session_results = match self.session.as_mut().unwrap().handle_input(bytes) {
-            Ok(results) => results,
-            Err(error) => return Err(error.to_string()),
-        };
-
-        self.handle_session_results(session_results, &mut server_results);
+        for input in self.data_messages.read(bytes)? {
+            match input {
+                Input::Session(bytes) => {
+                    let session_results = self.session.as_mut().unwrap().handle_input(&bytes).map_err(|error| error.to_string())?;
+                    self.handle_session_results(session_results, &mut server_results);
+                }
+                Input::Data(message) => self.handle_data_message(message),
+            }
+        }
         Ok(server_results)
     }
 
@@ -117,10 +182,61 @@ impl Server {
             } => {
                 self.handle_audio(timestamp, data);
             }
+            ServerSessionEvent::StreamMetadataChanged { app_name: _, stream_key, metadata } if stream_key == self.stream_key => {
+                self.playlists.lock().unwrap().stream_mut(&stream_key).set_metadata(StreamMetadata::from(&metadata));
+            }
             _ => (), // println!("Event raised {:?}", event),
         }
     }
 
+    // onTextData (或 @setDataFrame onTextData) 的內容轉成 ID3 timed metadata
+    fn handle_data_message(&mut self, mut message: DataMessage) {
+        if message.name == "onTextData" && !message.values.is_empty() {
+            let fields = DataMessages::fields(message.values.remove(0));
+            self.push_timed_metadata(&fields);
+        } else if message.name == "onCuePoint" && !message.values.is_empty() {
+            match Server::cue_point(message.values.remove(0)) {
+                Some(request) => self.pending_splice = Some(request),
+                None => println!("Ignored onCuePoint on stream key '{}'", self.stream_key),
+            }
+        }
+    }
+
+    // onCuePoint: {name, time, type, parameters}, name 為 CUE-OUT 時進入廣告, CUE-IN 時回到節目, 其他的 cue point 不處理
+    // duration (秒) 與 id 可以放在 parameters 或最外層
+    fn cue_point(value: Amf0Value) -> Option<SpliceRequest> {
+        let mut properties = value.get_object_properties().unwrap_or_default();
+        let parameters = properties.remove("parameters");
+        let mut fields = DataMessages::fields(Amf0Value::Object(properties));
+        fields.extend(parameters.map(DataMessages::fields).unwrap_or_default());
+        let field = |name: &str| fields.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, value)| value.trim().to_string());
+
+        let out = match field("name").unwrap_or_default().to_ascii_lowercase().as_str() {
+            "cue-out" | "out" | "splice-out" => true,
+            "cue-in" | "in" | "splice-in" => false,
+            _ => return None,
+        };
+        Some(SpliceRequest {
+            out,
+            duration: field("duration").and_then(|d| d.parse::<f64>().ok()).filter(|d| d.is_finite() && *d > 0.0),
+            id: field("id").and_then(|id| id.parse().ok()),
+        })
+    }
+
+    // 開始輸出 ts (收到 keyframe) 之後才寫入
+    fn push_timed_metadata(&mut self, fields: &[(String, String)]) {
+        if fields.is_empty() || !self.has_keyframe {
+            return;
+        }
+        let id3 = Id3::tag(fields);
+        if id3.len() > Id3::MAX_SIZE {
+            println!("Timed metadata on stream key '{}' is {} bytes, larger than {}, dropped", self.stream_key, id3.len(), Id3::MAX_SIZE);
+            return;
+        }
+        self.ts.push_metadata(&id3);
+        self.audio_ts.push_metadata(&id3);
+    }
+
     fn handle_connection_requested(&mut self, request_id: u32, app_name: String, server_results: &mut Vec<ServerResult>) {
         println!("Connection requested connection to app '{}'", app_name);
 
@@ -136,19 +252,43 @@ impl Server {
 
     fn handle_publish_requested(&mut self, request_id: u32, app_name: String, stream_key: String, server_results: &mut Vec<ServerResult>) {
         println!("Publish requested on app '{}' and stream key '{}'", app_name, stream_key);
-        // self.flv.init_file(String::from("./video.flv"));
 
+        if !PlayLists::is_valid_key(&stream_key) {
+            println!("Invalid stream key '{}'", stream_key);
+            server_results.push(ServerResult::Disconnect);
+            return;
+        }
+
+        if self.playlists.lock().unwrap().streams.get(&stream_key).is_some_and(|playlist| playlist.live) {
+            server_results.push(ServerResult::Disconnect);
+            return;
+        }
+        let directory = format!("./video/{}", stream_key);
+        let _ = fs::remove_dir_all(&directory);
+        if let Err(error) = fs::create_dir_all(format!("{}/audio", directory)).and_then(|_| fs::create_dir_all(format!("{}/subtitles", directory))) {
+            println!("Failed to create directory '{}': {}", directory, error);
+            server_results.push(ServerResult::Disconnect);
+            return;
+        }
+
+        let recording;
         {
-            let mut playlist = self.playlist.lock().unwrap();
-            if playlist.live {
-                server_results.push(ServerResult::Disconnect);
-                return;
-            }
+            let mut playlists = self.playlists.lock().unwrap();
+            let playlist = playlists.stream_mut(&stream_key);
             playlist.reset();
+            playlist.publishing = true;
+            recording = chat::log_enabled().then(|| playlist.recording());
+            playlist.set_media_time(0);
+            self.stats = StreamStats::new(self.address.clone(), app_name);
+            playlist.stats = self.stats.clone();
+            playlists.current = Some(stream_key.clone());
+            if let Some(group) = playlists.group_of(&stream_key) {
+                println!("Stream key '{}' joins abr group '{}'", stream_key, group);
+            }
         }
 
-        fs::remove_dir_all("./video").unwrap();
-        fs::create_dir_all("./video").unwrap();
+        self.recording = recording.map(Recording::new);
+        self.stream_key = stream_key;
 
         let accept_result = self.session.as_mut().unwrap().accept_request(request_id);
         match accept_result {
@@ -161,65 +301,436 @@ impl Server {
     }
 
     fn handle_video(&mut self, timestamp: RtmpTimestamp, data: Bytes) {
-        let video = Flv::read_video(data.clone());
+        let video = match Flv::read_video(data.clone()) {
+            Ok(video) => video,
+            Err(error) => return self.malformed_packet("video", error),
+        };
+        let codec = match video.codec {
+            Some(codec) => codec,
+            None => {
+                if video.is_sequence_header {
+                    println!("Unsupported video codec on stream key '{}'", self.stream_key);
+                }
+                self.stats.drop_packet();
+                return;
+            }
+        };
+        // 序列結束或與 sequence header 不同編碼的封包
+        if !video.is_sequence_header && (!video.is_frame || codec != self.video_config.codec) {
+            return;
+        }
         if video.is_keyframe {
             self.has_keyframe = true;
         }
         if !(self.has_keyframe || video.is_sequence_header) {
+            self.stats.drop_packet();
             return;
         }
-        // self.flv.push(DataType::Video, timestamp.value, video.is_keyframe, data.clone());
 
         if video.is_sequence_header {
-            self.video_config.set(video.data.clone());
+            let is_changed = self.video_config.is_changed(codec, &video.data);
+            if let Err(error) = self.video_config.set(codec, video.data.clone()) {
+                return self.malformed_packet("video", error);
+            }
+            // 推流中途更換設定(解析度等), 之前的內容先切成 segment, 新的 segment 從下一個 keyframe 開始
+            if is_changed && self.has_keyframe {
+                println!("Video sequence header changed on stream key '{}'", self.stream_key);
+                self.cut_segment(timestamp.value);
+                self.has_keyframe = false;
+                self.playlists.lock().unwrap().stream_mut(&self.stream_key).pending_discontinuity = true;
+                if let Some(recording) = &mut self.recording {
+                    recording.discontinuity = true;
+                }
+            }
+            self.ts.set_video_stream_type(match codec {
+                VideoCodec::Avc => StreamType::H264,
+                VideoCodec::Hevc => StreamType::H265,
+            });
+            let mut playlists = self.playlists.lock().unwrap();
+            let playlist = playlists.stream_mut(&self.stream_key);
+            playlist.video_codecs = Some(self.video_config.codecs());
+            playlist.resolution = self.video_config.resolution();
+            self.stats.declared_frame_rate = self.video_config.frame_rate();
             return;
         }
 
-        if video.is_keyframe {
-            if timestamp.value > self.next_write {
-                let mut playlist = self.playlist.lock().unwrap();
-                let filename = format!("{}.ts", timestamp.value);
-                self.ts.write_file(&filename);
-                self.next_write = timestamp.value + Server::WRITE_DURATION;
-                playlist.push(timestamp.value, filename, false);
-            }
+        let nalu = match Nalu::read(video.data, self.video_config.nalu_size, codec) {
+            Ok(nalu) => nalu,
+            Err(error) => return self.malformed_packet("video", error),
+        };
+
+        self.stats.video(timestamp.value, data.len(), video.is_keyframe);
+        self.sync_stats(timestamp.value);
+
+        // 字幕依 pts 排序後解碼, 在切點之前完成才會寫入這個 segment
+        let dts = timestamp.value as u64;
+        self.captions.push(dts, dts + video.composition_time, Captions::read_cc_data(&nalu, codec));
+
+        // 廣告插入點在 keyframe 切開, 預計長度結束後自動回到節目
+        // 切點為 abr group 時間經過 WRITE_DURATION 的倍數後的第一個 keyframe
+        // group 中各 publish 的 keyframe 要在相同的時間 (同一台編碼器輸出、GOP 相同) 才會在同一處切開, 否則只有 media sequence 一致
+        let time = self.group_time(timestamp.value);
+        let pts = timestamp.value as u64 + video.composition_time;
+        let splice = if video.is_keyframe { self.pending_splice.take() } else { None };
+        if let Some(request) = splice {
+            self.splice(timestamp.value, pts, request);
+        } else if video.is_keyframe && self.splice_out.is_some_and(|(_, end)| end.is_some_and(|end| timestamp.value >= end)) {
+            self.splice_in(timestamp.value, None);
+        } else if video.is_keyframe && time >= self.next_write {
+            self.cut_segment(timestamp.value);
         }
 
-        let nalu = Nalu::read(video.data, self.video_config.nalu_size);
-        let es = Nalu::to_es_layer(&self.video_config, nalu);
-        self.ts.push_video(timestamp.value as u64, video.composition_time, video.is_keyframe, es).unwrap();
+        let es = Nalu::to_es_layer(&self.video_config, &self.nalu_filter, nalu);
+        self.ts.push_video(timestamp.value as u64, video.composition_time, video.is_keyframe, es);
     }
 
     fn handle_audio(&mut self, timestamp: RtmpTimestamp, data: Bytes) {
-        let audio = Flv::read_audio(data.clone());
+        let audio = match Flv::read_audio(data.clone()) {
+            Ok(audio) => audio,
+            Err(error) => return self.malformed_packet("audio", error),
+        };
+        let codec = match audio.codec {
+            Some(codec) => codec,
+            None => {
+                // 不支援的編碼只輸出影像
+                if !self.unsupported_audio {
+                    println!("Unsupported audio codec on stream key '{}', audio is dropped", self.stream_key);
+                    self.unsupported_audio = true;
+                    self.audio_codec = None;
+                    self.set_audio_stream(None, None);
+                }
+                self.stats.drop_packet();
+                return;
+            }
+        };
         if !(self.has_keyframe || audio.is_sequence_header) {
+            self.stats.drop_packet();
             return;
         }
-        // self.flv.push(DataType::Audio, timestamp.value, false, data.clone());
 
         if audio.is_sequence_header {
-            self.audio_config.set(audio.data.clone());
+            match codec {
+                AudioCodec::Aac => match self.audio_config.set(audio.data.clone()) {
+                    Ok(()) => {
+                        let config = &self.audio_config;
+                        let channels = config.channels().map_or(String::from("unknown"), |channels| channels.to_string());
+                        println!("Audio aac: {} {} Hz, {} channels{}", config.codecs(), config.output_sample_rate, channels, if config.is_adts() { "" } else { ", using loas" });
+                        let audio_stream = if config.is_adts() { AudioStream::aac() } else { AudioStream::loas() };
+                        self.audio_codec = Some(codec);
+                        self.stats.sample_rate = Some(config.output_sample_rate);
+                        self.stats.channels = config.channels();
+                        self.set_audio_stream(Some(audio_stream), Some(self.audio_config.codecs()));
+                    }
+                    Err(error) => self.malformed_packet("audio", error),
+                },
+                AudioCodec::Opus => match self.opus_config.set(audio.data.clone()) {
+                    Ok(()) => {
+                        self.audio_codec = Some(codec);
+                        self.stats.sample_rate = Some(self.opus_config.sample_rate);
+                        self.stats.channels = Some(self.opus_config.channels);
+                        self.set_audio_stream(Some(AudioStream::opus(self.opus_config.channels)), Some(self.opus_config.codecs()));
+                    }
+                    Err(error) => self.malformed_packet("audio", error),
+                },
+                AudioCodec::Mp3 => (),
+            }
+            return;
+        }
+        if !audio.is_frame {
             return;
         }
+        if codec == AudioCodec::Mp3 && self.audio_codec != Some(codec) {
+            match Mp3Config::read(&audio.data) {
+                Ok(config) => {
+                    println!("Audio mp3: {} Hz, {} channels", config.sample_rate, config.channels);
+                    self.audio_codec = Some(codec);
+                    self.stats.sample_rate = Some(config.sample_rate);
+                    self.stats.channels = Some(config.channels);
+                    self.set_audio_stream(Some(AudioStream::mp3(config.mpeg1)), Some(config.codecs()));
+                }
+                Err(error) => return self.malformed_packet("audio", error),
+            }
+        }
+        // 沒有 sequence header 或與 sequence header 不同編碼的封包
+        if self.audio_codec != Some(codec) {
+            self.stats.drop_packet();
+            return;
+        }
+
+        self.stats.audio(timestamp.value, data.len());
+        self.sync_stats(timestamp.value);
 
-        let es = Adts::to_es_layer(&self.audio_config, audio.data.to_vec());
-        self.ts.push_audio(timestamp.value as u64, es);
+        let es = match codec {
+            AudioCodec::Aac if self.audio_config.is_adts() => Adts::to_es_layer(&self.audio_config, audio.data.to_vec()),
+            AudioCodec::Aac => Loas::to_es_layer(&self.audio_config, audio.data.to_vec()),
+            AudioCodec::Mp3 => audio.data.to_vec(),
+            AudioCodec::Opus => Opus::to_es_layer(&audio.data),
+        };
+        self.ts.push_audio(timestamp.value as u64, es.clone());
+        self.audio_ts.push_audio(timestamp.value as u64, es);
+    }
+
+    // 無法解析的封包計數後丟棄, 每次推流只輸出第一個錯誤
+    fn malformed_packet(&mut self, kind: &str, error: ParseError) {
+        if self.stats.malformed_packets == 0 {
+            println!("Malformed {} packet on stream key '{}': {}", kind, self.stream_key, error);
+        }
+        self.stats.malformed_packet();
+        metrics::MALFORMED_PACKETS.inc();
+    }
+
+    fn set_audio_stream(&mut self, audio_stream: Option<AudioStream>, codecs: Option<String>) {
+        self.ts.set_audio_stream(audio_stream.clone());
+        self.audio_ts.set_audio_stream(audio_stream);
+        self.playlists.lock().unwrap().stream_mut(&self.stream_key).audio_codecs = codecs;
+    }
+
+    fn splice(&mut self, timestamp: u32, pts: u64, request: SpliceRequest) {
+        if !request.out {
+            match self.splice_out {
+                Some((id, _)) => {
+                    let section = SpliceInsert { event_id: id, out_of_network: false, pts: TransportStream::clock(pts), duration: None }.section();
+                    self.splice_in(timestamp, Some(section));
+                }
+                None => println!("Cue in without cue out on stream key '{}'", self.stream_key),
+            }
+            return;
+        }
+        if self.splice_out.is_some() {
+            println!("Cue out during an ad break on stream key '{}'", self.stream_key);
+            return;
+        }
+
+        let id = request.id.unwrap_or_else(|| {
+            self.splice_event_id += 1;
+            self.splice_event_id
+        });
+        let duration = request.duration.map(|duration| (duration * 1000.0) as u32);
+        let section = SpliceInsert {
+            event_id: id,
+            out_of_network: true,
+            pts: TransportStream::clock(pts),
+            duration: duration.map(|duration| duration as u64 * 90),
+        }
+        .section();
+        println!("Cue out {} on stream key '{}'", id, self.stream_key);
+
+        self.cut_segment(timestamp);
+        self.ts.push_section(&section);
+        self.audio_ts.push_section(&section);
+        self.splice_out = Some((id, duration.map(|duration| timestamp.saturating_add(duration))));
+        self.playlists.lock().unwrap().stream_mut(&self.stream_key).pending_cue = Some(CueTag::Out {
+            id,
+            duration: request.duration,
+            scte35: SpliceInsert::hex(&section),
+        });
+    }
+
+    // section 為 None 時是依 break_duration 自動回到節目, 不另外送出 splice_insert
+    fn splice_in(&mut self, timestamp: u32, section: Option<Vec<u8>>) {
+        println!("Cue in on stream key '{}'", self.stream_key);
+        self.cut_segment(timestamp);
+        if let Some(section) = &section {
+            self.ts.push_section(section);
+            self.audio_ts.push_section(section);
+        }
+        self.splice_out = None;
+        self.playlists.lock().unwrap().stream_mut(&self.stream_key).pending_cue = Some(CueTag::In {
+            scte35: section.map(|section| SpliceInsert::hex(&section)),
+        });
+    }
+
+    // 同時更新聊天訊息使用的串流時間, 並取出管理 API 送來的 timed metadata 與廣告插入請求
+    fn sync_stats(&mut self, timestamp: u32) {
+        if self.stats.tick() {
+            let timed_metadata = {
+                let mut playlists = self.playlists.lock().unwrap();
+                let playlist = playlists.stream_mut(&self.stream_key);
+                playlist.stats = self.stats.clone();
+                playlist.set_media_time(timestamp);
+                if let Some(request) = playlist.splice_requests.drain(..).next_back() {
+                    self.pending_splice = Some(request);
+                }
+                std::mem::take(&mut playlist.timed_metadata)
+            };
+            for fields in timed_metadata {
+                self.push_timed_metadata(&fields);
+            }
+        }
+    }
+
+    // 第一次呼叫時決定 time_offset, 並從下一個 WRITE_DURATION 的倍數開始切
+    fn group_time(&mut self, timestamp: u32) -> u32 {
+        let offset = match self.time_offset {
+            Some(offset) => offset,
+            None => {
+                let offset = self.playlists.lock().unwrap().start_segments(&self.stream_key, timestamp, Server::WRITE_DURATION);
+                self.time_offset = Some(offset);
+                if let Some(recording) = &mut self.recording {
+                    recording.start(timestamp);
+                }
+                self.next_write = (timestamp.saturating_add(offset) / Server::WRITE_DURATION + 1) * Server::WRITE_DURATION;
+                offset
+            }
+        };
+        timestamp.saturating_add(offset)
+    }
+
+    fn cut_segment(&mut self, timestamp: u32) {
+        // 第一個 keyframe 就是 splice 點時, 之前沒有內容可以切
+        if self.ts.is_empty() {
+            return;
+        }
+        let started = Instant::now();
+        let filename = format!("{}.ts", timestamp);
+        let subtitles = self.captions.segment(timestamp as u64);
+        let time = timestamp.saturating_add(self.time_offset.unwrap_or(0));
+        self.next_write = (time / Server::WRITE_DURATION + 1) * Server::WRITE_DURATION;
+        // 寫入失敗時丟棄這個 segment, 下一個 segment 照常切
+        let bytes = match self.write_files(&filename, subtitles) {
+            Ok(bytes) => bytes,
+            Err(error) => {
+                println!("Failed to write segment '{}' on stream key '{}': {}", filename, self.stream_key, error);
+                return;
+            }
+        };
+        if let Some(recording) = &mut self.recording {
+            recording.push(&format!("./video/{}/{}", self.stream_key, filename), timestamp);
+        }
+        let mut playlists = self.playlists.lock().unwrap();
+        let sequence = playlists.next_sequence(&self.stream_key);
+        let playlist = playlists.stream_mut(&self.stream_key);
+        if self.captions.detected && !playlist.subtitles {
+            println!("Closed captions detected on stream key '{}'", self.stream_key);
+            playlist.subtitles = true;
+        }
+        playlist.push(sequence, timestamp, filename, bytes, false);
+        metrics::PLAYLIST_UPDATE.observe(started.elapsed().as_secs_f64());
+    }
+
+    // 每個 ts 檔都有對應的 WebVTT, 沒有字幕時只有檔頭
+    // 其中一個失敗時其他的仍會寫入, 清空暫存的內容
+    fn write_files(&mut self, filename: &str, subtitles: String) -> io::Result<(usize, usize)> {
+        let bytes = self.ts.write_file(&format!("{}/{}", self.stream_key, filename));
+        let audio_bytes = self.audio_ts.write_file(&format!("{}/audio/{}", self.stream_key, filename));
+        fs::write(format!("./video/{}/subtitles/{}", self.stream_key, filename.replace(".ts", ".vtt")), subtitles)?;
+        Ok((bytes?, audio_bytes?))
     }
 
     pub fn end_stream(&mut self) {
-        // self.flv.write_file();
-        self.ts.write_file("0.ts");
+        // panic 時 playlists 可能已被 poison, 再 panic 會直接 abort
+        if self.stream_key.is_empty() || self.playlists.is_poisoned() {
+            return;
+        }
+
+        let subtitles = self.captions.last_segment();
+        let bytes = self.write_files("0.ts", subtitles);
+        if let Err(error) = &bytes {
+            println!("Failed to write the last segment on stream key '{}': {}", self.stream_key, error);
+        }
+        if let Some(mut recording) = self.recording.take() {
+            recording.finish(&format!("./video/{}/0.ts", self.stream_key), self.stats.last_timestamp());
+        }
 
         let duration = {
-            let mut playlist = self.playlist.lock().unwrap();
-            playlist.push(0, "0.ts".to_string(), true) * 1000 + 1000
+            let mut playlists = self.playlists.lock().unwrap();
+            let sequence = playlists.next_sequence(&self.stream_key);
+            let playlist = playlists.stream_mut(&self.stream_key);
+            playlist.stats = self.stats.clone();
+            playlist.publishing = false;
+            match bytes {
+                Ok(bytes) => playlist.push(sequence, 0, "0.ts".to_string(), bytes, true) * 1000 + 1000,
+                // 最後的 segment 寫入失敗時只結束 playlist
+                Err(_) => {
+                    playlist.update(true);
+                    1000
+                }
+            }
         };
 
-        let playlist = self.playlist.clone();
+        let playlists = self.playlists.clone();
+        let stream_key = std::mem::take(&mut self.stream_key);
         thread::spawn(move || {
             let duration = std::time::Duration::from_millis(duration);
             std::thread::sleep(duration);
-            playlist.lock().unwrap().live = false;
+            let mut playlists = playlists.lock().unwrap();
+            playlists.stream_mut(&stream_key).live = false;
+            if playlists.current.as_ref() == Some(&stream_key) {
+                playlists.current = playlists.streams.values().find(|p| p.live).map(|p| p.name.clone());
+            }
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rml_rtmp::chunk_io::ChunkSerializer;
+    use rml_rtmp::messages::RtmpMessage;
+    use std::collections::HashMap;
+
+    fn cue_point(name: &str, parameters: &[(&str, Amf0Value)]) -> Amf0Value {
+        let mut properties = HashMap::new();
+        properties.insert(String::from("name"), Amf0Value::Utf8String(name.to_string()));
+        properties.insert(String::from("type"), Amf0Value::Utf8String(String::from("event")));
+        let parameters = parameters.iter().map(|(k, v)| (k.to_string(), v.clone())).collect();
+        properties.insert(String::from("parameters"), Amf0Value::Object(parameters));
+        Amf0Value::Object(properties)
+    }
+
+    #[test]
+    fn cue_point_out_and_in() {
+        let request = Server::cue_point(cue_point("CUE-OUT", &[("duration", Amf0Value::Utf8String(String::from("30"))), ("id", Amf0Value::Number(7.0))])).unwrap();
+        assert!(request.out);
+        assert_eq!(request.duration, Some(30.0));
+        assert_eq!(request.id, Some(7));
+
+        let request = Server::cue_point(cue_point("splice-in", &[])).unwrap();
+        assert!(!request.out);
+        assert_eq!(request.duration, None);
+    }
+
+    #[test]
+    fn cue_point_ignores_unknown_names() {
+        assert!(Server::cue_point(cue_point("chapter-2", &[])).is_none());
+        assert!(Server::cue_point(Amf0Value::Null).is_none());
+    }
+
+    fn chunks(serializer: &mut ChunkSerializer, message: RtmpMessage) -> Vec<u8> {
+        let payload = message.into_message_payload(RtmpTimestamp::new(0), 1).unwrap();
+        serializer.serialize(&payload, false, false).unwrap().bytes
+    }
+
+    // 推流端改變 chunk size 後, ServerSession 仍能讀取重新序列化的 message, data message 也會交給 server
+    #[test]
+    fn reads_data_messages_from_the_same_input() {
+        let mut server = Server::new(Arc::new(Mutex::new(PlayLists::new())), String::from("test"));
+        server.handle_handshake_bytes(&[]).unwrap();
+
+        let mut serializer = ChunkSerializer::new();
+        let mut bytes = chunks(&mut serializer, RtmpMessage::SetChunkSize { size: 4096 });
+        serializer.set_max_chunk_size(4096, RtmpTimestamp::new(0)).unwrap();
+        let mut properties = HashMap::new();
+        properties.insert(String::from("app"), Amf0Value::Utf8String(String::from("live")));
+        properties.insert(String::from("tcUrl"), Amf0Value::Utf8String(format!("rtmp://127.0.0.1/live?{}", "a".repeat(200))));
+        bytes.extend(chunks(
+            &mut serializer,
+            RtmpMessage::Amf0Command {
+                command_name: String::from("connect"),
+                transaction_id: 1.0,
+                command_object: Amf0Value::Object(properties),
+                additional_arguments: Vec::new(),
+            },
+        ));
+        bytes.extend(chunks(
+            &mut serializer,
+            RtmpMessage::Amf0Data {
+                values: vec![Amf0Value::Utf8String(String::from("onCuePoint")), cue_point("CUE-OUT", &[("duration", Amf0Value::Number(30.0))])],
+            },
+        ));
+
+        // connect 被接受時回應 window ack size、set peer bandwidth 等
+        assert!(!server.handle_bytes(&bytes).unwrap().is_empty());
+        assert!(server.pending_splice.as_ref().is_some_and(|request| request.out && request.duration == Some(30.0)));
+    }
+}
@@ -1,36 +1,192 @@
-use bytes::{Bytes, Buf};
+use bytes::Bytes;
+use super::bits::BitReader;
+use super::error::ParseError;
 
-// Flv Data - Audio Sequence_Header
+// Flv Data - Audio Sequence_Header (AudioSpecificConfig, ISO/IEC 14496-3 1.6.2.1)
 // ------------------------| ----
-// Object Type             | u5
-// Frequency Index         | u4
-// Channel Configuration   | u4
-// AOT Specific Config
+// Object Type             | u5    31: 32 + u6
+// Frequency Index         | u4    15: 之後為 u24 的取樣率
+// Channel Configuration   | u4    0: 由 program config element 決定
+// (5 = SBR, 29 = PS 時)
+// Extension Frequency     | u4    SBR 後的取樣率, 15: u24
+// Object Type             | u5    核心的編碼
+// GA Specific Config
 // Frame Length Flag       | u1
-// Depends On Core Coder   | u1
+// Depends On Core Coder   | u1    1: core coder delay u14
 // Extension Flag          | u1
+// (向下相容的 SBR/PS 訊號)
+// Sync Extension Type     | u11   0x2b7: object type, 5: sbr present u1, extension frequency
+// Sync Extension Type     | u11   0x548: ps present u1
 pub struct AdtsConfig {
+    // sequence header 宣告的 object type, 5 = HE-AAC, 29 = HE-AACv2
+    pub signaled_object_type: u8,
+    // SBR/PS 之下的核心編碼, 2 = AAC-LC
     pub object_type: u8,
     pub sampling_frequency_index: u8,
+    pub sample_rate: u32,
     pub channel_configuration: u8,
+    pub sbr: bool,
+    pub ps: bool,
+    // 含 SBR 時為解碼後的取樣率
+    pub output_sample_rate: u32,
+    // 原始的 AudioSpecificConfig, 改用 LOAS 時放入 StreamMuxConfig
+    pub config: Bytes,
 }
 
 impl AdtsConfig {
+    const SAMPLE_RATES: [u32; 13] = [96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350];
+    const EXPLICIT_FREQUENCY: u8 = 15;
+    // 含 GASpecificConfig 的 object type
+    const GA_OBJECT_TYPES: &'static [u8] = &[1, 2, 3, 4, 6, 7, 17, 19, 20, 21, 22, 23];
+
     pub fn new() -> AdtsConfig {
         AdtsConfig {
+            signaled_object_type: 0,
             object_type: 0,
             sampling_frequency_index: 0,
+            sample_rate: 0,
             channel_configuration: 0,
+            sbr: false,
+            ps: false,
+            output_sample_rate: 0,
+            config: Bytes::new(),
+        }
+    }
+
+    pub fn set(&mut self, data: Bytes) -> Result<(), ParseError> {
+        let config = AdtsConfig::read(&data).ok_or(ParseError::Truncated("aac AudioSpecificConfig"))?.map_err(ParseError::Invalid)?;
+        *self = AdtsConfig { config: data, ..config };
+        Ok(())
+    }
+
+    fn read(data: &[u8]) -> Option<Result<AdtsConfig, String>> {
+        let mut reader = BitReader::new(data);
+
+        let signaled_object_type = AdtsConfig::read_object_type(&mut reader)?;
+        if signaled_object_type == 0 {
+            return Some(Err(String::from("aac: invalid object type 0")));
         }
+        let (sampling_frequency_index, sample_rate) = match AdtsConfig::read_frequency(&mut reader)? {
+            Ok(frequency) => frequency,
+            Err(error) => return Some(Err(error)),
+        };
+        let channel_configuration = reader.read_bits(4)? as u8;
+
+        let mut config = AdtsConfig {
+            signaled_object_type,
+            object_type: signaled_object_type,
+            sampling_frequency_index,
+            sample_rate,
+            channel_configuration,
+            sbr: false,
+            ps: false,
+            output_sample_rate: sample_rate,
+            config: Bytes::new(),
+        };
+
+        // explicit hierarchical signaling
+        if signaled_object_type == 5 || signaled_object_type == 29 {
+            config.sbr = true;
+            config.ps = signaled_object_type == 29;
+            config.output_sample_rate = match AdtsConfig::read_frequency(&mut reader)? {
+                Ok((_, rate)) => rate,
+                Err(error) => return Some(Err(error)),
+            };
+            config.object_type = AdtsConfig::read_object_type(&mut reader)?;
+            return Some(Ok(config));
+        }
+
+        // backward compatible signaling, 讀不到時視為沒有 SBR
+        if AdtsConfig::GA_OBJECT_TYPES.contains(&config.object_type) && channel_configuration != 0 {
+            let _ = config.read_sync_extension(&mut reader);
+        }
+        Some(Ok(config))
     }
 
-    pub fn set(&mut self, mut data: Bytes) {
-        let byte0 = data.get_u8();
-        let byte1 = data.get_u8();
+    fn read_object_type(reader: &mut BitReader) -> Option<u8> {
+        let object_type = reader.read_bits(5)? as u8;
+        if object_type == 31 {
+            return Some(32 + reader.read_bits(6)? as u8);
+        }
+        Some(object_type)
+    }
+
+    fn read_frequency(reader: &mut BitReader) -> Option<Result<(u8, u32), String>> {
+        let index = reader.read_bits(4)? as u8;
+        if index == AdtsConfig::EXPLICIT_FREQUENCY {
+            let rate = reader.read_bits(24)?;
+            if rate == 0 {
+                return Some(Err(String::from("aac: invalid sampling frequency 0")));
+            }
+            return Some(Ok((index, rate)));
+        }
+        match AdtsConfig::SAMPLE_RATES.get(index as usize) {
+            Some(&rate) => Some(Ok((index, rate))),
+            None => Some(Err(format!("aac: reserved sampling frequency index {}", index))),
+        }
+    }
+
+    fn read_sync_extension(&mut self, reader: &mut BitReader) -> Option<()> {
+        reader.skip_bits(1)?; // frame_length_flag
+        if reader.read_flag()? {
+            reader.skip_bits(14)?; // core_coder_delay
+        }
+        let extension_flag = reader.read_flag()?;
+        if self.object_type == 6 || self.object_type == 20 {
+            reader.skip_bits(3)?; // layer_nr
+        }
+        if extension_flag {
+            if self.object_type == 22 {
+                reader.skip_bits(16)?; // num_of_sub_frame, layer_length
+            }
+            if [17, 19, 20, 23].contains(&self.object_type) {
+                reader.skip_bits(3)?; // resilience flags
+            }
+            reader.skip_bits(1)?; // extension_flag3
+        }
+
+        if reader.remaining() < 16 || reader.read_bits(11)? != 0x2b7 {
+            return Some(());
+        }
+        if AdtsConfig::read_object_type(reader)? != 5 || !reader.read_flag()? {
+            return Some(());
+        }
+        self.sbr = true;
+        self.output_sample_rate = AdtsConfig::read_frequency(reader)?.ok()?.1;
+        if reader.remaining() >= 12 && reader.read_bits(11)? == 0x548 {
+            self.ps = reader.read_flag()?;
+        }
+        Some(())
+    }
 
-        self.object_type = (byte0 & 0xF8) >> 3;
-        self.sampling_frequency_index = ((byte0 & 0x07) << 1) | (byte1 >> 7);
-        self.channel_configuration = (byte1 >> 3) & 0x0F;
+    // ADTS 只能表示 Main/LC/SSR/LTP, 表中的取樣率與 channel configuration 1~7
+    pub fn is_adts(&self) -> bool {
+        (1..=4).contains(&self.object_type) && (1..=7).contains(&self.channel_configuration) && self.adts_frequency_index().is_some()
+    }
+
+    // 明確指定的取樣率與表中相同時也能使用
+    fn adts_frequency_index(&self) -> Option<u8> {
+        AdtsConfig::SAMPLE_RATES.iter().position(|&rate| rate == self.sample_rate).map(|index| index as u8)
+    }
+
+    pub fn channels(&self) -> Option<u8> {
+        let channels = match self.channel_configuration {
+            0 => return None,
+            7 => 8,
+            channels => channels,
+        };
+        // PS 將單聲道還原為立體聲
+        Some(if self.ps && channels == 1 { 2 } else { channels })
+    }
+
+    // RFC 6381: mp4a.40.{object type}, HE-AAC 為 5, HE-AACv2 為 29
+    pub fn codecs(&self) -> String {
+        let object_type = match (self.sbr, self.ps) {
+            (true, true) => 29,
+            (true, false) => 5,
+            _ => self.signaled_object_type,
+        };
+        format!("mp4a.40.{}", object_type)
     }
 }
 
@@ -43,7 +199,7 @@ impl Adts {
     // Id               	                u1      0為MPEG-4, 1為MPEG-2
     // Layer 	                            u2      固定爲00
     // Protection Absent 	                u1      固定爲1
-    // Profile 	                            u2      值: 0~3, 1為aac
+    // Profile 	                            u2      值: 0~3, object type - 1, 1為aac-lc
     // Sampling Frequency Index 	        u4      表示採樣率, 0: 96000 Hz, 1: 88200 Hz, 2: 64000 Hz, 3：48000 Hz, 4: 44100 Hz, 5: 32000 Hz, 6: 24000 Hz, 7: 22050 Hz, 8: 16000 Hz, 9: 12000 Hz, 10: 11025 Hz, 11: 8000 Hz, 12: 7350 Hz
     // Private Bit 	                        u1      固定爲0
     // Channel Configuration 	            u3      值: 0~7, 1: 1 channel: front-center, 2: 2 channels: front-left, front-right, 3: 3 channels: front-center, front-left, front-right, 4: 4 channels: front-center, front-left, front-right, back-center
@@ -59,8 +215,9 @@ impl Adts {
 
         es.extend(Adts::SYNCWORD);
 
-        let profile = 0x40;
-        let sampling_frequency_index = adts_config.sampling_frequency_index << 2;
+        // SBR/PS 以核心的 profile 與取樣率表示, 由解碼器自行偵測
+        let profile = ((adts_config.object_type - 1) & 0x03) << 6;
+        let sampling_frequency_index = adts_config.adts_frequency_index().unwrap_or(adts_config.sampling_frequency_index) << 2;
         let channel_configuration0 = (adts_config.channel_configuration & 0x07) >> 2;
         es.push(profile | sampling_frequency_index | channel_configuration0);
 
@@ -69,7 +226,7 @@ impl Adts {
         let frame_length0 = ((frame_length & 0x1FFF) >> 11) as u8;
         es.push(channel_configuration1 | frame_length0);
 
-        let frame_length1 = ((frame_length & 0x7FF) << 5) as u16;
+        let frame_length1 = (frame_length & 0x7FF) << 5;
         let frame_length2 = frame_length1 | 0b0000_0000_0001_1111;
         es.extend(&[(frame_length2 >> 8) as u8, (frame_length2 & 0xff) as u8]);
 
@@ -79,3 +236,93 @@ impl Adts {
         es
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(data: &[u8]) -> AdtsConfig {
+        let mut config = AdtsConfig::new();
+        config.set(Bytes::copy_from_slice(data)).unwrap();
+        config
+    }
+
+    #[test]
+    fn reads_aac_lc() {
+        let config = read(&[0x12, 0x10]);
+        assert_eq!((config.object_type, config.sample_rate, config.channel_configuration), (2, 44100, 2));
+        assert!(!config.sbr && !config.ps && config.is_adts());
+        assert_eq!((config.channels(), config.codecs()), (Some(2), String::from("mp4a.40.2")));
+        assert_eq!(Adts::to_es_layer(&config, vec![0; 10])[..7], [0xff, 0xf1, 0x50, 0x80, 0x02, 0x3f, 0xfc]);
+    }
+
+    #[test]
+    fn reads_explicit_sbr_and_ps() {
+        // HE-AAC: 24 kHz 核心, SBR 後 48 kHz
+        let config = read(&[0x2b, 0x11, 0x88]);
+        assert_eq!((config.signaled_object_type, config.object_type), (5, 2));
+        assert_eq!((config.sample_rate, config.output_sample_rate), (24000, 48000));
+        assert!(config.sbr && !config.ps && config.is_adts());
+        assert_eq!(config.codecs(), "mp4a.40.5");
+
+        // HE-AACv2: 單聲道核心, PS 還原為立體聲
+        let config = read(&[0xeb, 0x09, 0x88]);
+        assert_eq!((config.signaled_object_type, config.object_type, config.output_sample_rate), (29, 2, 48000));
+        assert!(config.sbr && config.ps);
+        assert_eq!((config.channels(), config.codecs()), (Some(2), String::from("mp4a.40.29")));
+    }
+
+    #[test]
+    fn reads_backward_compatible_signaling() {
+        let config = read(&[0x13, 0x10, 0x56, 0xe5, 0x98]);
+        assert_eq!((config.signaled_object_type, config.sample_rate, config.output_sample_rate), (2, 24000, 48000));
+        assert!(config.sbr && !config.ps);
+        assert_eq!(config.codecs(), "mp4a.40.5");
+
+        let config = read(&[0x13, 0x08, 0x56, 0xe5, 0x9d, 0x48, 0x80]);
+        assert!(config.sbr && config.ps);
+        assert_eq!((config.channels(), config.codecs()), (Some(2), String::from("mp4a.40.29")));
+
+        // sync extension 不完整時視為沒有 SBR
+        let config = read(&[0x13, 0x10, 0x56]);
+        assert!(!config.sbr);
+        assert_eq!(config.codecs(), "mp4a.40.2");
+    }
+
+    #[test]
+    fn reads_explicit_frequency() {
+        // 與表中相同的取樣率仍可使用 ADTS
+        let config = read(&[0x17, 0x80, 0x56, 0x22, 0x10]);
+        assert_eq!((config.sampling_frequency_index, config.sample_rate, config.channel_configuration), (15, 44100, 2));
+        assert!(config.is_adts());
+        assert_eq!(Adts::to_es_layer(&config, vec![0; 10])[2], 0x50);
+
+        let config = read(&[0x17, 0x80, 0x61, 0xa8, 0x10]);
+        assert_eq!(config.sample_rate, 50000);
+        assert!(!config.is_adts());
+        // 取樣率 0
+        assert!(AdtsConfig::new().set(Bytes::from_static(&[0x17, 0x80, 0x00, 0x00, 0x10])).is_err());
+    }
+
+    #[test]
+    fn falls_back_to_loas() {
+        // channel_configuration 0 由 program config element 決定, ADTS 無法表示
+        let config = read(&[0x12, 0x00]);
+        assert_eq!((config.channel_configuration, config.channels()), (0, None));
+        assert!(!config.is_adts());
+        // object type 31 之後的 USAC (42)
+        let config = read(&[0xf9, 0x48, 0x40]);
+        assert_eq!(config.object_type, 42);
+        assert!(!config.is_adts());
+    }
+
+    #[test]
+    fn rejects_invalid_config() {
+        let mut config = AdtsConfig::new();
+        assert!(config.set(Bytes::new()).is_err());
+        assert!(config.set(Bytes::from_static(&[0x12])).is_err());
+        assert!(config.set(Bytes::from_static(&[0x00, 0x10])).is_err());
+        // sampling frequency index 13 保留
+        assert!(config.set(Bytes::from_static(&[0x16, 0x90])).is_err());
+    }
+}
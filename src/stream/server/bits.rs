@@ -0,0 +1,166 @@
+// Exp-Golomb / 位元讀取器, 用於解析 SPS 等以位元為單位的結構
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader { data, position: 0 }
+    }
+
+    pub fn read_bit(&mut self) -> Option<u8> {
+        let byte = *self.data.get(self.position / 8)?;
+        let bit = (byte >> (7 - self.position % 8)) & 1;
+        self.position += 1;
+        Some(bit)
+    }
+
+    pub fn read_bits(&mut self, count: u8) -> Option<u32> {
+        let mut value = 0u32;
+        for _ in 0..count {
+            value = (value << 1) | self.read_bit()? as u32;
+        }
+        Some(value)
+    }
+
+    pub fn read_flag(&mut self) -> Option<bool> {
+        Some(self.read_bit()? == 1)
+    }
+
+    pub fn remaining(&self) -> usize {
+        (self.data.len() * 8).saturating_sub(self.position)
+    }
+
+    pub fn skip_bits(&mut self, count: usize) -> Option<()> {
+        if self.position + count > self.data.len() * 8 {
+            return None;
+        }
+        self.position += count;
+        Some(())
+    }
+
+    // ue(v): 前導零個數 n, 接著讀 n 個位元, 值為 2^n - 1 + bits
+    pub fn read_ue(&mut self) -> Option<u32> {
+        let mut leading_zeros = 0;
+        while self.read_bit()? == 0 {
+            leading_zeros += 1;
+            if leading_zeros > 31 {
+                return None;
+            }
+        }
+        if leading_zeros == 0 {
+            return Some(0);
+        }
+        let bits = self.read_bits(leading_zeros)? as u64;
+        Some(((1u64 << leading_zeros) - 1 + bits) as u32)
+    }
+
+    // se(v): 1 -> 1, 2 -> -1, 3 -> 2, 4 -> -2 ...
+    pub fn read_se(&mut self) -> Option<i32> {
+        let value = self.read_ue()? as i64;
+        if value % 2 == 0 {
+            Some((-(value / 2)) as i32)
+        } else {
+            Some(((value + 1) / 2) as i32)
+        }
+    }
+}
+
+// 位元寫入器, 用於組成 LATM 等以位元為單位的結構, 最後不足一個 byte 的部分補零
+pub struct BitWriter {
+    data: Vec<u8>,
+    position: usize,
+}
+
+impl BitWriter {
+    pub fn new() -> BitWriter {
+        BitWriter { data: Vec::new(), position: 0 }
+    }
+
+    pub fn write_bits(&mut self, count: u8, value: u32) {
+        for i in (0..count).rev() {
+            if self.position == self.data.len() * 8 {
+                self.data.push(0);
+            }
+            let bit = ((value >> i) & 1) as u8;
+            *self.data.last_mut().unwrap() |= bit << (7 - self.position % 8);
+            self.position += 1;
+        }
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.write_bits(8, byte as u32);
+        }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+// 去除 emulation prevention byte: 0x00 0x00 0x03 -> 0x00 0x00
+pub fn to_rbsp(data: &[u8]) -> Vec<u8> {
+    let mut rbsp = Vec::with_capacity(data.len());
+    let mut zeros = 0;
+    for &byte in data {
+        if zeros >= 2 && byte == 0x03 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        rbsp.push(byte);
+    }
+    rbsp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_exp_golomb() {
+        // 1 | 010 | 011 | 00100 | 00111 | 0001000
+        let mut writer = BitWriter::new();
+        for (count, value) in [(1, 0b1), (3, 0b010), (3, 0b011), (5, 0b00100), (5, 0b00111), (7, 0b0001000)] {
+            writer.write_bits(count, value);
+        }
+        let data = writer.into_bytes();
+        let mut reader = BitReader::new(&data);
+        let values: Vec<u32> = (0..6).map(|_| reader.read_ue().unwrap()).collect();
+        assert_eq!(values, vec![0, 1, 2, 3, 6, 7]);
+    }
+
+    #[test]
+    fn reads_signed_exp_golomb() {
+        // ue 1, 2, 3, 4 -> se 1, -1, 2, -2
+        let data = [0b0100_1100, 0b1000_0101, 0b0000_0000];
+        let mut reader = BitReader::new(&data);
+        let values: Vec<i32> = (0..4).map(|_| reader.read_se().unwrap()).collect();
+        assert_eq!(values, vec![1, -1, 2, -2]);
+    }
+
+    #[test]
+    fn rejects_long_or_truncated_codes() {
+        // 31 個前導零為 u32 可表示的最大值
+        let mut writer = BitWriter::new();
+        writer.write_bits(31, 0);
+        writer.write_bits(1, 1);
+        writer.write_bits(31, 0x7fff_ffff);
+        let data = writer.into_bytes();
+        assert_eq!(BitReader::new(&data).read_ue(), Some(u32::MAX - 1));
+
+        assert_eq!(BitReader::new(&[0, 0, 0, 0, 0x80]).read_ue(), None);
+        assert_eq!(BitReader::new(&[0x00, 0x01]).read_ue(), None);
+        assert_eq!(BitReader::new(&[]).read_bit(), None);
+    }
+
+    #[test]
+    fn removes_emulation_prevention_bytes() {
+        assert_eq!(to_rbsp(&[0x00, 0x00, 0x03, 0x01]), vec![0x00, 0x00, 0x01]);
+        assert_eq!(to_rbsp(&[0x00, 0x00, 0x03, 0x00, 0x00, 0x03, 0x00]), vec![0x00, 0x00, 0x00, 0x00, 0x00]);
+        // 0x03 之前不足兩個 0x00 時保留
+        assert_eq!(to_rbsp(&[0x00, 0x03, 0x00, 0x03]), vec![0x00, 0x03, 0x00, 0x03]);
+    }
+}
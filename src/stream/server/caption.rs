@@ -0,0 +1,757 @@
+use std::collections::BTreeMap;
+use std::mem;
+use super::bits;
+use super::flv::VideoCodec;
+use super::nalu::Nalu;
+use super::ts::TransportStream;
+
+// CEA-608/708 字幕, 放在 SEI 的 user_data_registered_itu_t_t35 (payload type 4, ATSC A/53)
+// -------------| ------------- | ----
+// Country Code | u8            | 0xB5 (美國)
+// Provider     | u16           | 0x0031 (ATSC)
+// User Id      | u32           | "GA94"
+// Type Code    | u8            | 0x03 (cc_data)
+// Flags        | u8            | process_em_data(1) process_cc_data(1) additional_data(1) cc_count(5)
+// Em Data      | u8            |
+// cc_data      | [u8; 3] * n   | marker(5) cc_valid(1) cc_type(2) | cc_data_1 | cc_data_2
+// cc_type 0, 1 為 608 的 field 1, 2; 2, 3 為 708 (DTVCC) 封包的延續與開頭
+pub struct Captions {
+    cea608: Cea608,
+    cea708: Cea708,
+    // 同時有 608 與 708 時使用先出現文字的
+    source: Option<Source>,
+    // 依 pts 排序, 有 B 幀時解碼順序與顯示順序不同
+    pending: Vec<(u64, Vec<[u8; 3]>)>,
+    cues: Vec<Cue>,
+    // 目前顯示中的字幕 (開始時間, 內容)
+    current: Option<(u64, String)>,
+    segment_start: u64,
+    last_timestamp: u64,
+    pub detected: bool,
+}
+
+#[derive(Clone, Copy)]
+enum Source {
+    Cea608,
+    Cea708,
+}
+
+struct Cue {
+    start: u64,
+    end: u64,
+    text: String,
+}
+
+impl Captions {
+    const T35_HEADER: &'static [u8] = &[0xb5, 0x00, 0x31, b'G', b'A', b'9', b'4', 0x03];
+
+    pub fn new() -> Captions {
+        Captions {
+            cea608: Cea608::new(),
+            cea708: Cea708::new(),
+            source: None,
+            pending: Vec::new(),
+            cues: Vec::new(),
+            current: None,
+            segment_start: 0,
+            last_timestamp: 0,
+            detected: false,
+        }
+    }
+
+    // 取出一個 access unit 中所有 SEI 的 cc_data, h.265 只有 prefix SEI 會帶字幕
+    pub fn read_cc_data(nal_units: &[Nalu], codec: VideoCodec) -> Vec<[u8; 3]> {
+        let sei_type = match codec {
+            VideoCodec::Avc => 6,
+            VideoCodec::Hevc => 39,
+        };
+        let mut cc_data = Vec::new();
+        for nalu in nal_units.iter().filter(|nalu| nalu.unit_type == sei_type) {
+            Captions::read_sei(&bits::to_rbsp(&nalu.data), &mut cc_data);
+        }
+        cc_data
+    }
+
+    // 一個 SEI nalu 可以有多個 message, 最後一個 byte 為 rbsp trailing bits
+    fn read_sei(mut data: &[u8], cc_data: &mut Vec<[u8; 3]>) -> Option<()> {
+        while data.len() > 1 {
+            let payload_type = Captions::read_sei_value(&mut data)?;
+            let payload_size = Captions::read_sei_value(&mut data)?;
+            if payload_size > data.len() {
+                return None;
+            }
+            let (payload, rest) = data.split_at(payload_size);
+            if payload_type == 4 {
+                Captions::read_t35(payload, cc_data);
+            }
+            data = rest;
+        }
+        Some(())
+    }
+
+    // payload type 與 size 以 0xFF 延續
+    fn read_sei_value(data: &mut &[u8]) -> Option<usize> {
+        let mut value = 0;
+        loop {
+            let (&byte, rest) = data.split_first()?;
+            *data = rest;
+            value += byte as usize;
+            if byte != 0xff {
+                return Some(value);
+            }
+        }
+    }
+
+    fn read_t35(payload: &[u8], cc_data: &mut Vec<[u8; 3]>) {
+        if payload.len() < 10 || !payload.starts_with(Captions::T35_HEADER) || payload[8] & 0x40 == 0 {
+            return;
+        }
+        let count = (payload[8] & 0x1f) as usize;
+        for data in payload[10..].chunks_exact(3).take(count) {
+            if data[0] & 0x04 != 0 {
+                cc_data.push([data[0] & 0x03, data[1], data[2]]);
+            }
+        }
+    }
+
+    // 每個影像幀都要呼叫, pts 不大於目前 dts 的資料之後不會再有更早的, 依 pts 順序解碼
+    pub fn push(&mut self, dts: u64, pts: u64, cc_data: Vec<[u8; 3]>) {
+        if !cc_data.is_empty() {
+            let index = self.pending.partition_point(|(timestamp, _)| *timestamp <= pts);
+            self.pending.insert(index, (pts, cc_data));
+        }
+        self.last_timestamp = self.last_timestamp.max(dts);
+
+        let count = self.pending.partition_point(|(timestamp, _)| *timestamp <= dts);
+        let pending: Vec<_> = self.pending.drain(..count).collect();
+        for (timestamp, cc_data) in pending {
+            self.decode(timestamp, cc_data);
+        }
+    }
+
+    fn decode(&mut self, timestamp: u64, cc_data: Vec<[u8; 3]>) {
+        for [cc_type, data1, data2] in cc_data {
+            match cc_type {
+                0 => self.cea608.decode(data1, data2),
+                // field 2 為 CC3, CC4, 不處理
+                1 => continue,
+                _ => self.cea708.push(cc_type == 3, data1, data2),
+            }
+            self.update(timestamp);
+        }
+    }
+
+    // 顯示的文字改變時結束目前的字幕
+    fn update(&mut self, timestamp: u64) {
+        let text = match self.source {
+            Some(Source::Cea608) => self.cea608.text(),
+            Some(Source::Cea708) => self.cea708.text(),
+            None => {
+                let (cea608, cea708) = (self.cea608.text(), self.cea708.text());
+                if !cea608.is_empty() {
+                    self.source = Some(Source::Cea608);
+                    cea608
+                } else {
+                    if !cea708.is_empty() {
+                        self.source = Some(Source::Cea708);
+                    }
+                    cea708
+                }
+            }
+        };
+        if self.current.as_ref().map_or("", |(_, current)| current.as_str()) == text {
+            return;
+        }
+
+        if let Some((start, text)) = self.current.take() {
+            self.cues.push(Cue { start, end: timestamp, text });
+        }
+        if !text.is_empty() {
+            self.detected = true;
+            self.current = Some((timestamp, text));
+        }
+    }
+
+    // 上一個切點到 end 之間的 WebVTT, 跨過切點的字幕在兩邊各寫一次
+    // 時間與 ts 相同為串流時間(ms), 以 X-TIMESTAMP-MAP 對應到 pts
+    pub fn segment(&mut self, end: u64) -> String {
+        let start = mem::replace(&mut self.segment_start, end);
+        let mut vtt = format!("WEBVTT\nX-TIMESTAMP-MAP=MPEGTS:{},LOCAL:{}\n", TransportStream::clock(start), Captions::time(start));
+
+        let current = self.current.as_ref().map(|(timestamp, text)| Cue { start: *timestamp, end, text: text.clone() });
+        for cue in self.cues.iter().chain(current.iter()) {
+            let (cue_start, cue_end) = (cue.start.max(start), cue.end.min(end));
+            if cue_start < cue_end {
+                vtt = format!("{}\n{} --> {}\n{}\n", vtt, Captions::time(cue_start), Captions::time(cue_end), Captions::escape(&cue.text));
+            }
+        }
+        self.cues.retain(|cue| cue.end > end);
+        vtt
+    }
+
+    // 串流結束, 剩下的資料全部解碼
+    pub fn last_segment(&mut self) -> String {
+        let pending = mem::take(&mut self.pending);
+        for (timestamp, cc_data) in pending {
+            self.last_timestamp = self.last_timestamp.max(timestamp);
+            self.decode(timestamp, cc_data);
+        }
+        self.segment(self.last_timestamp.max(self.segment_start))
+    }
+
+    fn time(timestamp: u64) -> String {
+        format!("{:02}:{:02}:{:02}.{:03}", timestamp / 3_600_000, timestamp / 60_000 % 60, timestamp / 1000 % 60, timestamp % 1000)
+    }
+
+    fn escape(text: &str) -> String {
+        text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    PopOn,
+    RollUp(u8),
+    PaintOn,
+}
+
+// CEA-608 CC1, 每組兩個 byte, 最高位元為 parity
+// 0x10~0x1F 開頭為控制碼 (0x18 以上為 CC2), 其餘為字元
+// pop-on 寫入不顯示的記憶體, 收到 EOC 時交換; roll-up 與 paint-on 直接寫入顯示中的記憶體
+struct Cea608 {
+    // 收到 TR/RTD (text mode) 或還沒收到模式時為 None, 不處理文字
+    mode: Option<Mode>,
+    // 列號 1~15 -> 內容
+    displayed: BTreeMap<u8, String>,
+    non_displayed: BTreeMap<u8, String>,
+    row: u8,
+    // 目前的資料屬於 CC1
+    channel1: bool,
+    // 控制碼會重複傳送兩次, 第二次忽略
+    last_control: Option<(u8, u8)>,
+}
+
+impl Cea608 {
+    const BOTTOM_ROW: u8 = 15;
+    // preamble address code 第一個 byte 的低 3 位元對應的列
+    const ROWS: [u8; 8] = [11, 1, 3, 12, 14, 5, 7, 9];
+    const SPECIAL: &'static str = "®°½¿™¢£♪à èâêîôû";
+    const EXTENDED: [&'static str; 2] = ["ÁÉÓÚÜü‘¡*’—©℠•“”ÀÂÇÈÊËëÎÏïÔÙùÛ«»", "ÃãÍÌìÒòÕõ{}\\^_|~ÄäÖöß¥¤│ÅåØø┌┐└┘"];
+
+    fn new() -> Cea608 {
+        Cea608 {
+            mode: None,
+            displayed: BTreeMap::new(),
+            non_displayed: BTreeMap::new(),
+            row: Cea608::BOTTOM_ROW,
+            channel1: true,
+            last_control: None,
+        }
+    }
+
+    fn decode(&mut self, data1: u8, data2: u8) {
+        let (data1, data2) = (data1 & 0x7f, data2 & 0x7f);
+        if (0x10..=0x1f).contains(&data1) {
+            let control = Some((data1, data2));
+            if self.last_control == control {
+                self.last_control = None;
+                return;
+            }
+            self.last_control = control;
+            self.channel1 = data1 < 0x18;
+            if self.channel1 {
+                self.control(data1, data2);
+            }
+            return;
+        }
+
+        self.last_control = None;
+        // 0x00 為填充, 0x01~0x0F 為 XDS
+        if !self.channel1 || data1 < 0x20 {
+            return;
+        }
+        self.write_char(Cea608::basic(data1));
+        if data2 >= 0x20 {
+            self.write_char(Cea608::basic(data2));
+        }
+    }
+
+    fn control(&mut self, data1: u8, data2: u8) {
+        match (data1, data2) {
+            (0x14, 0x20..=0x2f) => self.command(data2),
+            // mid-row code (顏色、樣式), 佔一個空白
+            (0x11, 0x20..=0x2f) => self.write_char(' '),
+            (0x11, 0x30..=0x3f) => self.write_char(Cea608::SPECIAL.chars().nth((data2 - 0x30) as usize).unwrap_or(' ')),
+            // 擴充字元會取代前一個字元 (不支援擴充字元的解碼器顯示的替代字元)
+            (0x12 | 0x13, 0x20..=0x3f) => {
+                self.backspace();
+                self.write_char(Cea608::EXTENDED[(data1 - 0x12) as usize].chars().nth((data2 - 0x20) as usize).unwrap_or(' '));
+            }
+            // tab offset
+            (0x17, 0x21..=0x23) => (0..data2 - 0x20).for_each(|_| self.write_char(' ')),
+            (_, 0x40..=0x7f) => self.preamble(data1, data2),
+            _ => (),
+        }
+    }
+
+    fn command(&mut self, command: u8) {
+        match command {
+            // RCL
+            0x20 => self.mode = Some(Mode::PopOn),
+            // BS
+            0x21 => self.backspace(),
+            // RU2, RU3, RU4
+            0x25..=0x27 => {
+                if !matches!(self.mode, Some(Mode::RollUp(_))) {
+                    self.displayed.clear();
+                    self.non_displayed.clear();
+                }
+                self.mode = Some(Mode::RollUp(command - 0x23));
+                self.row = Cea608::BOTTOM_ROW;
+            }
+            // RDC
+            0x29 => self.mode = Some(Mode::PaintOn),
+            // TR, RTD
+            0x2a | 0x2b => self.mode = None,
+            // EDM
+            0x2c => self.displayed.clear(),
+            // CR
+            0x2d => {
+                if let Some(Mode::RollUp(rows)) = self.mode {
+                    self.roll_up(rows);
+                }
+            }
+            // ENM
+            0x2e => self.non_displayed.clear(),
+            // EOC
+            0x2f => {
+                mem::swap(&mut self.displayed, &mut self.non_displayed);
+                self.mode = Some(Mode::PopOn);
+            }
+            _ => (),
+        }
+    }
+
+    // roll-up 固定寫在最下面一列, 不依 preamble 移動
+    fn preamble(&mut self, data1: u8, data2: u8) {
+        if matches!(self.mode, Some(Mode::RollUp(_))) {
+            return;
+        }
+        let row = Cea608::ROWS[(data1 & 0x07) as usize];
+        self.row = if data1 & 0x07 != 0 && data2 & 0x20 != 0 { row + 1 } else { row };
+        // 新的一列從頭開始
+        let row = self.row;
+        if let Some(memory) = self.memory() {
+            memory.remove(&row);
+        }
+    }
+
+    // 內容往上移一列, 只保留 rows 列
+    fn roll_up(&mut self, rows: u8) {
+        let mut displayed = BTreeMap::new();
+        for (row, text) in mem::take(&mut self.displayed) {
+            if row > 1 && row + rows > Cea608::BOTTOM_ROW + 1 {
+                displayed.insert(row - 1, text);
+            }
+        }
+        self.displayed = displayed;
+    }
+
+    fn memory(&mut self) -> Option<&mut BTreeMap<u8, String>> {
+        match self.mode? {
+            Mode::PopOn => Some(&mut self.non_displayed),
+            _ => Some(&mut self.displayed),
+        }
+    }
+
+    fn write_char(&mut self, c: char) {
+        let row = self.row;
+        if let Some(memory) = self.memory() {
+            memory.entry(row).or_default().push(c);
+        }
+    }
+
+    fn backspace(&mut self) {
+        let row = self.row;
+        if let Some(text) = self.memory().and_then(|memory| memory.get_mut(&row)) {
+            text.pop();
+        }
+    }
+
+    // 與 ASCII 不同的字元
+    fn basic(byte: u8) -> char {
+        match byte {
+            0x2a => 'á',
+            0x5c => 'é',
+            0x5e => 'í',
+            0x5f => 'ó',
+            0x60 => 'ú',
+            0x7b => 'ç',
+            0x7c => '÷',
+            0x7d => 'Ñ',
+            0x7e => 'ñ',
+            0x7f => '█',
+            _ => byte as char,
+        }
+    }
+
+    fn text(&self) -> String {
+        Cea608::join(self.displayed.values())
+    }
+
+    fn join<'a>(lines: impl Iterator<Item = &'a String>) -> String {
+        lines.map(|line| line.trim()).filter(|line| !line.is_empty()).collect::<Vec<_>>().join("\n")
+    }
+}
+
+#[derive(Default)]
+struct Window {
+    rows: Vec<String>,
+    visible: bool,
+}
+
+// CEA-708 (DTVCC) service 1
+// 封包: sequence(2) packet_size_code(6) | service block...
+// service block: service_number(3) block_size(5) | (service_number 為 7 時) extended_service_number(u8) | 內容
+// 內容: C0 (0x00~0x1F) G0 (0x20~0x7F, ASCII) C1 (0x80~0x9F, 視窗指令) G1 (0xA0~0xFF, Latin-1)
+// 只處理文字與視窗的顯示, 不處理位置與樣式
+struct Cea708 {
+    packet: Vec<u8>,
+    windows: [Window; 8],
+    current: usize,
+}
+
+impl Cea708 {
+    fn new() -> Cea708 {
+        Cea708 {
+            packet: Vec::new(),
+            windows: Default::default(),
+            current: 0,
+        }
+    }
+
+    fn push(&mut self, start: bool, data1: u8, data2: u8) {
+        if start {
+            self.packet.clear();
+        } else if self.packet.is_empty() {
+            return;
+        }
+        self.packet.extend(&[data1, data2]);
+
+        // packet_size_code 為 0 時為 128 bytes
+        let size = match self.packet[0] & 0x3f {
+            0 => 128,
+            code => code as usize * 2,
+        };
+        if self.packet.len() >= size {
+            let packet = mem::take(&mut self.packet);
+            self.read_packet(&packet[1..size]);
+        }
+    }
+
+    fn read_packet(&mut self, mut data: &[u8]) {
+        while let Some((&header, rest)) = data.split_first() {
+            let (mut service, size) = (header >> 5, (header & 0x1f) as usize);
+            let mut rest = rest;
+            if service == 7 {
+                match rest.split_first() {
+                    Some((&extended, next)) => {
+                        service = extended & 0x3f;
+                        rest = next;
+                    }
+                    None => return,
+                }
+            }
+            // service 0 為之後都是填充
+            if service == 0 || size > rest.len() {
+                return;
+            }
+            let (block, next) = rest.split_at(size);
+            if service == 1 {
+                self.read_block(block);
+            }
+            data = next;
+        }
+    }
+
+    fn read_block(&mut self, block: &[u8]) {
+        let mut index = 0;
+        while index < block.len() {
+            let code = block[index];
+            let parameters = &block[index + 1..];
+            index += 1 + match code {
+                0x00..=0x1f => self.c0(code, parameters),
+                0x7f => {
+                    self.write_char('♪');
+                    0
+                }
+                0x20..=0x7e | 0xa0..=0xff => {
+                    self.write_char(code as char);
+                    0
+                }
+                0x80..=0x9f => self.c1(code, parameters),
+            };
+        }
+    }
+
+    // 回傳參數的長度
+    fn c0(&mut self, code: u8, parameters: &[u8]) -> usize {
+        match code {
+            // BS
+            0x08 => {
+                if let Some(row) = self.window().rows.last_mut() {
+                    row.pop();
+                }
+                0
+            }
+            // FF
+            0x0c => {
+                self.window().rows.clear();
+                0
+            }
+            // CR
+            0x0d => {
+                self.window().rows.push(String::new());
+                0
+            }
+            // HCR
+            0x0e => {
+                if let Some(row) = self.window().rows.last_mut() {
+                    row.clear();
+                }
+                0
+            }
+            // EXT1, 之後為 G2/G3 字元或 C2/C3 指令
+            0x10 => match parameters.first() {
+                Some(0x00..=0x07) => 1,
+                Some(0x08..=0x0f) => 2,
+                Some(0x10..=0x17) => 3,
+                Some(0x18..=0x1f) => 4,
+                Some(0x80..=0x87) => 5,
+                Some(0x88..=0x8f) => 6,
+                Some(0x90..=0x9f) => 2 + parameters.get(1).map_or(0, |length| (length & 0x3f) as usize),
+                Some(&code) => {
+                    if let Some(c) = Cea708::g2(code) {
+                        self.write_char(c);
+                    }
+                    1
+                }
+                None => 0,
+            },
+            0x11..=0x17 => 1,
+            0x18..=0x1f => 2,
+            _ => 0,
+        }
+    }
+
+    fn c1(&mut self, code: u8, parameters: &[u8]) -> usize {
+        match code {
+            // CW0~CW7
+            0x80..=0x87 => {
+                self.current = (code & 0x07) as usize;
+                0
+            }
+            // CLW, DSW, HDW, TGW, DLW, 參數為視窗的 bitmap
+            0x88..=0x8c => {
+                let windows = parameters.first().copied().unwrap_or(0);
+                for (id, window) in self.windows.iter_mut().enumerate() {
+                    if windows & (1 << id) == 0 {
+                        continue;
+                    }
+                    match code {
+                        0x88 => window.rows.clear(),
+                        0x89 => window.visible = true,
+                        0x8a => window.visible = false,
+                        0x8b => window.visible = !window.visible,
+                        _ => *window = Window::default(),
+                    }
+                }
+                1
+            }
+            // DLY
+            0x8d => 1,
+            // RST
+            0x8f => {
+                self.windows = Default::default();
+                0
+            }
+            // SPA, SPC, SPL
+            0x90 | 0x92 => 2,
+            0x91 => 3,
+            // SWA
+            0x97 => 4,
+            // DF0~DF7, 第一個參數的 bit 5 為 visible
+            0x98..=0x9f => {
+                self.current = (code & 0x07) as usize;
+                self.windows[self.current].visible = parameters.first().is_some_and(|parameter| parameter & 0x20 != 0);
+                6
+            }
+            _ => 0,
+        }
+    }
+
+    fn g2(code: u8) -> Option<char> {
+        let c = match code {
+            0x20 | 0x21 => ' ',
+            0x25 => '…',
+            0x2a => 'Š',
+            0x2c => 'Œ',
+            0x30 => '█',
+            0x31 => '‘',
+            0x32 => '’',
+            0x33 => '“',
+            0x34 => '”',
+            0x35 => '•',
+            0x39 => '™',
+            0x3a => 'š',
+            0x3c => 'œ',
+            0x3d => '℠',
+            0x3f => 'Ÿ',
+            _ => return None,
+        };
+        Some(c)
+    }
+
+    fn window(&mut self) -> &mut Window {
+        &mut self.windows[self.current]
+    }
+
+    fn write_char(&mut self, c: char) {
+        let window = self.window();
+        if window.rows.is_empty() {
+            window.rows.push(String::new());
+        }
+        window.rows.last_mut().unwrap().push(c);
+    }
+
+    fn text(&self) -> String {
+        Cea608::join(self.windows.iter().filter(|window| window.visible).flat_map(|window| window.rows.iter()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    // cc_type 0 (608 field 1)
+    fn field1(pairs: &[(u8, u8)]) -> Vec<[u8; 3]> {
+        pairs.iter().map(|&(data1, data2)| [0, data1, data2]).collect()
+    }
+
+    // 控制碼都送兩次
+    fn twice(data1: u8, data2: u8) -> [(u8, u8); 2] {
+        [(data1, data2), (data1, data2)]
+    }
+
+    #[test]
+    fn reads_cc_data_from_sei() {
+        let mut sei = vec![0x06];
+        // user_data_unregistered (type 5), uuid 含 0x00 0x00 0x03 emulation prevention
+        sei.extend(&[0x05, 0x10, 0x00, 0x00, 0x03, 0x01]);
+        sei.extend(&[0x11; 13]);
+        // user_data_registered_itu_t_t35: 3 組 cc_data, 第二組 cc_valid 為 0
+        sei.extend(&[0x04, 0x13]);
+        sei.extend(Captions::T35_HEADER);
+        sei.extend(&[0x40 | 3, 0xff, 0xfc, 0x94, 0x20, 0xf8, 0x80, 0x80, 0xff, 0x02, 0x21]);
+        sei.push(0x80);
+        let nalu = Nalu::read_unit(Bytes::from(sei), VideoCodec::Avc).unwrap();
+
+        assert_eq!(Captions::read_cc_data(&[nalu], VideoCodec::Avc), vec![[0, 0x94, 0x20], [3, 0x02, 0x21]]);
+    }
+
+    #[test]
+    fn ignores_other_t35_payloads() {
+        let mut sei = vec![0x06, 0x04, 0x0d, 0xb5, 0x00, 0x2f, b'D', b'T', b'G', b'1', 0x03, 0x41, 0xff, 0xfc, 0x94, 0x20, 0x80];
+        let nalu = Nalu::read_unit(Bytes::from(sei.clone()), VideoCodec::Avc).unwrap();
+        assert!(Captions::read_cc_data(&[nalu], VideoCodec::Avc).is_empty());
+
+        // payload size 超過 nalu 長度
+        sei[2] = 0x40;
+        let nalu = Nalu::read_unit(Bytes::from(sei), VideoCodec::Avc).unwrap();
+        assert!(Captions::read_cc_data(&[nalu], VideoCodec::Avc).is_empty());
+    }
+
+    #[test]
+    fn decodes_pop_on() {
+        let mut cea608 = Cea608::new();
+        let mut pairs = vec![];
+        // RCL, PAC 第 15 列, "HI", EOC
+        pairs.extend(twice(0x14, 0x20));
+        pairs.extend(twice(0x14, 0x70));
+        pairs.push((b'H', b'I'));
+        // 擴充字元取代前一個字元
+        pairs.push((b'!', 0x00));
+        pairs.extend(twice(0x12, 0x27));
+        for &(data1, data2) in &pairs {
+            cea608.decode(data1, data2);
+        }
+        assert_eq!(cea608.text(), "");
+
+        cea608.decode(0x14, 0x2f);
+        cea608.decode(0x14, 0x2f);
+        assert_eq!(cea608.text(), "HI¡");
+    }
+
+    #[test]
+    fn decodes_roll_up() {
+        let mut cea608 = Cea608::new();
+        // RU2, 三行文字, 每行之前 CR, 只保留最後兩行
+        for &(data1, data2) in twice(0x14, 0x25).iter() {
+            cea608.decode(data1, data2);
+        }
+        for line in ["ONE", "TWO", "SIX"] {
+            cea608.decode(0x14, 0x2d);
+            cea608.decode(0x14, 0x2d);
+            let bytes = line.as_bytes();
+            cea608.decode(bytes[0], bytes[1]);
+            cea608.decode(bytes[2], 0x00);
+        }
+        assert_eq!(cea608.text(), "TWO\nSIX");
+
+        // BS, EDM
+        cea608.decode(0x14, 0x21);
+        assert_eq!(cea608.text(), "TWO\nSI");
+        cea608.decode(0x14, 0x2c);
+        assert_eq!(cea608.text(), "");
+    }
+
+    #[test]
+    fn ignores_cc2_and_parity() {
+        let mut cea608 = Cea608::new();
+        // 0x94 0x29 為 RDC 加上 parity; 0x1c 0x29 為 CC2 的 RDC, 之後的文字屬於 CC2
+        cea608.decode(0x94, 0x29);
+        cea608.decode(b'A' | 0x80, b'B');
+        cea608.decode(0x1c, 0x29);
+        cea608.decode(b'C', b'D');
+        assert_eq!(cea608.text(), "AB");
+    }
+
+    #[test]
+    fn writes_segmented_webvtt() {
+        let mut captions = Captions::new();
+        // 1000ms paint-on "A<B", 3000ms 清除
+        captions.push(1000, 1000, field1(&[(0x14, 0x29), (0x14, 0x29), (b'A', b'<'), (b'B', 0x00)]));
+        captions.push(3000, 3000, field1(&[(0x14, 0x2c), (0x14, 0x2c)]));
+        assert!(captions.detected);
+
+        // 跨過 2000ms 切點的字幕在兩個 segment 各寫一次
+        let first = captions.segment(2000);
+        assert_eq!(first, "WEBVTT\nX-TIMESTAMP-MAP=MPEGTS:0,LOCAL:00:00:00.000\n\n00:00:01.000 --> 00:00:02.000\nA&lt;B\n");
+        let second = captions.segment(4000);
+        assert_eq!(second, "WEBVTT\nX-TIMESTAMP-MAP=MPEGTS:180000,LOCAL:00:00:02.000\n\n00:00:02.000 --> 00:00:03.000\nA&lt;B\n");
+        assert_eq!(captions.segment(6000), "WEBVTT\nX-TIMESTAMP-MAP=MPEGTS:360000,LOCAL:00:00:04.000\n");
+    }
+
+    #[test]
+    fn orders_cc_data_by_pts() {
+        let mut captions = Captions::new();
+        // B 幀: 解碼順序的第二幀 (pts 1066) 要在第三幀 (pts 1033) 之後解碼
+        captions.push(1000, 1000, field1(&[(0x14, 0x29), (0x14, 0x29)]));
+        captions.push(1033, 1066, field1(&[(b'B', 0x00)]));
+        captions.push(1066, 1033, field1(&[(b'A', 0x00)]));
+        captions.push(1100, 1100, vec![]);
+        let vtt = captions.last_segment();
+        assert!(vtt.ends_with("00:00:01.033 --> 00:00:01.066\nA\n\n00:00:01.066 --> 00:00:01.100\nAB\n"), "{}", vtt);
+    }
+}
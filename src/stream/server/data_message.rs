@@ -0,0 +1,90 @@
+use rml_amf0::Amf0Value;
+use rml_rtmp::chunk_io::{ChunkDeserializer, ChunkSerializer};
+use rml_rtmp::messages::RtmpMessage;
+
+// rml_rtmp 的 ServerSession 只處理 @setDataFrame onMetaData, 其他 data message (onTextData 等) 不會產生事件
+// 推流端送來的 chunk 只在這裡解析一次: AMF0 data message 交給 server, 所有 message 依序重新序列化後交給 ServerSession
+// 重新序列化時每個 message 連續送出且 chunk size 固定為預設值, 推流端的 SetChunkSize 只套用在這裡
+pub struct DataMessages {
+    deserializer: ChunkDeserializer,
+    serializer: ChunkSerializer,
+}
+
+// @setDataFrame 包裝的 message 以內層的名稱為準
+pub struct DataMessage {
+    pub name: String,
+    pub values: Vec<Amf0Value>,
+}
+
+// 依收到的順序處理
+pub enum Input {
+    // 交給 ServerSession 的 chunk
+    Session(Vec<u8>),
+    Data(DataMessage),
+}
+
+impl DataMessages {
+    const SET_CHUNK_SIZE: u8 = 1;
+    const AMF0_DATA: u8 = 18;
+
+    pub fn new() -> DataMessages {
+        DataMessages {
+            deserializer: ChunkDeserializer::new(),
+            serializer: ChunkSerializer::new(),
+        }
+    }
+
+    pub fn read(&mut self, mut bytes: &[u8]) -> Result<Vec<Input>, String> {
+        let mut inputs = Vec::new();
+        while let Some(payload) = self.deserializer.get_next_message(bytes).map_err(|error| error.to_string())? {
+            bytes = &[];
+            match (payload.type_id, payload.to_rtmp_message()) {
+                (DataMessages::SET_CHUNK_SIZE, Ok(RtmpMessage::SetChunkSize { size })) => {
+                    self.deserializer.set_max_chunk_size(size as usize).map_err(|error| error.to_string())?;
+                    continue;
+                }
+                (DataMessages::AMF0_DATA, Ok(RtmpMessage::Amf0Data { values })) => {
+                    if let Some(message) = DataMessages::data_message(values) {
+                        inputs.push(Input::Data(message));
+                    }
+                }
+                _ => (),
+            }
+            let packet = self.serializer.serialize(&payload, false, false).map_err(|error| error.to_string())?;
+            inputs.push(Input::Session(packet.bytes));
+        }
+        Ok(inputs)
+    }
+
+    fn data_message(mut values: Vec<Amf0Value>) -> Option<DataMessage> {
+        if values.first() == Some(&Amf0Value::Utf8String(String::from("@setDataFrame"))) {
+            values.remove(0);
+        }
+        if let Some(Amf0Value::Utf8String(name)) = values.first().cloned() {
+            values.remove(0);
+            return Some(DataMessage { name, values });
+        }
+        None
+    }
+
+    // 物件的屬性轉成字串, 用於 timed metadata
+    pub fn fields(value: Amf0Value) -> Vec<(String, String)> {
+        let mut fields: Vec<(String, String)> = value
+            .get_object_properties()
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|(name, value)| DataMessages::to_string(value).map(|value| (name, value)))
+            .collect();
+        fields.sort();
+        fields
+    }
+
+    pub fn to_string(value: Amf0Value) -> Option<String> {
+        match value {
+            Amf0Value::Utf8String(value) => Some(value),
+            Amf0Value::Number(value) => Some(value.to_string()),
+            Amf0Value::Boolean(value) => Some(value.to_string()),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,28 @@
+use std::fmt;
+use bytes::Buf;
+
+// 推流端送來的封包解析失敗, 由 server 計數後丟棄, 不中斷連線
+#[derive(Debug)]
+pub enum ParseError {
+    // 資料長度不足, 內容為解析中的結構
+    Truncated(&'static str),
+    // 內容不合法
+    Invalid(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::Truncated(what) => write!(f, "{}: unexpected end of data", what),
+            ParseError::Invalid(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+// bytes::Buf 的 get_* 與 advance 在長度不足時會 panic, 讀取前先確認
+pub fn ensure<B: Buf>(data: &B, length: usize, what: &'static str) -> Result<(), ParseError> {
+    if data.remaining() < length {
+        return Err(ParseError::Truncated(what));
+    }
+    Ok(())
+}
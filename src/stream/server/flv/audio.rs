@@ -1,21 +1,93 @@
 use bytes::{Bytes, Buf};
+use super::super::error::{self, ParseError};
 
-// Sound Format         | u4    10 = AAC
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AudioCodec {
+    Aac,
+    Mp3,
+    Opus,
+}
+
+// Sound Format         | u4    2 = MP3, 10 = AAC, 14 = MP3 8kHz, 9 = Enhanced RTMP
 // Sound Rate           | u2    AAC: always 3
 // Sound Size           | u1
 // Sound Type           | u1    AAC: always 1
-// AAC Packet Type      | u8    0 = sequence header
+// AAC Packet Type      | u8    0 = sequence header, 只有 AAC 有
 // Data                 | [u8]
+//
+// Enhanced RTMP (https://github.com/veovera/enhanced-rtmp)
+// Sound Format         | u4    9
+// Packet Type          | u4    0 = sequence start, 1 = coded frames, 2 = sequence end
+// FourCC               | u32   Opus, .mp3, mp4a
+// Data                 | [u8]  Opus 的 sequence start 為 OpusHead
 pub struct FlvAudio {
+    pub codec: Option<AudioCodec>,
     pub is_sequence_header: bool,
+    // 序列結束等不含音訊的封包為 false
+    pub is_frame: bool,
     pub data: Bytes,
 }
 
 impl FlvAudio {
-    pub fn read(mut data: Bytes) -> FlvAudio {
-        let header = data.get_u16();
-        let is_sequence_header = (header & 0xff) == 0;
+    pub fn read(mut data: Bytes) -> Result<FlvAudio, ParseError> {
+        error::ensure(&data, 1, "flv audio")?;
+        let byte0 = data.get_u8();
+        let (codec, packet_type) = match byte0 >> 4 {
+            9 => {
+                error::ensure(&data, 4, "flv audio")?;
+                let codec = match &data.get_u32().to_be_bytes() {
+                    b"mp4a" => Some(AudioCodec::Aac),
+                    b".mp3" => Some(AudioCodec::Mp3),
+                    b"Opus" => Some(AudioCodec::Opus),
+                    _ => None,
+                };
+                (codec, byte0 & 0x0f)
+            }
+            10 => {
+                error::ensure(&data, 1, "flv audio")?;
+                (Some(AudioCodec::Aac), data.get_u8())
+            }
+            2 | 14 => (Some(AudioCodec::Mp3), 1),
+            _ => (None, 1),
+        };
+
+        Ok(FlvAudio {
+            codec,
+            is_sequence_header: packet_type == 0,
+            is_frame: packet_type == 1,
+            data,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(data: &'static [u8]) -> Result<FlvAudio, ParseError> {
+        FlvAudio::read(Bytes::from_static(data))
+    }
+
+    #[test]
+    fn reads_audio_tags() {
+        let audio = read(&[0xaf, 0x00, 0x12, 0x10]).unwrap();
+        assert_eq!(audio.codec, Some(AudioCodec::Aac));
+        assert!(audio.is_sequence_header && !audio.is_frame);
+        assert_eq!(&audio.data[..], &[0x12, 0x10]);
+        let audio = read(&[0x2f, 0xff, 0xfb]).unwrap();
+        assert_eq!(audio.codec, Some(AudioCodec::Mp3));
+        assert!(audio.is_frame);
+        let audio = read(&[0x90, b'O', b'p', b'u', b's', b'O']).unwrap();
+        assert_eq!(audio.codec, Some(AudioCodec::Opus));
+        assert!(audio.is_sequence_header);
+        assert_eq!(read(&[0x91, b'f', b'L', b'a', b'C']).unwrap().codec, None);
+        assert_eq!(read(&[0x7f]).unwrap().codec, None);
+    }
 
-        FlvAudio { is_sequence_header, data }
+    #[test]
+    fn rejects_truncated_tags() {
+        assert!(read(&[]).is_err());
+        assert!(read(&[0xaf]).is_err());
+        assert!(read(&[0x91, b'O', b'p', b'u']).is_err());
     }
 }
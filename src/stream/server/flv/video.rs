@@ -1,34 +1,126 @@
 use bytes::{Bytes, Buf};
+use super::super::error::{self, ParseError};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum VideoCodec {
+    Avc,
+    Hevc,
+}
 
 // FLV Data - Normal
 // Field                | Type
 // -------------------- | ---
 // Frame Type           | u4
-// Codec ID             | u4
-// AVC Packet Type      | u8
+// Codec ID             | u4    7 = AVC, 12 = HEVC(非標準)
+// AVC Packet Type      | u8    0 = sequence header, 1 = nalu, 2 = end of sequence
 // Composition Time     | i24
 // Body                 | [u8]
+//
+// Enhanced RTMP (https://github.com/veovera/enhanced-rtmp)
+// Field                | Type
+// -------------------- | ---
+// Is Ex Header         | u1    1
+// Frame Type           | u3
+// Packet Type          | u4    0 = sequence start, 1 = coded frames, 2 = sequence end, 3 = coded frames x(無 composition time)
+// FourCC               | u32   hvc1, avc1
+// Composition Time     | i24   只有 coded frames 有
+// Body                 | [u8]
 pub struct FlvVideo {
+    pub codec: Option<VideoCodec>,
     pub is_keyframe: bool,
     pub is_sequence_header: bool,
+    // 序列結束、metadata 等不含影像的封包為 false
+    pub is_frame: bool,
     pub composition_time: u64,
     pub data: Bytes,
 }
 
 impl FlvVideo {
-    pub fn read(mut data: Bytes) -> FlvVideo {
+    const EX_HEADER: u8 = 0x80;
+    const KEYFRAME: u8 = 1;
+
+    pub fn read(mut data: Bytes) -> Result<FlvVideo, ParseError> {
+        error::ensure(&data, 1, "flv video")?;
         let byte0 = data.get_u8();
+        if byte0 & FlvVideo::EX_HEADER != 0 {
+            return FlvVideo::read_ex(byte0, data);
+        }
+        error::ensure(&data, 4, "flv video")?;
         let byte1 = data.get_u8();
 
-        let is_keyframe = (byte0 >> 4) == 1;
+        let codec = match byte0 & 0x0f {
+            7 => Some(VideoCodec::Avc),
+            12 => Some(VideoCodec::Hevc),
+            _ => None,
+        };
+        let is_keyframe = (byte0 >> 4) == FlvVideo::KEYFRAME;
         let is_sequence_header = byte1 == 0;
         let composition_time = data.get_uint(3);
 
-        FlvVideo {
+        Ok(FlvVideo {
+            codec,
             is_keyframe,
             is_sequence_header,
+            is_frame: byte1 == 1,
             composition_time,
             data,
-        }
+        })
+    }
+
+    fn read_ex(byte0: u8, mut data: Bytes) -> Result<FlvVideo, ParseError> {
+        let packet_type = byte0 & 0x0f;
+        error::ensure(&data, if packet_type == 1 { 7 } else { 4 }, "flv video")?;
+        let codec = match &data.get_u32().to_be_bytes() {
+            b"hvc1" => Some(VideoCodec::Hevc),
+            b"avc1" => Some(VideoCodec::Avc),
+            _ => None,
+        };
+        let composition_time = if packet_type == 1 { data.get_uint(3) } else { 0 };
+
+        Ok(FlvVideo {
+            codec,
+            is_keyframe: ((byte0 >> 4) & 0x07) == FlvVideo::KEYFRAME,
+            is_sequence_header: packet_type == 0,
+            is_frame: packet_type == 1 || packet_type == 3,
+            composition_time,
+            data,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(data: &'static [u8]) -> Result<FlvVideo, ParseError> {
+        FlvVideo::read(Bytes::from_static(data))
+    }
+
+    #[test]
+    fn reads_video_tags() {
+        let video = read(&[0x17, 0x00, 0x00, 0x00, 0x00, 0x01]).unwrap();
+        assert_eq!(video.codec, Some(VideoCodec::Avc));
+        assert!(video.is_keyframe && video.is_sequence_header && !video.is_frame);
+        let video = read(&[0x27, 0x01, 0x00, 0x00, 0x21, 0x65]).unwrap();
+        assert!(!video.is_keyframe && video.is_frame);
+        assert_eq!((video.composition_time, &video.data[..]), (0x21, &[0x65][..]));
+
+        let video = read(&[0x91, b'h', b'v', b'c', b'1', 0x00, 0x00, 0x42, 0x26]).unwrap();
+        assert_eq!((video.codec, video.composition_time), (Some(VideoCodec::Hevc), 0x42));
+        assert!(video.is_keyframe && video.is_frame);
+        let video = read(&[0xa3, b'a', b'v', b'c', b'1']).unwrap();
+        assert_eq!((video.codec, video.composition_time), (Some(VideoCodec::Avc), 0));
+        assert!(!video.is_keyframe && video.is_frame);
+        assert_eq!(read(&[0x90, b'a', b'v', b'0', b'1']).unwrap().codec, None);
+    }
+
+    #[test]
+    fn rejects_truncated_tags() {
+        assert!(read(&[]).is_err());
+        assert!(read(&[0x17]).is_err());
+        assert!(read(&[0x17, 0x01, 0x00, 0x00]).is_err());
+        assert!(read(&[0x90, b'h', b'v', b'c']).is_err());
+        // coded frames 需要 composition time
+        assert!(read(&[0x91, b'h', b'v', b'c', b'1', 0x00, 0x00]).is_err());
     }
 }
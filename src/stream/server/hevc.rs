@@ -0,0 +1,269 @@
+use std::convert::TryFrom;
+use super::bits::{self, BitReader};
+
+// H.265 profile_tier_level 的 general 部分, hvcC 與 SPS 中的格式相同
+// ---------------------------------| ----
+// General Profile Space            | u2
+// General Tier Flag                | u1
+// General Profile Idc              | u5
+// General Profile Compatibility    | u32
+// General Constraint Indicator     | u48
+// General Level Idc                | u8
+pub struct ProfileTierLevel {
+    pub profile_space: u8,
+    pub tier_flag: bool,
+    pub profile_idc: u8,
+    pub compatibility_flags: u32,
+    pub constraint_flags: [u8; 6],
+    pub level_idc: u8,
+}
+
+impl ProfileTierLevel {
+    pub fn read(reader: &mut BitReader) -> Option<ProfileTierLevel> {
+        let profile_space = reader.read_bits(2)? as u8;
+        let tier_flag = reader.read_flag()?;
+        let profile_idc = reader.read_bits(5)? as u8;
+        let compatibility_flags = reader.read_bits(32)?;
+        let mut constraint_flags = [0; 6];
+        for byte in constraint_flags.iter_mut() {
+            *byte = reader.read_bits(8)? as u8;
+        }
+        let level_idc = reader.read_bits(8)? as u8;
+
+        Some(ProfileTierLevel {
+            profile_space,
+            tier_flag,
+            profile_idc,
+            compatibility_flags,
+            constraint_flags,
+            level_idc,
+        })
+    }
+
+    // ISO/IEC 14496-15 E.3: hvc1.{profile space}{profile idc}.{compatibility 反轉位元}.{L|H}{level idc}.{constraint bytes}
+    pub fn codecs(&self) -> String {
+        let profile_space = match self.profile_space {
+            1 => "A",
+            2 => "B",
+            3 => "C",
+            _ => "",
+        };
+        let tier = if self.tier_flag { "H" } else { "L" };
+        let mut codecs = format!("hvc1.{}{}.{:x}.{}{}", profile_space, self.profile_idc, self.compatibility_flags.reverse_bits(), tier, self.level_idc);
+        // 結尾為零的 byte 省略
+        let length = self.constraint_flags.iter().rposition(|&byte| byte != 0).map_or(0, |i| i + 1);
+        for byte in &self.constraint_flags[..length] {
+            codecs = format!("{}.{:x}", codecs, byte);
+        }
+        codecs
+    }
+}
+
+// H.265 Sequence Parameter Set (nalu header 之後, 需先去除 emulation prevention byte)
+// ---------------------------------| ----
+// Sps Video Parameter Set Id       | u4
+// Sps Max Sub Layers Minus1        | u3
+// Sps Temporal Id Nesting Flag     | u1
+// Profile Tier Level               | general 96 bits + sub layers
+// Sps Seq Parameter Set Id         | ue
+// Chroma Format Idc                | ue    3: separate colour plane flag u1
+// Pic Width In Luma Samples        | ue
+// Pic Height In Luma Samples       | ue
+// Conformance Window Flag          | u1    left, right, top, bottom: ue
+// Bit Depth Luma Minus8            | ue
+// Bit Depth Chroma Minus8          | ue
+// ...
+pub struct HevcSps {
+    pub profile: ProfileTierLevel,
+    pub chroma_format_idc: u32,
+    pub bit_depth_luma: u32,
+    pub bit_depth_chroma: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl HevcSps {
+    pub fn read(data: &[u8]) -> Result<HevcSps, String> {
+        let rbsp = bits::to_rbsp(data);
+        HevcSps::read_rbsp(&rbsp).ok_or_else(|| String::from("hevc sps: unexpected end of data"))?
+    }
+
+    fn read_rbsp(data: &[u8]) -> Option<Result<HevcSps, String>> {
+        let mut reader = BitReader::new(data);
+
+        reader.skip_bits(4)?; // sps_video_parameter_set_id
+        let max_sub_layers_minus1 = reader.read_bits(3)? as usize;
+        reader.skip_bits(1)?; // sps_temporal_id_nesting_flag
+        let profile = ProfileTierLevel::read(&mut reader)?;
+        HevcSps::skip_sub_layers(&mut reader, max_sub_layers_minus1)?;
+
+        let id = reader.read_ue()?;
+        if id > 15 {
+            return Some(Err(format!("hevc sps: invalid sps_seq_parameter_set_id {}", id)));
+        }
+        let chroma_format_idc = reader.read_ue()?;
+        if chroma_format_idc > 3 {
+            return Some(Err(format!("hevc sps: invalid chroma_format_idc {}", chroma_format_idc)));
+        }
+        let separate_colour_plane = chroma_format_idc == 3 && reader.read_flag()?;
+
+        let width = reader.read_ue()? as u64;
+        let height = reader.read_ue()? as u64;
+        let (mut crop_left, mut crop_right, mut crop_top, mut crop_bottom) = (0u64, 0u64, 0u64, 0u64);
+        if reader.read_flag()? {
+            crop_left = reader.read_ue()? as u64;
+            crop_right = reader.read_ue()? as u64;
+            crop_top = reader.read_ue()? as u64;
+            crop_bottom = reader.read_ue()? as u64;
+        }
+        let bit_depth_luma = reader.read_ue()? as u64 + 8;
+        let bit_depth_chroma = reader.read_ue()? as u64 + 8;
+        if bit_depth_luma > 16 || bit_depth_chroma > 16 {
+            return Some(Err(format!("hevc sps: invalid bit depth {}/{}", bit_depth_luma, bit_depth_chroma)));
+        }
+
+        // 裁切單位為 SubWidthC, SubHeightC
+        let chroma_array_type = if separate_colour_plane { 0 } else { chroma_format_idc };
+        let (crop_unit_x, crop_unit_y) = match chroma_array_type {
+            1 => (2, 2),
+            2 => (2, 1),
+            _ => (1, 1),
+        };
+        let width = width.checked_sub(crop_unit_x * (crop_left + crop_right));
+        let height = height.checked_sub(crop_unit_y * (crop_top + crop_bottom));
+        let (width, height) = match (width.and_then(|w| u32::try_from(w).ok()), height.and_then(|h| u32::try_from(h).ok())) {
+            (Some(width), Some(height)) if width > 0 && height > 0 => (width, height),
+            _ => return Some(Err(String::from("hevc sps: invalid picture size or cropping"))),
+        };
+
+        Some(Ok(HevcSps {
+            profile,
+            chroma_format_idc,
+            bit_depth_luma: bit_depth_luma as u32,
+            bit_depth_chroma: bit_depth_chroma as u32,
+            width,
+            height,
+        }))
+    }
+
+    // sub layer 的 profile/level 不需要, 只計算長度
+    fn skip_sub_layers(reader: &mut BitReader, max_sub_layers_minus1: usize) -> Option<()> {
+        let mut present = Vec::with_capacity(max_sub_layers_minus1);
+        for _ in 0..max_sub_layers_minus1 {
+            let profile_present = reader.read_flag()?;
+            let level_present = reader.read_flag()?;
+            present.push((profile_present, level_present));
+        }
+        if max_sub_layers_minus1 > 0 {
+            reader.skip_bits(2 * (8 - max_sub_layers_minus1))?;
+        }
+        for (profile_present, level_present) in present {
+            if profile_present {
+                reader.skip_bits(88)?;
+            }
+            if level_present {
+                reader.skip_bits(8)?;
+            }
+        }
+        Some(())
+    }
+
+    pub fn summary(&self) -> String {
+        let chroma = match self.chroma_format_idc {
+            0 => "4:0:0",
+            1 => "4:2:0",
+            2 => "4:2:2",
+            _ => "4:4:4",
+        };
+        format!("{}x{} hevc {}, {} {}/{} bit", self.width, self.height, self.profile.codecs(), chroma, self.bit_depth_luma, self.bit_depth_chroma)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::bits::BitWriter;
+
+    // x265 輸出的 1080p Main profile SPS (含 nalu header 0x42 0x01)
+    const X265_1080P: &[u8] = &[
+        0x42, 0x01, 0x01, 0x01, 0x60, 0x00, 0x00, 0x03, 0x00, 0x90, 0x00, 0x00, 0x03, 0x00, 0x00, 0x03, 0x00, 0x7b, 0xa0, 0x03, 0xc0, 0x80, 0x10, 0xe5, 0x96, 0x56,
+        0x69, 0x24, 0xca, 0xf0, 0x10, 0x10, 0x00, 0x00, 0x03, 0x00, 0x10, 0x00, 0x00, 0x03, 0x01, 0xe0, 0x80,
+    ];
+
+    fn write_ue(writer: &mut BitWriter, value: u32) {
+        let value = value as u64 + 1;
+        let bits = 64 - value.leading_zeros() as u8;
+        writer.write_bits(bits - 1, 0);
+        writer.write_bits(bits, value as u32);
+    }
+
+    #[test]
+    fn reads_x265_1080p() {
+        let sps = HevcSps::read(&X265_1080P[2..]).unwrap();
+        assert_eq!((sps.width, sps.height), (1920, 1080));
+        assert_eq!((sps.chroma_format_idc, sps.bit_depth_luma, sps.bit_depth_chroma), (1, 8, 8));
+        assert_eq!(sps.profile.codecs(), "hvc1.1.6.L123.90");
+        assert_eq!(sps.summary(), "1920x1080 hevc hvc1.1.6.L123.90, 4:2:0 8/8 bit");
+    }
+
+    #[test]
+    fn formats_codecs() {
+        let profile = ProfileTierLevel {
+            profile_space: 0,
+            tier_flag: false,
+            profile_idc: 1,
+            compatibility_flags: 0x6000_0000,
+            constraint_flags: [0xb0, 0, 0, 0, 0, 0],
+            level_idc: 93,
+        };
+        assert_eq!(profile.codecs(), "hvc1.1.6.L93.b0");
+        // Main 10, high tier, 中間為零的 constraint byte 保留
+        let profile = ProfileTierLevel {
+            profile_space: 1,
+            tier_flag: true,
+            profile_idc: 2,
+            compatibility_flags: 0x2000_0000,
+            constraint_flags: [0x90, 0, 0x01, 0, 0, 0],
+            level_idc: 150,
+        };
+        assert_eq!(profile.codecs(), "hvc1.A2.4.H150.90.0.1");
+    }
+
+    #[test]
+    fn rejects_truncated_sps() {
+        // bit_depth_chroma_minus8 在第 25 byte 結束, 之前截斷都應該失敗
+        for length in 2..25 {
+            assert!(HevcSps::read(&X265_1080P[2..length]).is_err());
+        }
+        assert!(HevcSps::read(&X265_1080P[2..25]).is_ok());
+    }
+
+    // max_sub_layers_minus1 = 2, 第一層帶 profile, 第二層帶 level
+    #[test]
+    fn skips_sub_layers() {
+        let mut writer = BitWriter::new();
+        writer.write_bits(4, 0); // sps_video_parameter_set_id
+        writer.write_bits(3, 2); // sps_max_sub_layers_minus1
+        writer.write_bits(1, 1); // sps_temporal_id_nesting_flag
+        writer.write_bytes(&[0x01, 0x60, 0x00, 0x00, 0x00, 0xb0, 0x00, 0x00, 0x00, 0x00, 0x00, 93]);
+        writer.write_bits(4, 0b10_01); // sub_layer_profile_present_flag, sub_layer_level_present_flag
+        writer.write_bits(12, 0); // reserved_zero_2bits
+        for _ in 0..11 {
+            writer.write_bits(8, 0xff);
+        }
+        writer.write_bits(8, 90);
+        write_ue(&mut writer, 0); // sps_seq_parameter_set_id
+        write_ue(&mut writer, 1); // chroma_format_idc
+        write_ue(&mut writer, 1280);
+        write_ue(&mut writer, 720);
+        writer.write_bits(1, 0); // conformance_window_flag
+        write_ue(&mut writer, 2);
+        write_ue(&mut writer, 2);
+        writer.write_bits(1, 1);
+
+        let sps = HevcSps::read(&writer.into_bytes()).unwrap();
+        assert_eq!((sps.width, sps.height), (1280, 720));
+        assert_eq!((sps.bit_depth_luma, sps.bit_depth_chroma), (10, 10));
+        assert_eq!(sps.profile.codecs(), "hvc1.1.6.L93.b0");
+    }
+}
@@ -0,0 +1,41 @@
+// ID3v2.4 tag, HLS timed metadata (stream type 0x15) 的內容
+// Header | "ID3" | 版本 0x04 0x00 | flags | size (syncsafe u32, 不含 header)
+// Frame  | id [u8; 4] | size (syncsafe u32) | flags u16 | 內容
+// 欄位名稱為 T 開頭的 frame id (如 TIT2) 時寫成該 text frame, 其餘寫成 TXXX (description = 名稱)
+pub struct Id3 {}
+
+impl Id3 {
+    // text encoding 0x03 為 UTF-8
+    const UTF8: u8 = 0x03;
+    // 一個 tag 寫成一個 PES, pes_packet_len (u16) 還包含 flags 與 pts 的 8 bytes
+    pub const MAX_SIZE: usize = u16::MAX as usize - 8;
+
+    pub fn tag(fields: &[(String, String)]) -> Vec<u8> {
+        let mut frames = Vec::new();
+        for (name, value) in fields {
+            let is_text_frame = name.len() == 4 && name.starts_with('T') && name != "TXXX" && name.bytes().all(|b| b.is_ascii_uppercase() || b.is_ascii_digit());
+            if is_text_frame {
+                Id3::frame(&mut frames, name.as_bytes(), &[&[Id3::UTF8], value.as_bytes()].concat());
+            } else {
+                Id3::frame(&mut frames, b"TXXX", &[&[Id3::UTF8], name.as_bytes(), &[0], value.as_bytes()].concat());
+            }
+        }
+
+        let mut tag = vec![b'I', b'D', b'3', 0x04, 0x00, 0x00];
+        tag.extend(&Id3::syncsafe(frames.len()));
+        tag.extend(frames);
+        tag
+    }
+
+    fn frame(frames: &mut Vec<u8>, id: &[u8], data: &[u8]) {
+        frames.extend(id);
+        frames.extend(&Id3::syncsafe(data.len()));
+        frames.extend(&[0x00, 0x00]);
+        frames.extend(data);
+    }
+
+    // 每個 byte 只用低 7 位元
+    fn syncsafe(size: usize) -> [u8; 4] {
+        [(size >> 21) as u8 & 0x7f, (size >> 14) as u8 & 0x7f, (size >> 7) as u8 & 0x7f, size as u8 & 0x7f]
+    }
+}
@@ -0,0 +1,98 @@
+use super::adts::AdtsConfig;
+use super::bits::BitWriter;
+
+pub struct Loas {}
+
+impl Loas {
+    const SYNCWORD: u32 = 0x2b7;
+
+    // ADTS 無法表示的設定改用 LOAS/LATM (ISO/IEC 14496-3 1.7), 每個 frame 都帶 StreamMuxConfig
+    // AudioSyncStream
+    // Syncword                         u11     固定爲0x2b7
+    // Audio Mux Length Bytes           u13     AudioMuxElement 的長度
+    // AudioMuxElement
+    // Use Same Stream Mux              u1      固定爲0
+    // StreamMuxConfig
+    // Audio Mux Version                u1      固定爲1, AudioSpecificConfig 前帶長度
+    // Audio Mux Version A              u1      固定爲0
+    // Tara Buffer Fullness             LatmGetValue
+    // All Streams Same Time Framing    u1      固定爲1
+    // Num Sub Frames                   u6      固定爲0
+    // Num Program                      u4      固定爲0
+    // Num Layer                        u3      固定爲0
+    // Asc Len                          LatmGetValue
+    // AudioSpecificConfig              [u8]
+    // Frame Length Type                u3      固定爲0
+    // Latm Buffer Fullness             u8      固定爲0xff
+    // Other Data Present               u1      固定爲0
+    // Crc Check Present                u1      固定爲0
+    // Mux Slot Length Bytes            0xff 重複, 最後一個 byte 小於 0xff
+    // Payload                          [u8]
+    pub fn to_es_layer(config: &AdtsConfig, data: Vec<u8>) -> Vec<u8> {
+        let mut element = BitWriter::new();
+        element.write_bits(1, 0);
+
+        element.write_bits(1, 1);
+        element.write_bits(1, 0);
+        Loas::write_value(&mut element, 0xff);
+        element.write_bits(1, 1);
+        element.write_bits(6, 0);
+        element.write_bits(4, 0);
+        element.write_bits(3, 0);
+        Loas::write_value(&mut element, config.config.len() as u32 * 8);
+        element.write_bytes(&config.config);
+        element.write_bits(3, 0);
+        element.write_bits(8, 0xff);
+        element.write_bits(1, 0);
+        element.write_bits(1, 0);
+
+        let mut length = data.len();
+        while length >= 0xff {
+            element.write_bits(8, 0xff);
+            length -= 0xff;
+        }
+        element.write_bits(8, length as u32);
+        element.write_bytes(&data);
+        let element = element.into_bytes();
+
+        let mut es = BitWriter::new();
+        es.write_bits(11, Loas::SYNCWORD);
+        es.write_bits(13, element.len() as u32);
+        es.write_bytes(&element);
+        es.into_bytes()
+    }
+
+    // LatmGetValue: Bytes For Value u2, 之後為 (Bytes For Value + 1) 個 byte
+    fn write_value(writer: &mut BitWriter, value: u32) {
+        let bytes = (4 - value.leading_zeros() / 8).max(1);
+        writer.write_bits(2, bytes - 1);
+        writer.write_bits(bytes as u8 * 8, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    #[test]
+    fn writes_stream_mux_config() {
+        let mut config = AdtsConfig::new();
+        config.set(Bytes::from_static(&[0x12, 0x10])).unwrap();
+        let es = Loas::to_es_layer(&config, vec![0xaa, 0xbb]);
+        assert_eq!(es, [0x56, 0xe0, 0x0c, 0x47, 0xfc, 0x00, 0x00, 0x80, 0x90, 0x80, 0xff, 0x00, 0xaa, 0xae, 0xc0]);
+    }
+
+    #[test]
+    fn writes_latm_values() {
+        let value = |value: u32| {
+            let mut writer = BitWriter::new();
+            Loas::write_value(&mut writer, value);
+            writer.into_bytes()
+        };
+        assert_eq!(value(0), [0x00, 0x00]);
+        assert_eq!(value(0xff), [0x3f, 0xc0]);
+        assert_eq!(value(0x100), [0x40, 0x40, 0x00]);
+        assert_eq!(value(0x12345678), [0xc4, 0x8d, 0x15, 0x9e, 0x00]);
+    }
+}
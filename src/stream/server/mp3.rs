@@ -0,0 +1,81 @@
+use super::error::ParseError;
+
+// MPEG Audio Frame Header, MP3 沒有 sequence header, 由第一個 frame 取得設定
+// ------------------------| ----
+// Syncword                | u11   固定爲0x7ff
+// Version                 | u2    0: MPEG-2.5, 2: MPEG-2, 3: MPEG-1
+// Layer                   | u2    1: Layer III
+// Protection Absent       | u1
+// Bitrate Index           | u4
+// Sampling Frequency      | u2
+// Padding                 | u1
+// Private                 | u1
+// Channel Mode            | u2    3: 單聲道
+pub struct Mp3Config {
+    pub mpeg1: bool,
+    pub sample_rate: u32,
+    pub channels: u8,
+}
+
+impl Mp3Config {
+    const SAMPLE_RATES: [u32; 3] = [44100, 48000, 32000];
+
+    pub fn read(data: &[u8]) -> Result<Mp3Config, ParseError> {
+        if data.len() < 4 || data[0] != 0xff || data[1] & 0xe0 != 0xe0 {
+            return Err(ParseError::Invalid(String::from("mp3: missing frame sync")));
+        }
+        let version = (data[1] >> 3) & 0x03;
+        let index = ((data[2] >> 2) & 0x03) as usize;
+        if version == 1 || index == 3 {
+            return Err(ParseError::Invalid(String::from("mp3: reserved version or sampling frequency")));
+        }
+
+        // MPEG-2 為一半, MPEG-2.5 為四分之一
+        let sample_rate = match version {
+            3 => Mp3Config::SAMPLE_RATES[index],
+            2 => Mp3Config::SAMPLE_RATES[index] / 2,
+            _ => Mp3Config::SAMPLE_RATES[index] / 4,
+        };
+        let channels = if data[3] >> 6 == 3 { 1 } else { 2 };
+
+        Ok(Mp3Config {
+            mpeg1: version == 3,
+            sample_rate,
+            channels,
+        })
+    }
+
+    // Apple HLS 使用的 MP3 codecs
+    pub fn codecs(&self) -> String {
+        String::from("mp4a.40.34")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn halves_sample_rate_by_version() {
+        let config = Mp3Config::read(&[0xff, 0xfb, 0x90, 0x44]).unwrap();
+        assert_eq!((config.mpeg1, config.sample_rate, config.channels), (true, 44100, 2));
+        let config = Mp3Config::read(&[0xff, 0xfb, 0x94, 0xc4]).unwrap();
+        assert_eq!((config.mpeg1, config.sample_rate, config.channels), (true, 48000, 1));
+        let config = Mp3Config::read(&[0xff, 0xf3, 0x90, 0x44]).unwrap();
+        assert_eq!((config.mpeg1, config.sample_rate), (false, 22050));
+        let config = Mp3Config::read(&[0xff, 0xf3, 0x98, 0x44]).unwrap();
+        assert_eq!(config.sample_rate, 16000);
+        let config = Mp3Config::read(&[0xff, 0xe3, 0x94, 0x44]).unwrap();
+        assert_eq!((config.mpeg1, config.sample_rate), (false, 12000));
+    }
+
+    #[test]
+    fn rejects_reserved_headers() {
+        // version 1 保留
+        assert!(Mp3Config::read(&[0xff, 0xeb, 0x90, 0x44]).is_err());
+        // sampling frequency 3 保留
+        assert!(Mp3Config::read(&[0xff, 0xfb, 0x9c, 0x44]).is_err());
+        assert!(Mp3Config::read(&[0xff, 0x7b, 0x90, 0x44]).is_err());
+        assert!(Mp3Config::read(&[0xff, 0xfb, 0x90]).is_err());
+    }
+}
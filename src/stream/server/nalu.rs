@@ -1,4 +1,10 @@
+use std::env;
 use bytes::{Bytes, Buf};
+use super::flv::VideoCodec;
+use super::hevc::{HevcSps, ProfileTierLevel};
+use super::bits::BitReader;
+use super::sps::Sps;
+use super::error::{self, ParseError};
 
 // Flv Data - Video Sequence_Header
 // ------------------------| ----
@@ -15,30 +21,81 @@ use bytes::{Bytes, Buf};
 // PPS Count               |   u8
 // PPS Length              |   u16
 // PPS                     |   u[]
+//
+// Flv Data - HEVC Sequence_Header (hvcC)
+// ------------------------| ----
+// Version                 |   u8
+// General Profile Tier    |   u96, 見 hevc::ProfileTierLevel
+// Reserved, Segmentation  |   u16
+// Reserved, Parallelism   |   u8
+// Reserved, Chroma Format |   u8
+// Reserved, Luma Depth    |   u8
+// Reserved, Chroma Depth  |   u8
+// Avg Frame Rate          |   u16   frames / 256 秒
+// Constant Frame Rate     |   u2
+// Num Temporal Layers     |   u3
+// Temporal Id Nested      |   u1
+// NALU Length             |   u2
+// Array Count             |   u8
+// Array Completeness      |   u1
+// Reserved                |   u1
+// NALU Type               |   u6    32 = VPS, 33 = SPS, 34 = PPS, 39 = SEI
+// NALU Count              |   u16
+// NALU Length             |   u16
+// NALU                    |   u[]
 pub struct NaluConfig {
+    pub codec: VideoCodec,
     pub version: u8,
     pub profile_indication: u8,
     pub profile_compatability: u8,
     pub level_indication: u8,
     pub nalu_size: u8,
+    pub vps: Vec<Nalu>,
     pub sps: Vec<Nalu>,
     pub pps: Vec<Nalu>,
+    pub info: Option<Sps>,
+    pub hevc_profile: Option<ProfileTierLevel>,
+    pub hevc_info: Option<HevcSps>,
+    pub avg_frame_rate: u16,
+    // 原始的 sequence header, 用來判斷推流中途是否更換設定
+    pub config: Bytes,
 }
 
 impl NaluConfig {
     pub fn new() -> NaluConfig {
         NaluConfig {
+            codec: VideoCodec::Avc,
             version: 0,
             profile_indication: 0,
             profile_compatability: 0,
             level_indication: 0,
             nalu_size: 0,
+            vps: Vec::new(),
             sps: Vec::new(),
             pps: Vec::new(),
+            info: None,
+            hevc_profile: None,
+            hevc_info: None,
+            avg_frame_rate: 0,
+            config: Bytes::new(),
+        }
+    }
+
+    // 解析失敗時保留原本的設定
+    pub fn set(&mut self, codec: VideoCodec, data: Bytes) -> Result<(), ParseError> {
+        let mut config = NaluConfig::new();
+        config.codec = codec;
+        config.config = data.clone();
+        match codec {
+            VideoCodec::Avc => config.set_avc(data)?,
+            VideoCodec::Hevc => config.set_hevc(data)?,
         }
+        *self = config;
+        Ok(())
     }
 
-    pub fn set(&mut self, mut data: Bytes) {
+    fn set_avc(&mut self, mut data: Bytes) -> Result<(), ParseError> {
+        error::ensure(&data, 6, "avc sequence header")?;
         self.version = data.get_u8();
         self.profile_indication = data.get_u8();
         self.profile_compatability = data.get_u8();
@@ -48,23 +105,116 @@ impl NaluConfig {
         let sps_count = data.get_u8() & 0b11111;
         let mut sps = Vec::new();
         for _ in 0..sps_count {
-            let sps_length = data.get_u16() as usize;
-            let sps_temp = data.slice(..sps_length);
-            data.advance(sps_length);
-            sps.push(Nalu::read_unit(sps_temp));
+            sps.push(NaluConfig::read_parameter_set(&mut data, VideoCodec::Avc)?);
         }
 
+        error::ensure(&data, 1, "avc sequence header")?;
         let pps_count = data.get_u8();
         let mut pps = Vec::new();
         for _ in 0..pps_count {
-            let pps_length = data.get_u16() as usize;
-            let pps_temp = data.slice(..pps_length);
-            data.advance(pps_length);
-            pps.push(Nalu::read_unit(pps_temp));
+            pps.push(NaluConfig::read_parameter_set(&mut data, VideoCodec::Avc)?);
+        }
+
+        self.info = sps.first().and_then(|nalu| match Sps::read(&nalu.data) {
+            Ok(info) => {
+                println!("Video sps: {}", info.summary());
+                Some(info)
+            }
+            Err(error) => {
+                println!("Invalid sequence header: {}", error);
+                None
+            }
+        });
+        if let Some(info) = &self.info {
+            if info.profile_idc != self.profile_indication || info.level_idc != self.level_indication {
+                println!("Sequence header profile/level does not match sps: {} / {}", self.codecs(), info.codecs());
+            }
         }
 
         self.sps = sps;
         self.pps = pps;
+        Ok(())
+    }
+
+    fn set_hevc(&mut self, mut data: Bytes) -> Result<(), ParseError> {
+        error::ensure(&data, 23, "hevc sequence header")?;
+        self.version = data.get_u8();
+        self.hevc_profile = ProfileTierLevel::read(&mut BitReader::new(&data[..12]));
+        data.advance(12);
+        data.advance(2 + 1 + 1 + 1 + 1);
+        self.avg_frame_rate = data.get_u16();
+        self.nalu_size = (data.get_u8() & 0b11) + 1;
+
+        let array_count = data.get_u8();
+        for _ in 0..array_count {
+            error::ensure(&data, 3, "hevc sequence header")?;
+            let unit_type = data.get_u8() & 0x3f;
+            let nalu_count = data.get_u16();
+            for _ in 0..nalu_count {
+                let nalu = NaluConfig::read_parameter_set(&mut data, VideoCodec::Hevc)?;
+                match unit_type {
+                    Nalu::HEVC_VPS => self.vps.push(nalu),
+                    Nalu::HEVC_SPS => self.sps.push(nalu),
+                    Nalu::HEVC_PPS => self.pps.push(nalu),
+                    _ => (),
+                }
+            }
+        }
+
+        self.hevc_info = self.sps.first().and_then(|nalu| match HevcSps::read(&nalu.data) {
+            Ok(info) => {
+                println!("Video sps: {}", info.summary());
+                Some(info)
+            }
+            Err(error) => {
+                println!("Invalid sequence header: {}", error);
+                None
+            }
+        });
+        Ok(())
+    }
+
+    // Length u16 | NALU
+    fn read_parameter_set(data: &mut Bytes, codec: VideoCodec) -> Result<Nalu, ParseError> {
+        error::ensure(data, 2, "parameter set")?;
+        let length = data.get_u16() as usize;
+        error::ensure(data, length, "parameter set")?;
+        Nalu::read_unit(data.split_to(length), codec)
+    }
+
+    // 與目前的設定不同, 尚未設定時為 false
+    pub fn is_changed(&self, codec: VideoCodec, data: &Bytes) -> bool {
+        !self.config.is_empty() && (self.codec != codec || self.config != *data)
+    }
+
+    // RFC 6381: avc1.PPCCLL, hvc1 見 hevc::ProfileTierLevel::codecs
+    pub fn codecs(&self) -> String {
+        match self.codec {
+            VideoCodec::Hevc => match (&self.hevc_info, &self.hevc_profile) {
+                (Some(info), _) => info.profile.codecs(),
+                (None, Some(profile)) => profile.codecs(),
+                (None, None) => String::from("hvc1"),
+            },
+            VideoCodec::Avc => match &self.info {
+                Some(info) => info.codecs(),
+                None => format!("avc1.{:02x}{:02x}{:02x}", self.profile_indication, self.profile_compatability, self.level_indication),
+            },
+        }
+    }
+
+    pub fn resolution(&self) -> Option<(u32, u32)> {
+        match self.codec {
+            VideoCodec::Hevc => self.hevc_info.as_ref().map(|info| (info.width, info.height)),
+            VideoCodec::Avc => self.info.as_ref().map(|info| (info.width, info.height)),
+        }
+    }
+
+    pub fn frame_rate(&self) -> Option<f64> {
+        match self.codec {
+            VideoCodec::Hevc if self.avg_frame_rate > 0 => Some(self.avg_frame_rate as f64 / 256.0),
+            VideoCodec::Hevc => None,
+            VideoCodec::Avc => self.info.as_ref().and_then(|info| info.frame_rate),
+        }
     }
 }
 
@@ -95,92 +245,285 @@ impl NaluConfig {
 // 13~23  保留
 // 24~31  未使用
 
+// H.265 Nalu Header
+// -----| ---|
+// F	| u1 |	forbidden zero bit
+// Type	| u6 |	0~9 非關鍵幀, 16~23 關鍵幀(IRAP), 32 VPS, 33 SPS, 34 PPS, 35 分解符, 39/40 SEI
+// Layer| u6 |	nuh layer id
+// Tid	| u3 |	nuh temporal id plus1
+
 pub struct Nalu {
-    pub ref_idc: u8,
     pub unit_type: u8,
-    pub data: Bytes, // RBSP
+    pub header: Bytes, // h.264 一個 byte, h.265 兩個 byte
+    pub data: Bytes,   // RBSP
 }
 
 impl Nalu {
     const INTER_DELIMITER: &'static [u8] = &[0x00, 0x00, 0x01];
     const BEGIN_DELIMITER: &'static [u8] = &[0x00, 0x00, 0x00, 0x01];
-    const NALU_DELIMITER: &'static [u8] = &[0x00, 0x00, 0x00, 0x01, 0x09, 0x00];
+    // primary_pic_type 7 (所有類型), 之後為 rbsp stop bit
+    const NALU_DELIMITER: &'static [u8] = &[0x00, 0x00, 0x00, 0x01, 0x09, 0xf0];
+    // type 35, pic_type 2 (I, P, B)
+    const HEVC_NALU_DELIMITER: &'static [u8] = &[0x00, 0x00, 0x00, 0x01, 0x46, 0x01, 0x50];
 
-    fn to_vec(&self) -> Vec<u8> {
-        let mut v = Vec::with_capacity(self.data.len() + 1);
+    const AVC_SPS: u8 = 7;
+    const AVC_PPS: u8 = 8;
+    const HEVC_VPS: u8 = 32;
+    const HEVC_SPS: u8 = 33;
+    const HEVC_PPS: u8 = 34;
 
-        let header = (self.ref_idc << 5) | (self.unit_type);
-        v.push(header);
+    fn to_vec(&self) -> Vec<u8> {
+        let mut v = Vec::with_capacity(self.header.len() + self.data.len());
+        v.extend(self.header.clone());
         v.extend(self.data.clone());
         v
     }
 
-    pub fn read(mut data: Bytes, nalu_size: u8) -> Vec<Nalu> {
+    // nalu_size 來自 sequence header, 尚未收到時為 0
+    pub fn read(mut data: Bytes, nalu_size: u8, codec: VideoCodec) -> Result<Vec<Nalu>, ParseError> {
+        if !(1..=4).contains(&nalu_size) {
+            return Err(ParseError::Invalid(format!("nalu: invalid length size {}", nalu_size)));
+        }
         let nalu_size = nalu_size as usize;
         let mut nal_units = Vec::new();
 
         while data.has_remaining() {
+            error::ensure(&data, nalu_size, "nalu")?;
             let nalu_length = data.get_uint(nalu_size) as usize;
-            let nalu_data = data.slice(..nalu_length);
-            let nal_unit = Nalu::read_unit(nalu_data);
-            data.advance(nalu_length);
+            error::ensure(&data, nalu_length, "nalu")?;
+            let nal_unit = Nalu::read_unit(data.split_to(nalu_length), codec)?;
             nal_units.push(nal_unit);
         }
 
-        nal_units
+        Ok(nal_units)
+    }
+
+    pub fn read_unit(mut data: Bytes, codec: VideoCodec) -> Result<Nalu, ParseError> {
+        let (header, unit_type) = match codec {
+            VideoCodec::Avc => {
+                error::ensure(&data, 1, "nalu header")?;
+                let header = data.split_to(1);
+                let unit_type = header[0] & 0x1f;
+                (header, unit_type)
+            }
+            VideoCodec::Hevc => {
+                error::ensure(&data, 2, "nalu header")?;
+                let header = data.split_to(2);
+                let unit_type = (header[0] >> 1) & 0x3f;
+                (header, unit_type)
+            }
+        };
+
+        Ok(Nalu { unit_type, header, data })
     }
 
-    pub fn read_unit(mut data: Bytes) -> Nalu {
-        let nalu = data.get_u8();
-        let ref_idc = (nalu >> 5) & 0x03;
-        let unit_type = nalu & 0x1f;
+    fn is_keyframe(codec: VideoCodec, unit_type: u8) -> bool {
+        match codec {
+            VideoCodec::Avc => unit_type == 5,
+            VideoCodec::Hevc => matches!(unit_type, 16..=23),
+        }
+    }
 
-        Nalu { ref_idc, unit_type, data }
+    fn is_parameter_set(codec: VideoCodec, unit_type: u8) -> bool {
+        match codec {
+            VideoCodec::Avc => unit_type == Nalu::AVC_SPS || unit_type == Nalu::AVC_PPS,
+            VideoCodec::Hevc => matches!(unit_type, Nalu::HEVC_VPS..=Nalu::HEVC_PPS),
+        }
     }
 
-    // 轉成es時有nalu header, 固定爲0x00000001（幀開始）或0x000001（幀中）
-    // 轉成es時, pes和es之間需加入type=9的nalu, 關鍵幀前必須加入type=7和type=8的nalu, 這些nalu彼此相鄰。
-    // Pes Header | nalu(0x09) | 隨便(u8) | nalu(其他) | 內容 | nalu(0x67) | sps | nalu(0x68) | pps | nalu(0x65) | keyframe |
+    // 轉成es時有nalu header, 固定爲0x00000001（幀開始、參數集）或0x000001（幀中）
+    // 一個 FLV video tag 為一個 access unit, 開頭加入一個分解符 (h.264 type=9, h.265 type=35), 原有的分解符移除
+    // 關鍵幀之前加入 sequence header 中的參數集, 幀內已有的類型 (vps/sps/pps) 改用幀內的
+    // 其餘的 nalu 依原本的順序保留, 只移除 NaluFilter 指定的類型
+    // Pes Header | nalu(0x09) | 隨便(u8) | nalu(0x67) | sps | nalu(0x68) | pps | nalu(其他) | 內容 | nalu(0x65) | keyframe |
     // Pes Header | nalu(0x09) | 隨便(u8) | nalu(其他) | 內容 | nalu(0x41) | 內容 |
-    pub fn to_es_layer(nalu_config: &NaluConfig, data: Vec<Nalu>) -> Vec<u8> {
+    pub fn to_es_layer(nalu_config: &NaluConfig, filter: &NaluFilter, data: Vec<Nalu>) -> Vec<u8> {
+        let codec = nalu_config.codec;
         let mut es = Vec::new();
-        let mut is_delimit = false;
-        let mut is_keyframe_delimit = false;
+        let delimiter = match codec {
+            VideoCodec::Avc => Nalu::NALU_DELIMITER,
+            VideoCodec::Hevc => Nalu::HEVC_NALU_DELIMITER,
+        };
+        es.extend(delimiter);
 
-        for nalu in data {
-            match nalu.unit_type {
-                1 | 6 => {
-                    if !is_delimit {
-                        es.extend(Nalu::NALU_DELIMITER);
-                        is_delimit = true;
-                    }
-                }
-                5 => {
-                    if !is_delimit {
-                        es.extend(Nalu::NALU_DELIMITER);
-                        is_delimit = true;
-                    }
-
-                    if !is_keyframe_delimit {
-                        let nalu = nalu_config.sps.first().unwrap();
-                        let sps: Vec<u8> = nalu.to_vec();
-                        es.extend(Nalu::BEGIN_DELIMITER);
-                        es.extend(sps);
-
-                        let nalu = nalu_config.pps.first().unwrap();
-                        let pps: Vec<u8> = nalu.to_vec();
-                        es.extend(Nalu::BEGIN_DELIMITER);
-                        es.extend(pps);
-
-                        is_keyframe_delimit = true;
-                    }
+        // 參數集依 vps、sps、pps 的順序放在最前面, 解碼器解析 pps 時需要已收到 sps
+        let is_keyframe = data.iter().any(|nalu| Nalu::is_keyframe(codec, nalu.unit_type));
+        if is_keyframe {
+            let parameter_sets = match codec {
+                VideoCodec::Avc => vec![(Nalu::AVC_SPS, &nalu_config.sps), (Nalu::AVC_PPS, &nalu_config.pps)],
+                VideoCodec::Hevc => vec![(Nalu::HEVC_VPS, &nalu_config.vps), (Nalu::HEVC_SPS, &nalu_config.sps), (Nalu::HEVC_PPS, &nalu_config.pps)],
+            };
+            for (unit_type, config) in parameter_sets {
+                let in_band: Vec<&Nalu> = data.iter().filter(|nalu| nalu.unit_type == unit_type).collect();
+                let parameter_sets: Vec<&Nalu> = if in_band.is_empty() { config.iter().collect() } else { in_band };
+                for parameter_set in parameter_sets {
+                    es.extend(Nalu::BEGIN_DELIMITER);
+                    es.extend(parameter_set.to_vec());
                 }
-                _ => continue,
             }
+        }
 
-            es.extend(Self::INTER_DELIMITER);
+        for nalu in data {
+            if nalu.unit_type == Nalu::delimiter_type(codec) || filter.is_filtered(codec, nalu.unit_type) {
+                continue;
+            }
+            if is_keyframe && Nalu::is_parameter_set(codec, nalu.unit_type) {
+                continue;
+            }
+            es.extend(if Nalu::is_parameter_set(codec, nalu.unit_type) { Nalu::BEGIN_DELIMITER } else { Nalu::INTER_DELIMITER });
             es.extend(nalu.to_vec());
         }
         es
     }
+
+    fn delimiter_type(codec: VideoCodec) -> u8 {
+        match codec {
+            VideoCodec::Avc => 9,
+            VideoCodec::Hevc => 35,
+        }
+    }
+}
+
+// 轉成 es 時要移除的 nalu, 預設全部保留
+// 以環境變數 NALU_FILTER 設定, 以逗號分隔: filler, sei, end (序列/碼流結束), 或指定類型 avc:{type}, hevc:{type}
+// 例: NALU_FILTER="filler,hevc:41"
+#[derive(Clone)]
+pub struct NaluFilter {
+    avc: Vec<u8>,
+    hevc: Vec<u8>,
+}
+
+impl NaluFilter {
+    pub fn new() -> NaluFilter {
+        NaluFilter { avc: Vec::new(), hevc: Vec::new() }
+    }
+
+    pub fn from_env() -> NaluFilter {
+        NaluFilter::parse(&env::var("NALU_FILTER").unwrap_or_default())
+    }
+
+    pub fn parse(value: &str) -> NaluFilter {
+        let mut filter = NaluFilter::new();
+        for rule in value.split(',').map(|rule| rule.trim()).filter(|rule| !rule.is_empty()) {
+            match rule {
+                "filler" => {
+                    filter.avc.push(12);
+                    filter.hevc.push(38);
+                }
+                "sei" => {
+                    filter.avc.push(6);
+                    filter.hevc.extend(&[39, 40]);
+                }
+                "end" => {
+                    filter.avc.extend(&[10, 11]);
+                    filter.hevc.extend(&[36, 37]);
+                }
+                _ => match rule.split_once(':').map(|(codec, unit_type)| (codec, unit_type.parse::<u8>())) {
+                    Some(("avc", Ok(unit_type))) if unit_type < 32 => filter.avc.push(unit_type),
+                    Some(("hevc", Ok(unit_type))) if unit_type < 64 => filter.hevc.push(unit_type),
+                    _ => println!("Invalid NALU_FILTER rule '{}'", rule),
+                },
+            }
+        }
+        filter
+    }
+
+    pub fn is_filtered(&self, codec: VideoCodec, unit_type: u8) -> bool {
+        match codec {
+            VideoCodec::Avc => self.avc.contains(&unit_type),
+            VideoCodec::Hevc => self.hevc.contains(&unit_type),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nalu(codec: VideoCodec, data: &'static [u8]) -> Nalu {
+        Nalu::read_unit(Bytes::from_static(data), codec).unwrap()
+    }
+
+    fn avc_config() -> NaluConfig {
+        let mut config = NaluConfig::new();
+        config.sps.push(nalu(VideoCodec::Avc, &[0x67, 0x64]));
+        config.pps.push(nalu(VideoCodec::Avc, &[0x68, 0xee]));
+        config
+    }
+
+    // 只補上幀內沒有的參數集
+    #[test]
+    fn prepends_missing_parameter_sets() {
+        let config = avc_config();
+        let data = vec![nalu(VideoCodec::Avc, &[0x67, 0x4d]), nalu(VideoCodec::Avc, &[0x65, 0x88])];
+        let es = Nalu::to_es_layer(&config, &NaluFilter::new(), data);
+        assert_eq!(es, [&[0, 0, 0, 1, 0x09, 0xf0][..], &[0, 0, 0, 1, 0x67, 0x4d], &[0, 0, 0, 1, 0x68, 0xee], &[0, 0, 1, 0x65, 0x88]].concat());
+    }
+
+    // 原有的分解符移除, 每個 access unit 只有一個
+    #[test]
+    fn writes_one_delimiter_per_access_unit() {
+        let config = avc_config();
+        let data = vec![nalu(VideoCodec::Avc, &[0x09, 0x10]), nalu(VideoCodec::Avc, &[0x06, 0x05]), nalu(VideoCodec::Avc, &[0x41, 0x9a])];
+        let es = Nalu::to_es_layer(&config, &NaluFilter::new(), data);
+        assert_eq!(es, [&[0, 0, 0, 1, 0x09, 0xf0][..], &[0, 0, 1, 0x06, 0x05], &[0, 0, 1, 0x41, 0x9a]].concat());
+
+        let mut config = NaluConfig::new();
+        config.codec = VideoCodec::Hevc;
+        let data = vec![nalu(VideoCodec::Hevc, &[0x46, 0x01, 0x10]), nalu(VideoCodec::Hevc, &[0x02, 0x01, 0xd0])];
+        let es = Nalu::to_es_layer(&config, &NaluFilter::new(), data);
+        assert_eq!(es, [&[0, 0, 0, 1, 0x46, 0x01, 0x50][..], &[0, 0, 1, 0x02, 0x01, 0xd0]].concat());
+    }
+
+    // 非關鍵幀不加入參數集, 關鍵幀且幀內沒有參數集時加入 sequence header 中的
+    #[test]
+    fn prepends_parameter_sets_only_on_keyframes() {
+        let config = avc_config();
+        let es = Nalu::to_es_layer(&config, &NaluFilter::new(), vec![nalu(VideoCodec::Avc, &[0x41, 0x9a])]);
+        assert_eq!(es, [&[0, 0, 0, 1, 0x09, 0xf0][..], &[0, 0, 1, 0x41, 0x9a]].concat());
+
+        let es = Nalu::to_es_layer(&config, &NaluFilter::new(), vec![nalu(VideoCodec::Avc, &[0x65, 0x88])]);
+        assert_eq!(es, [&[0, 0, 0, 1, 0x09, 0xf0][..], &[0, 0, 0, 1, 0x67, 0x64], &[0, 0, 0, 1, 0x68, 0xee], &[0, 0, 1, 0x65, 0x88]].concat());
+
+        let mut config = NaluConfig::new();
+        config.codec = VideoCodec::Hevc;
+        config.vps.push(nalu(VideoCodec::Hevc, &[0x40, 0x01, 0x0c]));
+        config.sps.push(nalu(VideoCodec::Hevc, &[0x42, 0x01, 0x01]));
+        config.pps.push(nalu(VideoCodec::Hevc, &[0x44, 0x01, 0xc1]));
+        let es = Nalu::to_es_layer(&config, &NaluFilter::new(), vec![nalu(VideoCodec::Hevc, &[0x26, 0x01, 0xaf])]);
+        let expected = [
+            &[0, 0, 0, 1, 0x46, 0x01, 0x50][..],
+            &[0, 0, 0, 1, 0x40, 0x01, 0x0c],
+            &[0, 0, 0, 1, 0x42, 0x01, 0x01],
+            &[0, 0, 0, 1, 0x44, 0x01, 0xc1],
+            &[0, 0, 1, 0x26, 0x01, 0xaf],
+        ];
+        assert_eq!(es, expected.concat());
+    }
+
+    // 沒有在白名單中的類型 (data partition、序列結束等) 也保留
+    #[test]
+    fn keeps_other_units_unless_filtered() {
+        let config = avc_config();
+        let data = || vec![nalu(VideoCodec::Avc, &[0x02, 0x01]), nalu(VideoCodec::Avc, &[0x0c, 0xff]), nalu(VideoCodec::Avc, &[0x0a])];
+        let es = Nalu::to_es_layer(&config, &NaluFilter::new(), data());
+        assert_eq!(es, [&[0, 0, 0, 1, 0x09, 0xf0][..], &[0, 0, 1, 0x02, 0x01], &[0, 0, 1, 0x0c, 0xff], &[0, 0, 1, 0x0a]].concat());
+
+        let es = Nalu::to_es_layer(&config, &NaluFilter::parse("filler,end"), data());
+        assert_eq!(es, [&[0, 0, 0, 1, 0x09, 0xf0][..], &[0, 0, 1, 0x02, 0x01]].concat());
+    }
+
+    #[test]
+    fn parses_filter_rules() {
+        let filter = NaluFilter::parse("filler, sei,end,avc:3,hevc:41");
+        assert_eq!(filter.avc, vec![12, 6, 10, 11, 3]);
+        assert_eq!(filter.hevc, vec![38, 39, 40, 36, 37, 41]);
+        assert!(filter.is_filtered(VideoCodec::Hevc, 41));
+        assert!(!filter.is_filtered(VideoCodec::Avc, 41));
+
+        // 無效的規則略過
+        let filter = NaluFilter::parse("avc:32,hevc:64,avc:x,h264:5,idr,,avc:1");
+        assert_eq!(filter.avc, vec![1]);
+        assert!(filter.hevc.is_empty());
+    }
 }
@@ -0,0 +1,129 @@
+use bytes::{Bytes, Buf};
+use super::error::ParseError;
+
+// Enhanced RTMP Opus Sequence Start (OpusHead, RFC 7845)
+// ------------------------| ----
+// Magic Signature         | "OpusHead"
+// Version                 | u8
+// Channel Count           | u8
+// Pre Skip                | u16 le
+// Input Sample Rate       | u32 le
+// Output Gain             | i16 le
+// Mapping Family          | u8    0: 1~2 聲道, 1: 1~8 聲道 (Vorbis 順序)
+// MPEG-TS 的 channel config 只能表示 family 0 與 1, 其他 (如 255) 不支援
+pub struct OpusConfig {
+    pub channels: u8,
+    pub pre_skip: u16,
+    pub sample_rate: u32,
+}
+
+impl OpusConfig {
+    const MAGIC: &'static [u8] = b"OpusHead";
+
+    pub fn new() -> OpusConfig {
+        OpusConfig {
+            channels: 2,
+            pre_skip: 0,
+            sample_rate: 48000,
+        }
+    }
+
+    pub fn set(&mut self, mut data: Bytes) -> Result<(), ParseError> {
+        if data.len() < 19 || !data.starts_with(OpusConfig::MAGIC) {
+            return Err(ParseError::Invalid(String::from("opus: invalid OpusHead")));
+        }
+        data.advance(OpusConfig::MAGIC.len() + 1);
+        let channels = data.get_u8();
+        let pre_skip = data.get_u16_le();
+        let sample_rate = data.get_u32_le();
+        data.advance(2); // output gain
+        let mapping_family = data.get_u8();
+        let max_channels = match mapping_family {
+            0 => 2,
+            1 => 8,
+            _ => return Err(ParseError::Invalid(format!("opus: unsupported channel mapping family {}", mapping_family))),
+        };
+        if channels == 0 || channels > max_channels {
+            return Err(ParseError::Invalid(format!("opus: invalid channel count {} for mapping family {}", channels, mapping_family)));
+        }
+        self.channels = channels;
+        self.pre_skip = pre_skip;
+        self.sample_rate = sample_rate;
+        Ok(())
+    }
+
+    pub fn codecs(&self) -> String {
+        String::from("opus")
+    }
+}
+
+pub struct Opus {}
+
+impl Opus {
+    // Opus in MPEG-TS (https://opus-codec.org/docs/ETSI_TS_opus-v0.1.3-draft.pdf) 的 control header
+    // Prefix                   u11     固定爲0x3ff
+    // Start Trim Flag          u1
+    // End Trim Flag            u1
+    // Control Extension Flag   u1
+    // Reserved                 u2
+    // Au Size                  0xff 重複, 最後一個 byte 小於 0xff
+    pub fn to_es_layer(data: &[u8]) -> Vec<u8> {
+        let mut es = Vec::with_capacity(data.len() + 2 + data.len() / 255 + 1);
+        es.extend(&[0x7f, 0xe0]);
+
+        let mut size = data.len();
+        while size >= 0xff {
+            es.push(0xff);
+            size -= 0xff;
+        }
+        es.push(size as u8);
+        es.extend(data);
+        es
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn opus_head(channels: u8, mapping_family: u8) -> Vec<u8> {
+        let mut head = b"OpusHead".to_vec();
+        head.extend(&[1, channels, 0x38, 0x01, 0x80, 0xbb, 0x00, 0x00, 0x00, 0x00, mapping_family]);
+        head
+    }
+
+    #[test]
+    fn reads_opus_head() {
+        let mut config = OpusConfig::new();
+        config.set(Bytes::from(opus_head(1, 0))).unwrap();
+        assert_eq!((config.channels, config.pre_skip, config.sample_rate), (1, 312, 48000));
+        config.set(Bytes::from(opus_head(6, 1))).unwrap();
+        assert_eq!(config.channels, 6);
+
+        // 長度不足或 magic 不符
+        let head = opus_head(2, 0);
+        assert!(config.set(Bytes::from(head[..18].to_vec())).is_err());
+        let mut magic = head.clone();
+        magic[4] = b'T';
+        assert!(config.set(Bytes::from(magic)).is_err());
+        // 無法以 MPEG-TS channel config 表示的設定, 失敗時保留原本的設定
+        assert!(config.set(Bytes::from(opus_head(2, 255))).is_err());
+        assert!(config.set(Bytes::from(opus_head(6, 0))).is_err());
+        assert!(config.set(Bytes::from(opus_head(9, 1))).is_err());
+        assert!(config.set(Bytes::from(opus_head(0, 1))).is_err());
+        assert_eq!(config.channels, 6);
+    }
+
+    #[test]
+    fn writes_au_size() {
+        let es = Opus::to_es_layer(&[0; 254]);
+        assert_eq!((&es[..3], es.len()), (&[0x7f, 0xe0, 0xfe][..], 3 + 254));
+        let es = Opus::to_es_layer(&[0; 255]);
+        assert_eq!((&es[..4], es.len()), (&[0x7f, 0xe0, 0xff, 0x00][..], 4 + 255));
+        let es = Opus::to_es_layer(&[0; 510]);
+        assert_eq!((&es[..5], es.len()), (&[0x7f, 0xe0, 0xff, 0xff, 0x00][..], 5 + 510));
+        let es = Opus::to_es_layer(&[1; 511]);
+        assert_eq!((&es[..5], es.len()), (&[0x7f, 0xe0, 0xff, 0xff, 0x01][..], 5 + 511));
+        assert_eq!(es[5], 1);
+    }
+}
@@ -0,0 +1,116 @@
+use std::fs;
+
+// CHAT_LOG=1 時保存整場直播, 重新 publish 時 ./video/{stream key} 會被清除
+// segment 以 hard link 保存在錄影資料夾, 檔名為開始時間(ms), 與聊天訊息的 media_time 相同
+pub struct Recording {
+    directory: String,
+    segments: Vec<RecordedSegment>,
+    // 目前 segment 的開始時間(ms), 第一個 keyframe 之前為 None
+    start: Option<u32>,
+    // 下一個 segment 的編碼設定與前一個不同
+    pub discontinuity: bool,
+}
+
+struct RecordedSegment {
+    start: u32,
+    duration: u32,
+    discontinuity: bool,
+}
+
+impl Recording {
+    pub fn new(directory: String) -> Recording {
+        if let Err(error) = fs::create_dir_all(&directory) {
+            println!("failed to create recording {}: {}", directory, error);
+        }
+        Recording { directory, segments: Vec::new(), start: None, discontinuity: false }
+    }
+
+    pub fn start(&mut self, timestamp: u32) {
+        self.start.get_or_insert(timestamp);
+    }
+
+    // path 為已寫入的 segment, end 為下一個 segment 的開始時間
+    pub fn push(&mut self, path: &str, end: u32) {
+        let start = match self.start {
+            Some(start) => start,
+            None => return,
+        };
+        let target = format!("{}/{}.ts", self.directory, start);
+        if let Err(error) = fs::hard_link(path, &target).or_else(|_| fs::copy(path, &target).map(|_| ())) {
+            println!("failed to record {}: {}", target, error);
+            return;
+        }
+        self.segments.push(RecordedSegment {
+            start,
+            duration: end.saturating_sub(start).max(1),
+            discontinuity: std::mem::take(&mut self.discontinuity),
+        });
+        self.start = Some(end);
+        self.write_playlist(false);
+    }
+
+    pub fn finish(&mut self, path: &str, end: u32) {
+        self.push(path, end);
+        self.write_playlist(true);
+    }
+
+    // 直播中為 EVENT, 結束後為 VOD
+    fn write_playlist(&self, end: bool) {
+        let target_duration = self.segments.iter().map(|s| s.duration.div_ceil(1000)).max().unwrap_or(1);
+        let mut m3u8 = String::from("");
+        m3u8 = format!("{}#EXTM3U\r\n", m3u8);
+        m3u8 = format!("{}#EXT-X-VERSION:3\r\n", m3u8);
+        m3u8 = format!("{}#EXT-X-PLAYLIST-TYPE:{}\r\n", m3u8, if end { "VOD" } else { "EVENT" });
+        m3u8 = format!("{}#EXT-X-TARGETDURATION:{}\r\n", m3u8, target_duration);
+        m3u8 = format!("{}#EXT-X-MEDIA-SEQUENCE:0\r\n", m3u8);
+        for segment in &self.segments {
+            if segment.discontinuity {
+                m3u8 = format!("{}#EXT-X-DISCONTINUITY\r\n", m3u8);
+            }
+            m3u8 = format!("{}#EXTINF:{:.3},\r\n{}.ts\r\n", m3u8, segment.duration as f64 / 1000.0, segment.start);
+        }
+        if end {
+            m3u8 = format!("{}#EXT-X-ENDLIST\r\n", m3u8);
+        }
+        if let Err(error) = fs::write(format!("{}/video.m3u8", self.directory), m3u8) {
+            println!("failed to write recording playlist {}: {}", self.directory, error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn keeps_segments_after_live_directory_is_removed() {
+        let root = env::temp_dir().join(format!("mock-yo-stream-recording-{}", std::process::id()));
+        let live = root.join("live");
+        fs::create_dir_all(&live).unwrap();
+        let segment = |name: &str| {
+            let path = live.join(name).to_string_lossy().to_string();
+            fs::write(&path, name).unwrap();
+            path
+        };
+
+        let directory = root.join("recording").to_string_lossy().to_string();
+        let mut recording = Recording::new(directory.clone());
+        recording.start(40);
+        recording.push(&segment("2040.ts"), 2040);
+        recording.discontinuity = true;
+        recording.push(&segment("4100.ts"), 4100);
+        assert!(fs::read_to_string(format!("{}/video.m3u8", directory)).unwrap().contains("#EXT-X-PLAYLIST-TYPE:EVENT\r\n"));
+        recording.finish(&segment("0.ts"), 5000);
+        fs::remove_dir_all(&live).unwrap();
+
+        let m3u8 = fs::read_to_string(format!("{}/video.m3u8", directory)).unwrap();
+        assert_eq!(
+            m3u8,
+            "#EXTM3U\r\n#EXT-X-VERSION:3\r\n#EXT-X-PLAYLIST-TYPE:VOD\r\n#EXT-X-TARGETDURATION:3\r\n#EXT-X-MEDIA-SEQUENCE:0\r\n\
+             #EXTINF:2.000,\r\n40.ts\r\n#EXT-X-DISCONTINUITY\r\n#EXTINF:2.060,\r\n2040.ts\r\n#EXTINF:0.900,\r\n4100.ts\r\n#EXT-X-ENDLIST\r\n"
+        );
+        assert_eq!(fs::read_to_string(format!("{}/2040.ts", directory)).unwrap(), "4100.ts");
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,116 @@
+// SCTE-35 splice_info_section, 只使用 splice_insert
+// ---------------------| ----
+// Table Id             | u8    0xFC
+// Section Length       | u16   section_syntax_indicator(1) private_indicator(1) sap_type(2) section_length(12)
+// Protocol Version     | u8
+// PTS Adjustment       | u40   encrypted_packet(1) encryption_algorithm(6) pts_adjustment(33)
+// CW Index             | u8
+// Command Length       | u24   tier(12) splice_command_length(12)
+// Command Type         | u8    0x05 (splice_insert)
+// Splice Insert        | [u8]
+// Descriptor Length    | u16
+// CRC32                | u32
+pub struct SpliceInsert {
+    pub event_id: u32,
+    // true 為進入廣告, false 為回到節目
+    pub out_of_network: bool,
+    // 90kHz
+    pub pts: u64,
+    pub duration: Option<u64>,
+}
+
+impl SpliceInsert {
+    const TABLE_ID: u8 = 0xfc;
+    const SPLICE_INSERT: u8 = 0x05;
+
+    pub fn section(&self) -> Vec<u8> {
+        let command = self.command();
+
+        let mut section = vec![SpliceInsert::TABLE_ID, 0, 0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+        // tier 0xFFF
+        section.extend(&[0xff, 0xf0 | (command.len() >> 8) as u8 & 0x0f, command.len() as u8]);
+        section.push(SpliceInsert::SPLICE_INSERT);
+        section.extend(command);
+        section.extend(&[0x00, 0x00]);
+
+        // section_length 之後到 CRC 結束的長度, sap_type 為 3 (未指定)
+        let section_length = section.len() - 3 + 4;
+        section[1] = 0x30 | (section_length >> 8) as u8 & 0x0f;
+        section[2] = section_length as u8;
+        let crc = SpliceInsert::crc32(&section);
+        section.extend(&crc.to_be_bytes());
+        section
+    }
+
+    // splice_event_id | cancel(1) reserved(7) | out_of_network(1) program_splice(1) duration(1) splice_immediate(1) event_id_compliance(1) reserved(3)
+    // | splice_time | break_duration | unique_program_id u16 | avail_num u8 | avails_expected u8
+    fn command(&self) -> Vec<u8> {
+        let mut command = self.event_id.to_be_bytes().to_vec();
+        command.push(0x7f);
+        command.push((self.out_of_network as u8) << 7 | 0x40 | (self.duration.is_some() as u8) << 5 | 0x08 | 0x07);
+        // time_specified_flag(1) reserved(6) pts_time(33)
+        command.push(0xfe | (self.pts >> 32) as u8 & 0x01);
+        command.extend(&(self.pts as u32).to_be_bytes());
+        // auto_return(1) reserved(6) duration(33), 時間到自動回到節目
+        if let Some(duration) = self.duration {
+            command.push(0xfe | (duration >> 32) as u8 & 0x01);
+            command.extend(&(duration as u32).to_be_bytes());
+        }
+        command.extend(&[0x00, 0x01, 0x00, 0x00]);
+        command
+    }
+
+    // MPEG-2 CRC32, 與 PSI 相同
+    fn crc32(data: &[u8]) -> u32 {
+        let mut crc = 0xffff_ffffu32;
+        for &byte in data {
+            crc ^= (byte as u32) << 24;
+            for _ in 0..8 {
+                crc = if crc & 0x8000_0000 != 0 { (crc << 1) ^ 0x04c1_1db7 } else { crc << 1 };
+            }
+        }
+        crc
+    }
+
+    // playlist 中 SCTE35-OUT / SCTE35-IN 的格式
+    pub fn hex(section: &[u8]) -> String {
+        let hex: Vec<String> = section.iter().map(|byte| format!("{:02X}", byte)).collect();
+        format!("0x{}", hex.concat())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32_matches_mpeg2() {
+        // CRC-32/MPEG-2 的 check value
+        assert_eq!(SpliceInsert::crc32(b"123456789"), 0x0376_e6e7);
+        assert_eq!(SpliceInsert::crc32(&[]), 0xffff_ffff);
+    }
+
+    #[test]
+    fn splice_out_with_duration() {
+        let section = SpliceInsert { event_id: 77, out_of_network: true, pts: 540_000, duration: Some(585_000) }.section();
+        assert_eq!(SpliceInsert::hex(&section), "0xFC302500000000000000FFF014050000004D7FEFFE00083D60FE0008ED2800010000000050AC002A");
+        // section_length 為之後的長度, splice_command_length 為 splice_insert 的長度
+        assert_eq!(((section[1] as usize & 0x0f) << 8) | section[2] as usize, section.len() - 3);
+        assert_eq!(((section[11] as usize & 0x0f) << 8) | section[12] as usize, 20);
+        assert_eq!(section[13], 0x05);
+        // 含 CRC 的整個 section 再計算一次為 0
+        assert_eq!(SpliceInsert::crc32(&section), 0);
+    }
+
+    #[test]
+    fn splice_in_without_duration() {
+        let section = SpliceInsert { event_id: 1, out_of_network: false, pts: (1 << 32) | 0x1234, duration: None }.section();
+        assert_eq!(section.len(), 35);
+        assert_eq!(&section[14..18], &[0, 0, 0, 1]);
+        // out_of_network 0, program_splice 1, duration_flag 0, splice_immediate 0
+        assert_eq!(section[19] & 0xf0, 0x40);
+        // pts 的第 33 位元
+        assert_eq!(&section[20..25], &[0xff, 0x00, 0x00, 0x12, 0x34]);
+        assert_eq!(SpliceInsert::crc32(&section), 0);
+    }
+}
@@ -0,0 +1,321 @@
+use std::convert::TryFrom;
+use super::bits::{self, BitReader};
+
+// H.264 Sequence Parameter Set (nalu header 之後, 需先去除 emulation prevention byte)
+// ---------------------------------| ----
+// Profile Idc                      | u8
+// Constraint Flags                 | u8
+// Level Idc                        | u8
+// Seq Parameter Set Id             | ue
+// (High Profile) Chroma Format Idc | ue    ...
+// Log2 Max Frame Num Minus4        | ue
+// Pic Order Cnt Type               | ue    ...
+// Max Num Ref Frames               | ue
+// Gaps In Frame Num Allowed        | u1
+// Pic Width In Mbs Minus1          | ue
+// Pic Height In Map Units Minus1   | ue
+// Frame Mbs Only Flag              | u1
+// ...
+// Frame Cropping Flag              | u1    left, right, top, bottom: ue
+// Vui Parameters Present Flag      | u1    timing info: num units in tick u32, time scale u32
+pub struct Sps {
+    pub profile_idc: u8,
+    pub constraint_flags: u8,
+    pub level_idc: u8,
+    pub chroma_format_idc: u32,
+    pub bit_depth_luma: u32,
+    pub bit_depth_chroma: u32,
+    pub width: u32,
+    pub height: u32,
+    pub interlaced: bool,
+    pub frame_rate: Option<f64>,
+}
+
+impl Sps {
+    const HIGH_PROFILES: &'static [u8] = &[100, 110, 122, 244, 44, 83, 86, 118, 128, 138, 139, 134, 135];
+    const EXTENDED_SAR: u32 = 255;
+
+    pub fn read(data: &[u8]) -> Result<Sps, String> {
+        let rbsp = bits::to_rbsp(data);
+        Sps::read_rbsp(&rbsp).ok_or_else(|| String::from("sps: unexpected end of data"))?
+    }
+
+    fn read_rbsp(data: &[u8]) -> Option<Result<Sps, String>> {
+        let mut reader = BitReader::new(data);
+
+        let profile_idc = reader.read_bits(8)? as u8;
+        let constraint_flags = reader.read_bits(8)? as u8;
+        let level_idc = reader.read_bits(8)? as u8;
+        let id = reader.read_ue()?;
+        if id > 31 {
+            return Some(Err(format!("sps: invalid seq_parameter_set_id {}", id)));
+        }
+
+        let mut chroma_format_idc = 1;
+        let mut separate_colour_plane = false;
+        let mut bit_depth_luma = 8;
+        let mut bit_depth_chroma = 8;
+        if Sps::HIGH_PROFILES.contains(&profile_idc) {
+            chroma_format_idc = reader.read_ue()?;
+            if chroma_format_idc > 3 {
+                return Some(Err(format!("sps: invalid chroma_format_idc {}", chroma_format_idc)));
+            }
+            if chroma_format_idc == 3 {
+                separate_colour_plane = reader.read_flag()?;
+            }
+            bit_depth_luma = reader.read_ue()? as u64 + 8;
+            bit_depth_chroma = reader.read_ue()? as u64 + 8;
+            if bit_depth_luma > 14 || bit_depth_chroma > 14 {
+                return Some(Err(format!("sps: invalid bit depth {}/{}", bit_depth_luma, bit_depth_chroma)));
+            }
+            reader.skip_bits(1)?; // qpprime_y_zero_transform_bypass_flag
+            if reader.read_flag()? {
+                let count = if chroma_format_idc != 3 { 8 } else { 12 };
+                for i in 0..count {
+                    if reader.read_flag()? {
+                        Sps::skip_scaling_list(&mut reader, if i < 6 { 16 } else { 64 })?;
+                    }
+                }
+            }
+        }
+
+        let log2_max_frame_num = reader.read_ue()? as u64 + 4;
+        if log2_max_frame_num > 16 {
+            return Some(Err(format!("sps: invalid log2_max_frame_num {}", log2_max_frame_num)));
+        }
+        match reader.read_ue()? {
+            0 => {
+                reader.read_ue()?; // log2_max_pic_order_cnt_lsb_minus4
+            }
+            1 => {
+                reader.skip_bits(1)?; // delta_pic_order_always_zero_flag
+                reader.read_se()?; // offset_for_non_ref_pic
+                reader.read_se()?; // offset_for_top_to_bottom_field
+                let cycle = reader.read_ue()?;
+                if cycle > 255 {
+                    return Some(Err(format!("sps: invalid num_ref_frames_in_pic_order_cnt_cycle {}", cycle)));
+                }
+                for _ in 0..cycle {
+                    reader.read_se()?;
+                }
+            }
+            2 => (),
+            poc_type => return Some(Err(format!("sps: invalid pic_order_cnt_type {}", poc_type))),
+        }
+        reader.read_ue()?; // max_num_ref_frames
+        reader.skip_bits(1)?; // gaps_in_frame_num_value_allowed_flag
+
+        let width_in_mbs = reader.read_ue()? as u64 + 1;
+        let height_in_map_units = reader.read_ue()? as u64 + 1;
+        let frame_mbs_only = reader.read_flag()?;
+        if !frame_mbs_only {
+            reader.skip_bits(1)?; // mb_adaptive_frame_field_flag
+        }
+        reader.skip_bits(1)?; // direct_8x8_inference_flag
+
+        let (mut crop_left, mut crop_right, mut crop_top, mut crop_bottom) = (0u64, 0u64, 0u64, 0u64);
+        if reader.read_flag()? {
+            crop_left = reader.read_ue()? as u64;
+            crop_right = reader.read_ue()? as u64;
+            crop_top = reader.read_ue()? as u64;
+            crop_bottom = reader.read_ue()? as u64;
+        }
+
+        // 裁切單位依 ChromaArrayType 而定
+        let field_factor = if frame_mbs_only { 1 } else { 2 };
+        let chroma_array_type = if separate_colour_plane { 0 } else { chroma_format_idc };
+        let (crop_unit_x, crop_unit_y) = match chroma_array_type {
+            1 => (2, 2 * field_factor),
+            2 => (2, field_factor),
+            _ => (1, field_factor),
+        };
+
+        let width = (width_in_mbs * 16).checked_sub(crop_unit_x * (crop_left + crop_right));
+        let height = (height_in_map_units * 16 * field_factor).checked_sub(crop_unit_y * (crop_top + crop_bottom));
+        let (width, height) = match (width.and_then(|w| u32::try_from(w).ok()), height.and_then(|h| u32::try_from(h).ok())) {
+            (Some(width), Some(height)) if width > 0 && height > 0 => (width, height),
+            _ => return Some(Err(String::from("sps: invalid picture size or cropping"))),
+        };
+
+        let frame_rate = if reader.read_flag()? { Sps::read_vui_frame_rate(&mut reader)? } else { None };
+
+        Some(Ok(Sps {
+            profile_idc,
+            constraint_flags,
+            level_idc,
+            chroma_format_idc,
+            bit_depth_luma: bit_depth_luma as u32,
+            bit_depth_chroma: bit_depth_chroma as u32,
+            width,
+            height,
+            interlaced: !frame_mbs_only,
+            frame_rate,
+        }))
+    }
+
+    // Vui Parameters
+    // ---------------------------------| ----
+    // Aspect Ratio Info Present        | u1    aspect ratio idc u8, 255: sar width u16, sar height u16
+    // Overscan Info Present            | u1    overscan appropriate u1
+    // Video Signal Type Present        | u1    video format u3, full range u1, colour description u1: u8 * 3
+    // Chroma Loc Info Present          | u1    ue, ue
+    // Timing Info Present              | u1    num units in tick u32, time scale u32, fixed frame rate u1
+    fn read_vui_frame_rate(reader: &mut BitReader) -> Option<Option<f64>> {
+        if reader.read_flag()? && reader.read_bits(8)? == Sps::EXTENDED_SAR {
+            reader.skip_bits(32)?;
+        }
+        if reader.read_flag()? {
+            reader.skip_bits(1)?;
+        }
+        if reader.read_flag()? {
+            reader.skip_bits(4)?;
+            if reader.read_flag()? {
+                reader.skip_bits(24)?;
+            }
+        }
+        if reader.read_flag()? {
+            reader.read_ue()?;
+            reader.read_ue()?;
+        }
+        if !reader.read_flag()? {
+            return Some(None);
+        }
+
+        let num_units_in_tick = reader.read_bits(32)?;
+        let time_scale = reader.read_bits(32)?;
+        if num_units_in_tick == 0 || time_scale == 0 {
+            return Some(None);
+        }
+        // 一個 frame 為兩個 field tick
+        Some(Some(time_scale as f64 / (2.0 * num_units_in_tick as f64)))
+    }
+
+    fn skip_scaling_list(reader: &mut BitReader, size: usize) -> Option<()> {
+        let mut last_scale: i64 = 8;
+        let mut next_scale: i64 = 8;
+        for _ in 0..size {
+            if next_scale != 0 {
+                let delta_scale = reader.read_se()? as i64;
+                next_scale = (last_scale + delta_scale + 256).rem_euclid(256);
+            }
+            if next_scale != 0 {
+                last_scale = next_scale;
+            }
+        }
+        Some(())
+    }
+
+    pub fn summary(&self) -> String {
+        let chroma = match self.chroma_format_idc {
+            0 => "4:0:0",
+            1 => "4:2:0",
+            2 => "4:2:2",
+            _ => "4:4:4",
+        };
+        let scan = if self.interlaced { "interlaced" } else { "progressive" };
+        let frame_rate = self.frame_rate.map_or(String::from("unknown fps"), |rate| format!("{:.2} fps", rate));
+        format!("{}x{} {} {}, {} {}/{} bit", self.width, self.height, scan, frame_rate, chroma, self.bit_depth_luma, self.bit_depth_chroma)
+    }
+
+    // RFC 6381: avc1.PPCCLL
+    pub fn codecs(&self) -> String {
+        format!("avc1.{:02x}{:02x}{:02x}", self.profile_idc, self.constraint_flags, self.level_idc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::bits::BitWriter;
+
+    // x264 輸出的 SPS (含 nalu header 0x67)
+    const X264_1080P: &[u8] = &[
+        0x67, 0x64, 0x00, 0x28, 0xac, 0xd9, 0x40, 0x78, 0x02, 0x27, 0xe5, 0xc0, 0x44, 0x00, 0x00, 0x03, 0x00, 0x04, 0x00, 0x00, 0x03, 0x00, 0xf0, 0x3c, 0x60, 0xc6, 0x58,
+    ];
+    const X264_720P: &[u8] = &[
+        0x67, 0x64, 0x00, 0x1f, 0xac, 0xd9, 0x40, 0x50, 0x05, 0xbb, 0x01, 0x6a, 0x02, 0x02, 0x02, 0x80, 0x00, 0x00, 0x03, 0x00, 0x80, 0x00, 0x00, 0x1e, 0x47, 0x8c, 0x18, 0xcb,
+    ];
+
+    fn write_ue(writer: &mut BitWriter, value: u32) {
+        let value = value as u64 + 1;
+        let bits = 64 - value.leading_zeros() as u8;
+        writer.write_bits(bits - 1, 0);
+        writer.write_bits(bits, value as u32);
+    }
+
+    fn write_se(writer: &mut BitWriter, value: i32) {
+        write_ue(writer, if value > 0 { 2 * value as u32 - 1 } else { (-2 * value) as u32 });
+    }
+
+    #[test]
+    fn reads_x264_1080p() {
+        // 1920x1088 裁掉下方 8 行, 含 emulation prevention byte
+        let sps = Sps::read(&X264_1080P[1..]).unwrap();
+        assert_eq!((sps.profile_idc, sps.constraint_flags, sps.level_idc), (100, 0, 40));
+        assert_eq!((sps.width, sps.height), (1920, 1080));
+        assert_eq!((sps.chroma_format_idc, sps.bit_depth_luma, sps.bit_depth_chroma), (1, 8, 8));
+        assert!(!sps.interlaced);
+        assert_eq!(sps.frame_rate, Some(30.0));
+        assert_eq!(sps.codecs(), "avc1.640028");
+    }
+
+    #[test]
+    fn reads_x264_720p() {
+        let sps = Sps::read(&X264_720P[1..]).unwrap();
+        assert_eq!((sps.width, sps.height), (1280, 720));
+        assert_eq!(sps.frame_rate, Some(30.0));
+        assert_eq!(sps.codecs(), "avc1.64001f");
+        assert_eq!(sps.summary(), "1280x720 progressive 30.00 fps, 4:2:0 8/8 bit");
+    }
+
+    // High profile 帶 seq_scaling_list, delta_scale 為 -128 與 127 時 next_scale 需以 256 取餘數
+    #[test]
+    fn skips_high_profile_scaling_lists() {
+        let mut writer = BitWriter::new();
+        writer.write_bytes(&[100, 0, 40]);
+        write_ue(&mut writer, 0); // seq_parameter_set_id
+        write_ue(&mut writer, 1); // chroma_format_idc
+        write_ue(&mut writer, 2); // bit_depth_luma_minus8
+        write_ue(&mut writer, 2); // bit_depth_chroma_minus8
+        writer.write_bits(1, 0); // qpprime_y_zero_transform_bypass_flag
+        writer.write_bits(1, 1); // seq_scaling_matrix_present_flag
+        for i in 0..8 {
+            writer.write_bits(1, (i == 0 || i == 6) as u32);
+            if i == 0 {
+                // 8 -> 135 -> 7 -> 127 -> 0, 之後沿用 last_scale
+                for delta in [127, -128, 120, -127] {
+                    write_se(&mut writer, delta);
+                }
+            } else if i == 6 {
+                for _ in 0..64 {
+                    write_se(&mut writer, 0);
+                }
+            }
+        }
+        write_ue(&mut writer, 0); // log2_max_frame_num_minus4
+        write_ue(&mut writer, 0); // pic_order_cnt_type
+        write_ue(&mut writer, 0); // log2_max_pic_order_cnt_lsb_minus4
+        write_ue(&mut writer, 4); // max_num_ref_frames
+        writer.write_bits(1, 0);
+        write_ue(&mut writer, 119);
+        write_ue(&mut writer, 67);
+        writer.write_bits(2, 0b11); // frame_mbs_only_flag, direct_8x8_inference_flag
+        writer.write_bits(1, 1);
+        for crop in [0, 0, 0, 4] {
+            write_ue(&mut writer, crop);
+        }
+        writer.write_bits(2, 0b01); // vui 0, stop bit
+
+        let sps = Sps::read(&writer.into_bytes()).unwrap();
+        assert_eq!((sps.width, sps.height), (1920, 1080));
+        assert_eq!((sps.bit_depth_luma, sps.bit_depth_chroma), (10, 10));
+        assert_eq!(sps.frame_rate, None);
+    }
+
+    #[test]
+    fn rejects_invalid_sps() {
+        assert!(Sps::read(&X264_1080P[1..10]).is_err());
+        // seq_parameter_set_id 32
+        assert!(Sps::read(&[66, 0, 30, 0b0000_0100, 0b0010_0000]).is_err());
+    }
+}
@@ -1,14 +1,79 @@
 use std::fs::File;
-use mpeg2ts;
+use std::io::{self, Write};
+use super::super::super::metrics;
 use mpeg2ts::{
-    ts::{TsPacket, TsHeader, TsPayload, Pid, ContinuityCounter},
+    ts::{TsPacket, TsHeader, TsPayload, Pid, ContinuityCounter, Descriptor},
     pes::PesHeader,
+    es::StreamType,
 };
 
+// pmt 與 pes 中音訊的設定
+#[derive(Clone)]
+pub struct AudioStream {
+    stream_type: StreamType,
+    stream_id: u8,
+    descriptors: Vec<Descriptor>,
+}
+
+impl AudioStream {
+    const MPEG_AUDIO_STREAM_ID: u8 = 192;
+    const PRIVATE_STREAM_ID: u8 = 0xbd;
+
+    pub fn aac() -> AudioStream {
+        AudioStream {
+            stream_type: StreamType::AdtsAac,
+            stream_id: AudioStream::MPEG_AUDIO_STREAM_ID,
+            descriptors: vec![],
+        }
+    }
+
+    pub fn loas() -> AudioStream {
+        AudioStream {
+            stream_type: StreamType::Mpeg4LoasMultiFormatFramedAudio,
+            stream_id: AudioStream::MPEG_AUDIO_STREAM_ID,
+            descriptors: vec![],
+        }
+    }
+
+    // 取樣率 32kHz 以上為 MPEG-1(0x03), 其餘為 MPEG-2(0x04)
+    pub fn mp3(mpeg1: bool) -> AudioStream {
+        AudioStream {
+            stream_type: if mpeg1 { StreamType::Mpeg1Audio } else { StreamType::Mpeg2HalvedSampleRateAudio },
+            stream_id: AudioStream::MPEG_AUDIO_STREAM_ID,
+            descriptors: vec![],
+        }
+    }
+
+    // private data(0x06) 加上 registration descriptor "Opus" 與 extension descriptor(channel config)
+    pub fn opus(channels: u8) -> AudioStream {
+        AudioStream {
+            stream_type: StreamType::Mpeg2PacketizedData,
+            stream_id: AudioStream::PRIVATE_STREAM_ID,
+            descriptors: vec![
+                Descriptor { tag: 0x05, data: b"Opus".to_vec() },
+                Descriptor { tag: 0x7f, data: vec![0x80, channels.min(8)] },
+            ],
+        }
+    }
+}
+
+// mpeg2ts 的 Raw payload 不會設定 payload_unit_start_indicator, section (SCTE-35) 自行組成 188 bytes 的封包
+enum Packet {
+    Ts(TsPacket),
+    Raw([u8; TsPacket::SIZE]),
+}
+
 pub struct TransportStream {
     video_continuity_counter: ContinuityCounter,
     audio_continuity_counter: ContinuityCounter,
-    packets: Vec<TsPacket>,
+    metadata_continuity_counter: ContinuityCounter,
+    scte35_continuity_counter: ContinuityCounter,
+    packets: Vec<Packet>,
+    audio_only: bool,
+    video_stream_type: StreamType,
+    audio_stream: Option<AudioStream>,
+    first_timestamp: Option<u64>,
+    last_timestamp: u64,
 }
 
 impl TransportStream {
@@ -16,49 +81,102 @@ impl TransportStream {
     const PMT_PID: u16 = 256;
     const VIDEO_PID: u16 = 257;
     const AUDIO_PID: u16 = 258;
+    const METADATA_PID: u16 = 259;
+    const SCTE35_PID: u16 = 260;
+    // SCTE-35 的 stream type 為 0x86, mpeg2ts 的 StreamType 只能以 enum 建立 PMT, 沒有 SCTE-35 這個名稱,
+    // 0x86 對應的 variant 是 Dts8ChannelLosslessAudio (與 DTS 無關), 實際的用途由 registration descriptor "CUEI" 標示
+    const SCTE35_STREAM_TYPE: StreamType = StreamType::Dts8ChannelLosslessAudio;
     const VIDEO_STREAM_ID: u8 = 224;
-    const AUDIO_STREAM_ID: u8 = 192;
+    const PRIVATE_STREAM_ID: u8 = 0xbd;
+    // metadata_descriptor: application format 0xFFFF "ID3 ", format 0xFF "ID3 ", service id 0, flags
+    const ID3_DESCRIPTOR: &'static [u8] = &[0xff, 0xff, b'I', b'D', b'3', b' ', 0xff, b'I', b'D', b'3', b' ', 0x00, 0x0f];
 
     pub fn new() -> TransportStream {
         TransportStream {
             video_continuity_counter: ContinuityCounter::new(),
             audio_continuity_counter: ContinuityCounter::new(),
+            metadata_continuity_counter: ContinuityCounter::new(),
+            scte35_continuity_counter: ContinuityCounter::new(),
             packets: Vec::new(),
+            audio_only: false,
+            video_stream_type: StreamType::H264,
+            audio_stream: Some(AudioStream::aac()),
+            first_timestamp: None,
+            last_timestamp: 0,
         }
     }
 
-    pub fn write_file(&mut self, filename: &str) {
+    // 只含音訊的 ts, 用於 audio-only 的 variant, pcr 改由音訊封包攜帶
+    pub fn audio_only() -> TransportStream {
+        TransportStream {
+            audio_only: true,
+            ..TransportStream::new()
+        }
+    }
+
+    // 依 sequence header 的編碼設定 pmt
+    pub fn set_video_stream_type(&mut self, stream_type: StreamType) {
+        self.video_stream_type = stream_type;
+    }
+
+    // 不支援的音訊編碼為 None, pmt 中不列出音訊
+    pub fn set_audio_stream(&mut self, audio_stream: Option<AudioStream>) {
+        self.audio_stream = audio_stream;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    // 回傳寫入的 byte 數, 寫入失敗時這個 segment 的內容會被丟棄
+    pub fn write_file(&mut self, filename: &str) -> io::Result<usize> {
         use mpeg2ts::ts::{TsPacketWriter, WriteTsPacket};
 
-        let filename = format!("./video/{}", filename);
-        let file = File::create(filename).unwrap();
         let packets: Vec<_> = self.packets.drain(..).collect();
-        let mut writer = TsPacketWriter::new(file);
+        let first_timestamp = self.first_timestamp.take();
+        let filename = format!("./video/{}", filename);
+        let file = File::create(filename)?;
+        let mut writer = TsPacketWriter::new(&file);
+        let ts_error = |error: mpeg2ts::Error| io::Error::other(error.to_string());
 
-        writer.write_ts_packet(&TransportStream::default_pat()).unwrap();
-        writer.write_ts_packet(&TransportStream::default_pmt()).unwrap();
+        let pmt = if self.audio_only { TransportStream::audio_only_pmt(&self.audio_stream) } else { TransportStream::default_pmt(self.video_stream_type, &self.audio_stream) };
+        writer.write_ts_packet(&TransportStream::default_pat()).map_err(ts_error)?;
+        writer.write_ts_packet(&pmt).map_err(ts_error)?;
 
         for packet in &packets {
-            writer.write_ts_packet(packet).unwrap();
+            match packet {
+                Packet::Ts(packet) => writer.write_ts_packet(packet).map_err(ts_error)?,
+                Packet::Raw(packet) => (&file).write_all(packet)?,
+            }
         }
+
+        // 純音訊的 ts 與影音 ts 切點相同, 不重複計算
+        if !self.audio_only {
+            let duration = self.last_timestamp.saturating_sub(first_timestamp.unwrap_or(self.last_timestamp));
+            metrics::SEGMENTS_WRITTEN.inc();
+            metrics::SEGMENT_DURATION.observe(duration as f64 / 1000.0);
+        }
+
+        Ok((packets.len() + 2) * TsPacket::SIZE)
     }
 
-    pub fn push_video(&mut self, timestamp: u64, composition_time: u64, is_keyframe: bool, mut video: Vec<u8>) -> Result<(), ()> {
+    pub fn push_video(&mut self, timestamp: u64, composition_time: u64, is_keyframe: bool, mut video: Vec<u8>) {
         use mpeg2ts::{
             ts::{AdaptationField, payload},
             es::StreamId,
         };
 
+        self.track_timestamp(timestamp);
         let mut header = TransportStream::default_header(TransportStream::VIDEO_PID);
         header.continuity_counter = self.video_continuity_counter;
 
         let packet = {
             let data = {
-                let bytes: Vec<u8> = if video.len() < 153 { video.drain(..).collect() } else { video.drain(..153).collect() };
+                let bytes: Vec<u8> = if video.len() < 153 { std::mem::take(&mut video) } else { video.drain(..153).collect() };
                 mpeg2ts::ts::payload::Bytes::new(&bytes[..]).unwrap()
             };
 
-            let pcr = mpeg2ts::time::ClockReference::new(timestamp * 90).unwrap();
+            let pcr = mpeg2ts::time::ClockReference::new(TransportStream::clock(timestamp)).unwrap();
 
             let adaptation_field = if is_keyframe {
                 Some(AdaptationField {
@@ -75,8 +193,8 @@ impl TransportStream {
                 None
             };
 
-            let pts = mpeg2ts::time::Timestamp::new((timestamp + composition_time) * 90).unwrap();
-            let dts = mpeg2ts::time::Timestamp::new(timestamp * 90).unwrap();
+            let pts = mpeg2ts::time::Timestamp::new(TransportStream::clock(timestamp + composition_time)).unwrap();
+            let dts = mpeg2ts::time::Timestamp::new(TransportStream::clock(timestamp)).unwrap();
 
             TsPacket {
                 header: header.clone(),
@@ -98,12 +216,12 @@ impl TransportStream {
             }
         };
 
-        self.packets.push(packet);
+        self.packets.push(Packet::Ts(packet));
         header.continuity_counter.increment();
 
-        while video.len() > 0 {
+        while !video.is_empty() {
             let raw = {
-                let bytes: Vec<u8> = if video.len() < payload::Bytes::MAX_SIZE { video.drain(..).collect() } else { video.drain(..payload::Bytes::MAX_SIZE).collect() };
+                let bytes: Vec<u8> = if video.len() < payload::Bytes::MAX_SIZE { std::mem::take(&mut video) } else { video.drain(..payload::Bytes::MAX_SIZE).collect() };
                 mpeg2ts::ts::payload::Bytes::new(&bytes[..]).unwrap()
             };
 
@@ -113,37 +231,59 @@ impl TransportStream {
                 payload: Some(TsPayload::Raw(raw)),
             };
 
-            self.packets.push(packet);
+            self.packets.push(Packet::Ts(packet));
             header.continuity_counter.increment();
         }
 
         self.video_continuity_counter = header.continuity_counter;
-
-        Ok(())
     }
 
     pub fn push_audio(&mut self, timestamp: u64, mut audio: Vec<u8>) {
-        use mpeg2ts::{ts::payload, es::StreamId};
+        use mpeg2ts::{
+            ts::{AdaptationField, payload},
+            es::StreamId,
+        };
+
+        let stream_id = match &self.audio_stream {
+            Some(audio_stream) => audio_stream.stream_id,
+            None => return,
+        };
 
         let data = {
-            let bytes: Vec<u8> = if audio.len() < 153 { audio.drain(..).collect() } else { audio.drain(..153).collect() };
+            let bytes: Vec<u8> = if audio.len() < 153 { std::mem::take(&mut audio) } else { audio.drain(..153).collect() };
             mpeg2ts::ts::payload::Bytes::new(&bytes[..]).unwrap()
         };
 
+        self.track_timestamp(timestamp);
         let mut header = TransportStream::default_header(TransportStream::AUDIO_PID);
         header.continuity_counter = self.audio_continuity_counter;
 
+        let adaptation_field = if self.audio_only {
+            Some(AdaptationField {
+                discontinuity_indicator: false,
+                random_access_indicator: true,
+                es_priority_indicator: false,
+                pcr: Some(mpeg2ts::time::ClockReference::new(TransportStream::clock(timestamp)).unwrap()),
+                opcr: None,
+                splice_countdown: None,
+                transport_private_data: Vec::new(),
+                extension: None,
+            })
+        } else {
+            None
+        };
+
         let packet = TsPacket {
             header: header.clone(),
-            adaptation_field: None,
+            adaptation_field,
             payload: Some(TsPayload::Pes(payload::Pes {
                 header: PesHeader {
-                    stream_id: StreamId::new(TransportStream::AUDIO_STREAM_ID),
+                    stream_id: StreamId::new(stream_id),
                     priority: false,
                     data_alignment_indicator: false,
                     copyright: false,
                     original_or_copy: false,
-                    pts: Some(mpeg2ts::time::Timestamp::new(timestamp * 90).unwrap()),
+                    pts: Some(mpeg2ts::time::Timestamp::new(TransportStream::clock(timestamp)).unwrap()),
                     dts: None,
                     escr: None,
                 },
@@ -152,12 +292,12 @@ impl TransportStream {
             })),
         };
 
-        self.packets.push(packet);
+        self.packets.push(Packet::Ts(packet));
         header.continuity_counter.increment();
 
-        while audio.len() > 0 {
+        while !audio.is_empty() {
             let raw = {
-                let bytes: Vec<u8> = if audio.len() < payload::Bytes::MAX_SIZE { audio.drain(..).collect() } else { audio.drain(..payload::Bytes::MAX_SIZE).collect() };
+                let bytes: Vec<u8> = if audio.len() < payload::Bytes::MAX_SIZE { std::mem::take(&mut audio) } else { audio.drain(..payload::Bytes::MAX_SIZE).collect() };
                 mpeg2ts::ts::payload::Bytes::new(&bytes[..]).unwrap()
             };
 
@@ -167,13 +307,84 @@ impl TransportStream {
                 payload: Some(TsPayload::Raw(raw)),
             };
 
-            self.packets.push(packet);
+            self.packets.push(Packet::Ts(packet));
             header.continuity_counter.increment();
         }
 
         self.audio_continuity_counter = header.continuity_counter;
     }
 
+    // ID3 timed metadata, pts 為目前最後一個影音封包的時間
+    // 超過 pes_packet_len 範圍 (Id3::MAX_SIZE) 的 tag 不寫入
+    pub fn push_metadata(&mut self, id3: &[u8]) {
+        use mpeg2ts::{ts::payload, es::StreamId};
+        use std::convert::TryFrom;
+
+        let mut header = TransportStream::default_header(TransportStream::METADATA_PID);
+        header.continuity_counter = self.metadata_continuity_counter;
+
+        // pes header 之後 (flags 3 bytes, pts 5 bytes) 的長度
+        let pes_packet_len = match u16::try_from(8 + id3.len()) {
+            Ok(len) => len,
+            Err(_) => return,
+        };
+        let mut chunks = std::iter::once(&id3[..id3.len().min(153)]).chain(id3.get(153..).unwrap_or(&[]).chunks(payload::Bytes::MAX_SIZE));
+
+        let first = payload::Bytes::new(chunks.next().unwrap()).unwrap();
+        self.packets.push(Packet::Ts(TsPacket {
+            header: header.clone(),
+            adaptation_field: None,
+            payload: Some(TsPayload::Pes(payload::Pes {
+                header: PesHeader {
+                    stream_id: StreamId::new(TransportStream::PRIVATE_STREAM_ID),
+                    priority: false,
+                    data_alignment_indicator: true,
+                    copyright: false,
+                    original_or_copy: false,
+                    pts: Some(mpeg2ts::time::Timestamp::new(TransportStream::clock(self.last_timestamp)).unwrap()),
+                    dts: None,
+                    escr: None,
+                },
+                pes_packet_len,
+                data: first,
+            })),
+        }));
+        header.continuity_counter.increment();
+
+        for chunk in chunks {
+            self.packets.push(Packet::Ts(TsPacket {
+                header: header.clone(),
+                adaptation_field: None,
+                payload: Some(TsPayload::Raw(payload::Bytes::new(chunk).unwrap())),
+            }));
+            header.continuity_counter.increment();
+        }
+
+        self.metadata_continuity_counter = header.continuity_counter;
+    }
+
+    // 一個 section 一個封包: header | pointer field (0) | section | 0xFF 填充
+    pub fn push_section(&mut self, section: &[u8]) {
+        let pid = TransportStream::SCTE35_PID;
+        let mut packet = [0xff; TsPacket::SIZE];
+        packet[..5].copy_from_slice(&[0x47, 0x40 | (pid >> 8) as u8, pid as u8, 0x10 | self.scte35_continuity_counter.as_u8(), 0x00]);
+        packet[5..5 + section.len()].copy_from_slice(section);
+        self.packets.push(Packet::Raw(packet));
+        self.scte35_continuity_counter.increment();
+    }
+
+    // PTS/DTS 為 33 位元的 90kHz 時鐘, 超過時從 0 循環, 約 26.5 小時
+    pub fn clock(timestamp: u64) -> u64 {
+        timestamp.wrapping_mul(90) & mpeg2ts::time::Timestamp::MAX
+    }
+
+    fn track_timestamp(&mut self, timestamp: u64) {
+        if self.first_timestamp.is_none() {
+            self.first_timestamp = Some(timestamp);
+        }
+        self.last_timestamp = self.last_timestamp.max(timestamp);
+    }
+
     pub fn default_header(pid: u16) -> TsHeader {
         use mpeg2ts::ts::TransportScramblingControl;
 
@@ -203,11 +414,17 @@ impl TransportStream {
         }
     }
 
-    pub fn default_pmt() -> TsPacket {
-        use mpeg2ts::{
-            ts::{VersionNumber, payload::Pmt, EsInfo},
-            es::StreamType,
-        };
+    pub fn default_pmt(video_stream_type: StreamType, audio_stream: &Option<AudioStream>) -> TsPacket {
+        use mpeg2ts::ts::{VersionNumber, payload::Pmt, EsInfo};
+
+        let mut table = vec![EsInfo {
+            stream_type: video_stream_type,
+            elementary_pid: Pid::new(TransportStream::VIDEO_PID).unwrap(),
+            descriptors: vec![],
+        }];
+        table.extend(TransportStream::audio_es_info(audio_stream));
+        table.push(TransportStream::metadata_es_info());
+        table.push(TransportStream::scte35_es_info());
 
         TsPacket {
             header: TransportStream::default_header(TransportStream::PMT_PID),
@@ -216,19 +433,64 @@ impl TransportStream {
                 program_num: 1,
                 pcr_pid: Some(Pid::new(TransportStream::VIDEO_PID).unwrap()),
                 version_number: VersionNumber::default(),
-                table: vec![
-                    EsInfo {
-                        stream_type: StreamType::H264,
-                        elementary_pid: Pid::new(TransportStream::VIDEO_PID).unwrap(),
-                        descriptors: vec![],
-                    },
-                    EsInfo {
-                        stream_type: StreamType::AdtsAac,
-                        elementary_pid: Pid::new(TransportStream::AUDIO_PID).unwrap(),
-                        descriptors: vec![],
-                    },
-                ],
+                table,
+            })),
+        }
+    }
+
+    pub fn audio_only_pmt(audio_stream: &Option<AudioStream>) -> TsPacket {
+        use mpeg2ts::ts::{VersionNumber, payload::Pmt};
+
+        TsPacket {
+            header: TransportStream::default_header(TransportStream::PMT_PID),
+            adaptation_field: None,
+            payload: Some(TsPayload::Pmt(Pmt {
+                program_num: 1,
+                pcr_pid: Some(Pid::new(TransportStream::AUDIO_PID).unwrap()),
+                version_number: VersionNumber::default(),
+                table: TransportStream::audio_es_info(audio_stream).into_iter().chain(vec![TransportStream::metadata_es_info(), TransportStream::scte35_es_info()]).collect(),
             })),
         }
     }
+
+    fn audio_es_info(audio_stream: &Option<AudioStream>) -> Option<mpeg2ts::ts::EsInfo> {
+        use mpeg2ts::ts::EsInfo;
+
+        audio_stream.as_ref().map(|audio_stream| EsInfo {
+            stream_type: audio_stream.stream_type,
+            elementary_pid: Pid::new(TransportStream::AUDIO_PID).unwrap(),
+            descriptors: audio_stream.descriptors.clone(),
+        })
+    }
+
+    fn metadata_es_info() -> mpeg2ts::ts::EsInfo {
+        use mpeg2ts::ts::EsInfo;
+
+        EsInfo {
+            stream_type: StreamType::PacketizedMetadata,
+            elementary_pid: Pid::new(TransportStream::METADATA_PID).unwrap(),
+            descriptors: vec![Descriptor { tag: 0x26, data: TransportStream::ID3_DESCRIPTOR.to_vec() }],
+        }
+    }
+
+    // registration descriptor "CUEI" 與 cue_identifier_descriptor, cue_stream_type 0 (splice_insert)
+    fn scte35_es_info() -> mpeg2ts::ts::EsInfo {
+        use mpeg2ts::ts::EsInfo;
+
+        EsInfo {
+            stream_type: TransportStream::SCTE35_STREAM_TYPE,
+            elementary_pid: Pid::new(TransportStream::SCTE35_PID).unwrap(),
+            descriptors: vec![Descriptor { tag: 0x05, data: b"CUEI".to_vec() }, Descriptor { tag: 0x8a, data: vec![0x00] }],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scte35_stream_type_is_0x86() {
+        assert_eq!(TransportStream::SCTE35_STREAM_TYPE as u8, 0x86);
+    }
 }